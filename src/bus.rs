@@ -2,6 +2,7 @@ use crate::ral::{
     modify_reg, otg_device, otg_global, otg_global_dieptxfx, otg_pwrclk, read_reg, write_reg,
 };
 use crate::transition::{EndpointConfig, EndpointDescriptor};
+use core::cell::Cell;
 use core::marker::PhantomData;
 use critical_section::{CriticalSection, Mutex};
 use embedded_hal::blocking::delay::DelayMs;
@@ -9,125 +10,1211 @@ use usb_device::bus::{PollResult, UsbBusAllocator};
 use usb_device::endpoint::{EndpointAddress, EndpointType};
 use usb_device::{Result, UsbDirection, UsbError};
 
-use crate::endpoint::{EndpointIn, EndpointOut};
-use crate::endpoint_memory::{EndpointBufferState, EndpointMemoryAllocator};
-use crate::target::UsbRegisters;
-use crate::{PhyType, UsbPeripheral};
+use crate::endpoint::{ep0_mpsiz, EndpointIn, EndpointOut};
+use crate::endpoint_memory::{
+    EndpointBufferQueue, EndpointBufferState, EndpointMemoryAllocator, MAX_OUT_BUFFERS,
+};
+use crate::otg::OtgCapabilities;
+use crate::target::{CoreMode, UsbRegisters};
+use crate::{PhyType, UsbPeripheral, VbusSensing};
+
+/// Bus events recorded by `UsbBus::on_interrupt`
+const EVENT_RESET: u8 = 1 << 0;
+const EVENT_RESUME: u8 = 1 << 1;
+const EVENT_SUSPEND: u8 = 1 << 2;
+const EVENT_DISCONNECT: u8 = 1 << 3;
+
+/// USB 2.0 Link Power Management (LPM) settings.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct LpmConfig {
+    /// Smallest BESL (best effort service latency) for which the PHY is put into deep low power
+    /// mode in L1, in the 4-bit encoding of the LPM token.
+    pub besl_threshold: u8,
+    /// Stop the PHY clock in L1 (deep sleep), otherwise only the shallow sleep is used.
+    pub deep_sleep: bool,
+}
+
+/// Port types told apart by USB Battery Charging 1.2 detection, see `UsbBus::detect_charger`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ChargerType {
+    /// Standard downstream port, up to 500 mA once configured
+    StandardDownstreamPort,
+    /// Charging downstream port, up to 1.5 A with data transfers
+    ChargingDownstreamPort,
+    /// Dedicated charging port, up to 1.5 A, D+ and D- are shorted and there is no host
+    DedicatedChargingPort,
+    /// PS/2 port or proprietary charger pulling D- up, the current limit isn't known
+    Proprietary,
+}
+
+/// USB 2.0 electrical test modes, entered with SET_FEATURE(TEST_MODE).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TestMode {
+    /// Continuous J state
+    TestJ,
+    /// Continuous K state
+    TestK,
+    /// High-speed receive mode, IN tokens are answered with NAK
+    Se0Nak,
+    /// Repeated transmission of the test packet, which the core generates itself
+    TestPacket,
+    /// Forced enable of a high-speed downstream port
+    ForceEnable,
+}
+
+impl TestMode {
+    /// Converts the test selector of SET_FEATURE(TEST_MODE), the upper byte of `wIndex`.
+    pub fn from_selector(selector: u8) -> Option<Self> {
+        match selector {
+            0x01 => Some(TestMode::TestJ),
+            0x02 => Some(TestMode::TestK),
+            0x03 => Some(TestMode::Se0Nak),
+            0x04 => Some(TestMode::TestPacket),
+            0x05 => Some(TestMode::ForceEnable),
+            _ => None,
+        }
+    }
+
+    /// Value of the DCTL.TCTL field
+    fn tctl(self) -> u32 {
+        match self {
+            TestMode::TestJ => 0b001,
+            TestMode::TestK => 0b010,
+            TestMode::Se0Nak => 0b011,
+            TestMode::TestPacket => 0b100,
+            TestMode::ForceEnable => 0b101,
+        }
+    }
+}
+
+/// Highest speed the device enumerates at.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Speed {
+    /// High speed (480 Mbit/s), if the core has a high-speed PHY
+    High,
+    /// Full speed (12 Mbit/s)
+    Full,
+}
+
+/// Time within a (micro)frame at which the core reports the end of the periodic frame
+/// (DCFG.PFIVL), in percent of the frame interval.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FrameInterval {
+    /// 80 % of the (micro)frame
+    Percent80,
+    /// 85 % of the (micro)frame
+    Percent85,
+    /// 90 % of the (micro)frame
+    Percent90,
+    /// 95 % of the (micro)frame
+    Percent95,
+}
+
+/// FIFO thresholds of the DMA engine (DTHRCTL), lengths in 32-bit words.
+///
+/// With a threshold the core starts a transfer between the FIFO and the bus once that many
+/// words are available instead of a whole packet.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Thresholds {
+    /// Transmit threshold of non-isochronous IN endpoints
+    pub non_iso_tx: Option<u16>,
+    /// Transmit threshold of isochronous IN endpoints, shares the length with `non_iso_tx`
+    pub iso_tx: Option<u16>,
+    /// Receive threshold of OUT endpoints
+    pub rx: Option<u16>,
+}
+
+/// Runtime settings of [`UsbBus`], see [`UsbBus::with_config`].
+///
+/// The defaults, returned by [`UsbBusConfig::new`], correspond to [`UsbBus::new`]. Each setting
+/// can be overridden with the builder methods:
+///
+/// ```
+/// # use synopsys_usb_otg::bus::{FrameInterval, Speed};
+/// # use synopsys_usb_otg::UsbBusConfig;
+/// let config = UsbBusConfig::new()
+///     .max_speed(Speed::Full)
+///     .frame_interval(FrameInterval::Percent90)
+///     .rx_fifo_slack(40);
+/// ```
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct UsbBusConfig {
+    max_speed: Speed,
+    frame_interval: FrameInterval,
+    transceiver_delay: bool,
+    timeout_calibration: u8,
+    rx_fifo_slack: u16,
+    vbus_sensing: Option<VbusSensing>,
+    out_buffers: usize,
+    dma: bool,
+    thresholds: Thresholds,
+}
+
+impl UsbBusConfig {
+    /// Creates a configuration with the default settings, those of [`UsbBus::new`].
+    pub fn new() -> Self {
+        UsbBusConfig {
+            max_speed: Speed::High,
+            frame_interval: FrameInterval::Percent80,
+            transceiver_delay: cfg!(feature = "xcvrdly"),
+            timeout_calibration: 1,
+            rx_fifo_slack: 30,
+            vbus_sensing: None,
+            out_buffers: 1,
+            dma: false,
+            thresholds: Thresholds::default(),
+        }
+    }
+
+    /// Limits the speed of a core with a high-speed PHY, e.g. to full speed for a board
+    /// revision without a working ULPI PHY. The default is the highest speed of the PHY.
+    pub fn max_speed(mut self, speed: Speed) -> Self {
+        self.max_speed = speed;
+        self
+    }
+
+    /// Sets the periodic frame interval, 80 % by default.
+    pub fn frame_interval(mut self, interval: FrameInterval) -> Self {
+        self.frame_interval = interval;
+        self
+    }
+
+    /// Enables the transceiver delay (DCFG.XCVRDLY) needed by some external HS PHYs, see the
+    /// README. The default is set by the `xcvrdly` feature, full-speed cores ignore it.
+    #[cfg(feature = "hs")]
+    pub fn transceiver_delay(mut self, enabled: bool) -> Self {
+        self.transceiver_delay = enabled;
+        self
+    }
+
+    /// Sets the additional PHY clocks of the timeout calibration (GUSBCFG.TOCAL) of HS cores,
+    /// 1 by default. Full-speed cores keep the reset value.
+    pub fn timeout_calibration(mut self, clocks: u8) -> Self {
+        self.timeout_calibration = clocks & 0b111;
+        self
+    }
+
+    /// Sets the number of words the Rx FIFO gets in addition to the OUT packet buffers, 30 by
+    /// default.
+    ///
+    /// The Rx FIFO also holds the status entries of received packets and SETUP packets. Some
+    /// cores need more than the reference manuals state, the default was found empirically.
+    pub fn rx_fifo_slack(mut self, words: u16) -> Self {
+        self.rx_fifo_slack = words;
+        self
+    }
+
+    /// Overrides [`UsbPeripheral::vbus_sensing`].
+    pub fn vbus_sensing(mut self, sensing: VbusSensing) -> Self {
+        self.vbus_sensing = Some(sensing);
+        self
+    }
+
+    /// Sets the number of packet buffers per OUT endpoint except EP0, see
+    /// [`UsbBus::new_with_out_buffers`]. The value is clamped to 1..=8.
+    pub fn out_buffers(mut self, count: usize) -> Self {
+        self.out_buffers = count.clamp(1, MAX_OUT_BUFFERS);
+        self
+    }
+
+    /// Uses the internal DMA engine, see [`UsbBus::new_with_dma`].
+    ///
+    /// Has no effect on full-speed cores, which have no DMA engine. The `embassy` driver always
+    /// uses the FIFOs and ignores this setting.
+    #[cfg(feature = "hs")]
+    pub fn dma(mut self, enabled: bool) -> Self {
+        self.dma = enabled;
+        self
+    }
+
+    /// Sets the FIFO thresholds of the DMA engine, only used with [`dma`](Self::dma).
+    #[cfg(feature = "hs")]
+    pub fn thresholds(mut self, thresholds: Thresholds) -> Self {
+        self.thresholds = thresholds;
+        self
+    }
+}
+
+impl Default for UsbBusConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 /// USB peripheral driver for STM32 microcontrollers.
 pub struct UsbBus<USB> {
     peripheral: USB,
+    config: UsbBusConfig,
     regs: Mutex<UsbRegisters>,
     allocator: EndpointAllocator<USB>,
+    otg: Mutex<Cell<OtgCapabilities>>,
+    otg_interrupts: Mutex<Cell<u32>>,
+    events: Mutex<Cell<u8>>,
+    connected: Mutex<Cell<bool>>,
+    ep_in_complete: Mutex<Cell<u16>>,
+    lpm: Mutex<Cell<Option<LpmConfig>>>,
+    test_mode: Mutex<Cell<Option<TestMode>>>,
 }
 
 impl<USB: UsbPeripheral> UsbBus<USB> {
     /// Constructs a new USB peripheral driver.
     pub fn new(peripheral: USB, ep_memory: &'static mut [u32]) -> UsbBusAllocator<Self> {
-        let bus = UsbBus {
+        Self::with_config(peripheral, ep_memory, UsbBusConfig::default())
+    }
+
+    /// Constructs a new USB peripheral driver with the given settings.
+    pub fn with_config(
+        peripheral: USB,
+        ep_memory: &'static mut [u32],
+        config: UsbBusConfig,
+    ) -> UsbBusAllocator<Self> {
+        UsbBusAllocator::new(Self::new_bus(peripheral, ep_memory, config))
+    }
+
+    /// Constructs a new USB peripheral driver with several packet buffers per OUT endpoint.
+    ///
+    /// Each OUT endpoint except EP0 gets a queue of `out_buffers` packet buffers allocated from
+    /// `ep_memory`, so back-to-back packets are accepted while the application is still
+    /// processing earlier ones. Packets are read in the order they were received.
+    ///
+    /// `out_buffers` is clamped to 1..=8, as with [`UsbBusConfig::out_buffers`].
+    pub fn new_with_out_buffers(
+        peripheral: USB,
+        ep_memory: &'static mut [u32],
+        out_buffers: usize,
+    ) -> UsbBusAllocator<Self> {
+        let config = UsbBusConfig::new().out_buffers(out_buffers);
+        Self::with_config(peripheral, ep_memory, config)
+    }
+
+    /// Constructs a new USB peripheral driver that uses the internal DMA engine.
+    ///
+    /// In DMA mode the core moves packets between the FIFOs and `ep_memory` on its own, there is
+    /// no word-by-word copying through the FIFO registers. Packet buffers for IN endpoints are
+    /// allocated from `ep_memory` as well, and the EP0 OUT buffer is at least 24 bytes long to
+    /// hold back-to-back SETUP packets.
+    ///
+    /// `ep_memory` must be reachable by the USB AHB master (e.g. not in a tightly coupled memory)
+    /// and must not be cached, or be cleaned and invalidated by the application.
+    ///
+    /// Full-speed cores have no DMA engine, they fall back to the Rx/Tx FIFOs as with
+    /// [`UsbBus::new`].
+    #[cfg(feature = "hs")]
+    pub fn new_with_dma(peripheral: USB, ep_memory: &'static mut [u32]) -> UsbBusAllocator<Self> {
+        Self::with_config(peripheral, ep_memory, UsbBusConfig::new().dma(true))
+    }
+
+    pub(crate) fn new_bus(
+        peripheral: USB,
+        ep_memory: &'static mut [u32],
+        config: UsbBusConfig,
+    ) -> Self {
+        let dma = config.dma && USB::HIGH_SPEED;
+        UsbBus {
             peripheral,
+            config,
             regs: Mutex::new(UsbRegisters::new::<USB>()),
-            allocator: EndpointAllocator::new(ep_memory),
+            allocator: EndpointAllocator::new(
+                ep_memory,
+                dma,
+                config.out_buffers,
+                config.rx_fifo_slack,
+            ),
+            otg: Mutex::new(Cell::new(OtgCapabilities::default())),
+            otg_interrupts: Mutex::new(Cell::new(0)),
+            events: Mutex::new(Cell::new(0)),
+            connected: Mutex::new(Cell::new(false)),
+            ep_in_complete: Mutex::new(Cell::new(0)),
+            lpm: Mutex::new(Cell::new(None)),
+            test_mode: Mutex::new(Cell::new(None)),
+        }
+    }
+
+    #[cfg(feature = "embassy")]
+    pub(crate) fn endpoint_in(&self, index: usize) -> Option<&EndpointIn> {
+        self.allocator.endpoints_in.get(index)?.as_ref()
+    }
+
+    #[cfg(feature = "embassy")]
+    pub(crate) fn endpoint_out(&self, index: usize) -> Option<&EndpointOut> {
+        self.allocator.endpoints_out.get(index)?.as_ref()
+    }
+
+    /// OTG interrupts acknowledged by `on_interrupt` that `Negotiation::poll` hasn't reported yet
+    pub(crate) fn otg_interrupts<'cs>(&'cs self, cs: CriticalSection<'cs>) -> &'cs Cell<u32> {
+        self.otg_interrupts.borrow(cs)
+    }
+
+    pub fn free(self) -> USB {
+        self.peripheral
+    }
+
+    fn configure_all(&self, cs: CriticalSection<'_>) {
+        let regs = self.regs.borrow(cs);
+
+        // Rx FIFO
+        // This calculation doesn't correspond to one in a Reference Manual.
+        // In fact, the required number of words is higher than indicated in RM.
+        // The following numbers are pessimistic and were figured out empirically.
+        // F429 requires 35+ words for the (EP0[8] + EP2[64]) setup
+        // F446 requires 39+ words for the same setup
+        let rx_fifo_size = self.allocator.memory_allocator.total_rx_buffer_size_words()
+            + self.config.rx_fifo_slack;
+        write_reg!(otg_global, regs.global(), GRXFSIZ, rx_fifo_size as u32);
+        let mut fifo_top = rx_fifo_size;
+
+        // Tx FIFO #0
+        let fifo_size = self.allocator.memory_allocator.tx_fifo_size_words(0);
+
+        #[cfg(not(feature = "hs"))]
+        write_reg!(otg_global, regs.global(), DIEPTXF0,
+            TX0FD: fifo_size as u32,
+            TX0FSA: fifo_top as u32
+        );
+        #[cfg(feature = "hs")]
+        write_reg!(otg_global, regs.global(), GNPTXFSIZ,
+            TX0FD: fifo_size as u32,
+            TX0FSA: fifo_top as u32
+        );
+
+        fifo_top += fifo_size;
+
+        // Tx FIFOs
+        for i in 1..USB::ENDPOINT_COUNT {
+            let fifo_size = self.allocator.memory_allocator.tx_fifo_size_words(i);
+
+            let dieptxfx = regs.dieptxfx(i);
+            write_reg!(otg_global_dieptxfx, dieptxfx, DIEPTXFx,
+                INEPTXFD: fifo_size as u32,
+                INEPTXSA: fifo_top as u32
+            );
+
+            fifo_top += fifo_size;
+        }
+
+        debug_assert!(fifo_top as usize <= USB::FIFO_DEPTH_WORDS);
+
+        // Flush Rx & Tx FIFOs
+        modify_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH: 1, TXFFLSH: 1, TXFNUM: 0x10);
+        while read_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH, TXFFLSH) != (0, 0) {}
+
+        for ep in &self.allocator.endpoints_in {
+            if let Some(ep) = ep {
+                // enabling EP TX interrupt
+                modify_reg!(otg_device, regs.device(), DAINTMSK, |v| v
+                    | (0x0001 << ep.address().index()));
+
+                ep.configure(cs);
+            }
+        }
+
+        for ep in &self.allocator.endpoints_out {
+            if let Some(ep) = ep {
+                if ep.address().index() == 0 || self.allocator.dma {
+                    // enabling RX interrupt from EP0, or from all endpoints in DMA mode
+                    modify_reg!(otg_device, regs.device(), DAINTMSK, |v| v
+                        | (0x00010000 << ep.address().index()));
+                }
+
+                ep.configure(cs);
+            }
+        }
+    }
+
+    fn deconfigure_all(&self, cs: CriticalSection<'_>) {
+        let regs = self.regs.borrow(cs);
+
+        // disable interrupts
+        modify_reg!(otg_device, regs.device(), DAINTMSK, IEPM: 0, OEPM: 0);
+
+        for ep in &self.allocator.endpoints_in {
+            if let Some(ep) = ep {
+                ep.deconfigure(cs);
+            }
+        }
+
+        for ep in &self.allocator.endpoints_out {
+            if let Some(ep) = ep {
+                ep.deconfigure(cs);
+            }
+        }
+    }
+
+    fn init(&self) {
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+            let otg = self.otg.borrow(cs).get();
+
+            crate::target::init_core(
+                &self.peripheral,
+                *regs,
+                CoreMode::Device,
+                otg,
+                self.config.timeout_calibration,
+            );
+
+            self.start(cs);
+        });
+    }
+
+    /// Configures the core for device operation and connects to the host.
+    fn start(&self, cs: CriticalSection<'_>) {
+        let regs = self.regs.borrow(cs);
+        let otg = self.otg.borrow(cs).get();
+
+        let core_id = read_reg!(otg_global, regs.global(), CID);
+        let sensing = self.vbus_sensing();
+        let internal_sensing = sensing == VbusSensing::Internal;
+
+        // Configuring Vbus sense and SOF output
+        match core_id {
+            0x0000_1200 | 0x0000_1100 if internal_sensing => {
+                // F429-like chips have the GCCFG.NOVBUSSENS bit
+
+                //modify_reg!(otg_global, regs.global, GCCFG, NOVBUSSENS: 0);
+                modify_reg!(otg_global, regs.global(), GCCFG, |r| r & !(1 << 21));
+
+                modify_reg!(otg_global, regs.global(), GCCFG, VBUSASEN: 0, VBUSBSEN: 1, SOFOUTEN: 0);
+            }
+            0x0000_1200 | 0x0000_1100 => {
+                //modify_reg!(otg_global, regs.global, GCCFG, NOVBUSSENS: 1);
+                modify_reg!(otg_global, regs.global(), GCCFG, |r| r | (1 << 21));
+
+                modify_reg!(otg_global, regs.global(), GCCFG, VBUSASEN: 0, VBUSBSEN: 0, SOFOUTEN: 0);
+            }
+            0x0000_2000 | 0x0000_2100 | 0x0000_2300 | 0x0000_3000 | 0x0000_3100
+                if internal_sensing =>
+            {
+                // F446-like chips have the GCCFG.VBDEN bit with the opposite meaning
+
+                //modify_reg!(otg_global, regs.global, GCCFG, VBDEN: 1);
+                modify_reg!(otg_global, regs.global(), GCCFG, |r| r | (1 << 21));
+
+                // The B-peripheral session follows VBUS
+                //modify_reg!(otg_global, regs.global, GOTGCTL, BVALOEN: 0, BVALOVAL: 0);
+                modify_reg!(otg_global, regs.global(), GOTGCTL, |r| r & !(0b11 << 6));
+            }
+            0x0000_2000 | 0x0000_2100 | 0x0000_2300 | 0x0000_3000 | 0x0000_3100 => {
+                //modify_reg!(otg_global, regs.global, GCCFG, VBDEN: 0);
+                modify_reg!(otg_global, regs.global(), GCCFG, |r| r & !(1 << 21));
+
+                // Force B-peripheral session
+                //modify_reg!(otg_global, regs.global, GOTGCTL, BVALOEN: 1, BVALOVAL: 1);
+                modify_reg!(otg_global, regs.global(), GOTGCTL, |r| r | (0b11 << 6));
+            }
+            _ => {}
+        }
+
+        // Enable PHY clock
+        write_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, 0);
+
+        // Soft disconnect device
+        modify_reg!(otg_device, regs.device(), DCTL, SDIS: 1);
+
+        // Setup USB speed and frame interval
+        let speed = match (USB::HIGH_SPEED, self.peripheral.phy_type()) {
+            (false, _) => 0b11,
+            (true, PhyType::InternalFullSpeed) => 0b11,
+            // Full speed using the high-speed PHY
+            (true, _) if self.config.max_speed == Speed::Full => 0b01,
+            (true, PhyType::InternalHighSpeed) => 0b00,
+            (true, PhyType::ExternalHighSpeed) => 0b00,
+        };
+        let pfivl = match self.config.frame_interval {
+            FrameInterval::Percent80 => 0b00,
+            FrameInterval::Percent85 => 0b01,
+            FrameInterval::Percent90 => 0b10,
+            FrameInterval::Percent95 => 0b11,
+        };
+        modify_reg!(otg_device, regs.device(), DCFG,
+            PFIVL: pfivl,
+            DSPD: speed
+        );
+        #[cfg(feature = "hs")]
+        if USB::HIGH_SPEED && self.config.transceiver_delay {
+            modify_reg!(otg_device, regs.device(), DCFG, XCVRDLY: 1);
+        }
+
+        // unmask EP interrupts
+        write_reg!(otg_device, regs.device(), DIEPMSK, XFRCM: 1);
+
+        // unmask core interrupts
+        if self.allocator.dma {
+            // The core drains the Rx FIFO itself, completion is signalled per endpoint
+            write_reg!(otg_device, regs.device(), DOEPMSK, XFRCM: 1, STUPM: 1);
+            write_reg!(otg_global, regs.global(), GINTMSK,
+                USBRST: 1, ENUMDNEM: 1,
+                USBSUSPM: 1, WUIM: 1,
+                IEPINT: 1, OEPINT: 1
+            );
+        } else {
+            write_reg!(otg_global, regs.global(), GINTMSK,
+                USBRST: 1, ENUMDNEM: 1,
+                USBSUSPM: 1, WUIM: 1,
+                IEPINT: 1, RXFLVLM: 1
+            );
+        }
+        #[cfg(not(feature = "hs"))]
+        modify_reg!(otg_global, regs.global(), GINTMSK, IISOIXFRM: 1, IPXFRM_IISOOXFRM: 1);
+        #[cfg(feature = "hs")]
+        modify_reg!(otg_global, regs.global(), GINTMSK, IISOIXFRM: 1, PXFRM_IISOOXFRM: 1);
+        if otg.srp || otg.hnp {
+            modify_reg!(otg_global, regs.global(), GINTMSK, OTGINT: 1, SRQIM: 1);
+        } else if internal_sensing {
+            // Session end detection
+            modify_reg!(otg_global, regs.global(), GINTMSK, OTGINT: 1);
+        }
+        if let Some(config) = self.lpm.borrow(cs).get() {
+            self.enable_lpm(cs, config);
+        }
+
+        // clear pending interrupts
+        write_reg!(otg_global, regs.global(), GINTSTS, 0xffffffff);
+
+        // Enable DMA with INCR4 bursts
+        #[cfg(feature = "hs")]
+        if self.allocator.dma {
+            modify_reg!(otg_global, regs.global(), GAHBCFG, HBSTLEN: 0b0011, DMAEN: 1);
+
+            let thresholds = self.config.thresholds;
+            let tx = thresholds.non_iso_tx.or(thresholds.iso_tx).unwrap_or(0);
+            write_reg!(otg_device, regs.device(), DTHRCTL,
+                NONISOTHREN: thresholds.non_iso_tx.is_some() as u32,
+                ISOTHREN: thresholds.iso_tx.is_some() as u32,
+                TXTHRLEN: tx as u32,
+                RXTHREN: thresholds.rx.is_some() as u32,
+                RXTHRLEN: thresholds.rx.unwrap_or(0) as u32
+            );
+        }
+
+        // unmask global interrupt
+        modify_reg!(otg_global, regs.global(), GAHBCFG, GINT: 1);
+
+        // connect(true), unless the HAL doesn't see VBUS
+        let vbus = match sensing {
+            VbusSensing::Disabled => true,
+            VbusSensing::Internal => read_reg!(otg_global, regs.global(), GOTGCTL, BSVLD) != 0,
+            VbusSensing::Gpio => self.peripheral.vbus_present(),
         };
+        self.connected.borrow(cs).set(vbus);
+        let connect = sensing != VbusSensing::Gpio || vbus;
+        modify_reg!(otg_device, regs.device(), DCTL, SDIS: !connect as u32);
+    }
+
+    /// Connects to or disconnects from the host after VBUS has changed.
+    ///
+    /// Only needed with [`VbusSensing::Gpio`], call this when
+    /// [`UsbPeripheral::vbus_present`] changes, e.g. from the GPIO interrupt. The loss of VBUS is
+    /// reported by `poll` like a disconnect detected by the core.
+    pub fn vbus_changed(&self) {
+        if self.vbus_sensing() != VbusSensing::Gpio {
+            return;
+        }
+
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+            let present = self.peripheral.vbus_present();
+            modify_reg!(otg_device, regs.device(), DCTL, SDIS: !present as u32);
+
+            if !present {
+                self.disconnect(cs);
+            }
+        });
+    }
+
+    pub(crate) fn vbus_sensing(&self) -> VbusSensing {
+        self.config
+            .vbus_sensing
+            .unwrap_or_else(|| self.peripheral.vbus_sensing())
+    }
+
+    /// Returns `true` if VBUS is present, or isn't sensed at all.
+    #[cfg(feature = "embassy")]
+    pub(crate) fn vbus_present(&self, cs: CriticalSection<'_>) -> bool {
+        let regs = self.regs.borrow(cs);
+        match self.vbus_sensing() {
+            VbusSensing::Disabled => true,
+            VbusSensing::Internal => read_reg!(otg_global, regs.global(), GOTGCTL, BSVLD) != 0,
+            VbusSensing::Gpio => self.peripheral.vbus_present(),
+        }
+    }
+
+    /// Keeps the session request interrupt unmasked while detached, so that the return of VBUS
+    /// is noticed with [`VbusSensing::Internal`].
+    #[cfg(feature = "embassy")]
+    pub(crate) fn listen_for_session(&self, cs: CriticalSection<'_>) {
+        let regs = self.regs.borrow(cs);
+        if self.vbus_sensing() == VbusSensing::Internal {
+            write_reg!(otg_global, regs.global(), GINTSTS, SRQINT: 1);
+            modify_reg!(otg_global, regs.global(), GINTMSK, SRQIM: 1);
+        }
+    }
+
+    /// Returns `false` once the device has been unplugged, until the host resets it again.
+    ///
+    /// `usb-device` reports a disconnect as `UsbDeviceState::Suspend`, use this to tell an
+    /// unplugged device apart from one suspended by the host. Disconnects are only detected with
+    /// [`VbusSensing::Internal`] or [`VbusSensing::Gpio`].
+    pub fn is_connected(&self) -> bool {
+        critical_section::with(|cs| self.connected.borrow(cs).get())
+    }
+
+    /// Disconnects from the host and stops device operation.
+    ///
+    /// Used when the core is handed over to the host driver.
+    pub fn detach(&self) {
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+
+            // Soft disconnect device
+            modify_reg!(otg_device, regs.device(), DCTL, SDIS: 1);
+
+            self.deconfigure_all(cs);
+
+            // mask core interrupts
+            write_reg!(otg_global, regs.global(), GINTMSK, 0);
+        });
+    }
+
+    /// Reinitializes the core in device mode and connects to the host.
+    ///
+    /// Endpoints are configured again on the next bus reset.
+    pub fn attach(&self) {
+        self.init();
+    }
+
+    /// Resumes device operation after the core became a peripheral through HNP.
+    ///
+    /// Unlike [`attach`](Self::attach) this doesn't reset the core, so the session is kept.
+    pub(crate) fn attach_negotiated(&self) {
+        critical_section::with(|cs| self.start(cs));
+    }
+
+    /// Sets the OTG protocols the core takes part in.
+    ///
+    /// HNP requires the core to pick its role from the ID pin and the negotiation, so device
+    /// mode is no longer forced. The core is reinitialized, so call this right after the
+    /// `UsbDevice` has been built. Use [`otg::Negotiation`](crate::otg::Negotiation) to
+    /// request sessions and role swaps.
+    pub fn set_otg_capabilities(&self, capabilities: OtgCapabilities) {
+        critical_section::with(|cs| self.otg.borrow(cs).set(capabilities));
+        self.init();
+    }
+
+    /// Queues `data` as a single multi-packet transfer on an IN endpoint.
+    ///
+    /// The transfer is reported as one `ep_in_complete` event when all of `data` has been sent.
+    /// If `zlp` is set and the size of `data` is a multiple of the maximum packet size, a
+    /// zero-length packet is sent afterwards to terminate the transfer.
+    ///
+    /// At most 1023 packets can be sent in one transfer. In DMA mode `data` is fetched by the
+    /// core directly and must be word-aligned.
+    pub fn write_transfer(
+        &self,
+        ep_addr: EndpointAddress,
+        data: &'static [u8],
+        zlp: bool,
+    ) -> Result<()> {
+        if !ep_addr.is_in() || ep_addr.index() >= USB::ENDPOINT_COUNT {
+            return Err(UsbError::InvalidEndpoint);
+        }
+        if let Some(ep) = &self.allocator.endpoints_in[ep_addr.index()] {
+            ep.write_transfer(data, zlp)
+        } else {
+            Err(UsbError::InvalidEndpoint)
+        }
+    }
+
+    /// Enables or disables Link Power Management.
+    ///
+    /// With LPM enabled the core acknowledges LPM tokens and enters the L1 sleep state, which is
+    /// reported by `poll` like a suspend, the return to L0 like a resume. LPM is only available
+    /// on newer cores (e.g. STM32L4, U5, H7) and has to be advertised to the host as well, with
+    /// the LPM bit of the USB 2.0 extension descriptor in the BOS.
+    ///
+    /// Returns `UsbError::Unsupported` when enabling LPM on a core without it, disabling it
+    /// there does nothing.
+    pub fn set_lpm(&self, config: Option<LpmConfig>) -> Result<()> {
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+            // F429-like cores have no GLPMCFG register
+            match read_reg!(otg_global, regs.global(), CID) {
+                0x0000_2000 | 0x0000_2100 | 0x0000_2300 | 0x0000_3000 | 0x0000_3100 => {}
+                _ if config.is_none() => return Ok(()),
+                _ => return Err(UsbError::Unsupported),
+            }
+
+            self.lpm.borrow(cs).set(config);
+
+            match config {
+                Some(config) => self.enable_lpm(cs, config),
+                None => {
+                    write_reg!(otg_global, regs.global(), GLPMCFG, 0);
+                    modify_reg!(otg_global, regs.global(), GINTMSK, LPMINTM: 0);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn enable_lpm(&self, cs: CriticalSection<'_>, config: LpmConfig) {
+        let regs = self.regs.borrow(cs);
+        write_reg!(otg_global, regs.global(), GLPMCFG,
+            LPMEN: 1,
+            LPMACK: 1,
+            ENBESL: 1,
+            BESLTHRS: config.besl_threshold as u32,
+            L1SSEN: 1,
+            L1DSEN: config.deep_sleep as u32
+        );
+        modify_reg!(otg_global, regs.global(), GINTMSK, LPMINTM: 1);
+    }
+
+    /// Returns the BESL the host requested with the LPM token if the link is in the L1 state.
+    pub fn l1_besl(&self) -> Option<u8> {
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+            self.lpm.borrow(cs).get()?;
+            let (sleeping, besl) = read_reg!(otg_global, regs.global(), GLPMCFG, SLPSTS, BESL);
+            if sleeping != 0 {
+                Some(besl as u8)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Enters a USB 2.0 electrical test mode.
+    ///
+    /// Call this while handling SET_FEATURE(TEST_MODE), `usb-device` leaves the request to the
+    /// classes. The mode is entered once the status stage of the request has completed, and is
+    /// only left by a power cycle of the device.
+    pub fn set_test_mode(&self, mode: TestMode) {
+        critical_section::with(|cs| self.test_mode.borrow(cs).set(Some(mode)));
+    }
+
+    /// Handles the USB interrupt.
+    ///
+    /// Acknowledges the pending interrupt sources, drains the Rx FIFO into the endpoint buffers
+    /// and records bus events and completed IN transfers. `poll` then only reports the recorded
+    /// state, so calling this from the OTG interrupt handler keeps the interrupt latency and the
+    /// critical section of `poll` short. `poll` calls it as well, applications that only poll
+    /// don't have to call it.
+    pub fn on_interrupt(&self) {
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+
+            // While suspended only bus activity restores the clocks
+            if read_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, STPPCLK) != 0 {
+                let (wakeup, reset, otg_int) =
+                    read_reg!(otg_global, regs.global(), GINTSTS, WKUPINT, USBRST, OTGINT);
+                if wakeup == 0 && reset == 0 && otg_int == 0 {
+                    return;
+                }
+                self.restore_clocks(cs);
+            }
+
+            let (wakeup, suspend, enum_done, reset, iep, oep, rxflvl, otg_int, session_request) = read_reg!(
+                otg_global,
+                regs.global(),
+                GINTSTS,
+                WKUPINT,
+                USBSUSP,
+                ENUMDNE,
+                USBRST,
+                IEPINT,
+                OEPINT,
+                RXFLVL,
+                OTGINT,
+                SRQINT
+            );
+            let incomplete_iso_in = read_reg!(otg_global, regs.global(), GINTSTS, IISOIXFR);
+            #[cfg(not(feature = "hs"))]
+            let incomplete_iso_out =
+                read_reg!(otg_global, regs.global(), GINTSTS, IPXFR_INCOMPISOOUT);
+            #[cfg(feature = "hs")]
+            let incomplete_iso_out =
+                read_reg!(otg_global, regs.global(), GINTSTS, PXFR_INCOMPISOOUT);
+
+            if reset != 0 {
+                write_reg!(otg_global, regs.global(), GINTSTS, USBRST: 1);
+
+                self.deconfigure_all(cs);
+                self.ep_in_complete.borrow(cs).set(0);
+                self.connected.borrow(cs).set(true);
+
+                // Flush RX
+                modify_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH: 1);
+                while read_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH) == 1 {}
+
+                if !self.allocator.dma {
+                    modify_reg!(otg_global, regs.global(), GINTMSK, RXFLVLM: 1);
+                }
+            }
+
+            if enum_done != 0 {
+                write_reg!(otg_global, regs.global(), GINTSTS, ENUMDNE: 1);
+
+                let speed = read_reg!(otg_device, regs.device(), DSTS, ENUMSPD);
+
+                // Compute and update TRDT. The AHB frequency has been validated on allocation of
+                // EP0, the slowest setting is a fallback only.
+                let high_speed = speed == 0b00;
+                let trdt =
+                    turnaround_time(self.peripheral.ahb_frequency_hz(), high_speed).unwrap_or(0xF);
+                modify_reg!(otg_global, regs.global(), GUSBCFG, TRDT: trdt);
+
+                self.add_event(cs, EVENT_RESET);
+            }
+
+            if suspend != 0 {
+                write_reg!(otg_global, regs.global(), GINTSTS, USBSUSP: 1);
+
+                self.add_event(cs, EVENT_SUSPEND);
+            }
+
+            if self.lpm.borrow(cs).get().is_some()
+                && read_reg!(otg_global, regs.global(), GINTSTS, LPMINT) != 0
+            {
+                write_reg!(otg_global, regs.global(), GINTSTS, LPMINT: 1);
+
+                // The LPM token has been acknowledged, the link enters L1
+                if read_reg!(otg_global, regs.global(), GLPMCFG, SLPSTS) != 0 {
+                    self.add_event(cs, EVENT_SUSPEND);
+                }
+            }
+
+            let otg = self.otg.borrow(cs).get();
+            if (otg.srp || otg.hnp) && (otg_int != 0 || session_request != 0) {
+                // Acknowledged here so that they don't retrigger the interrupt, `Negotiation::poll`
+                // reports them later
+                let pending = crate::otg::take_interrupts(*regs);
+                let latched = self.otg_interrupts.borrow(cs);
+                latched.set(latched.get() | pending);
+
+                // VBUS dropped below the session valid level, the cable has been unplugged
+                if pending & otg_global::GOTGINT::SEDET::mask != 0 {
+                    self.disconnect(cs);
+                }
+            } else {
+                if otg_int != 0 && read_reg!(otg_global, regs.global(), GOTGINT, SEDET) != 0 {
+                    write_reg!(otg_global, regs.global(), GOTGINT, SEDET: 1);
+
+                    self.disconnect(cs);
+                }
+
+                // VBUS is valid again, only unmasked while detached
+                if session_request != 0 {
+                    write_reg!(otg_global, regs.global(), GINTSTS, SRQINT: 1);
+                }
+            }
+
+            // Also signals the return from L1
+            if wakeup != 0 {
+                // Clear the interrupt
+                write_reg!(otg_global, regs.global(), GINTSTS, WKUPINT: 1);
+
+                self.add_event(cs, EVENT_RESUME);
+            }
+
+            // RXFLVL & IEPINT flags are read-only, there is no need to clear them
+            if rxflvl != 0 && !self.allocator.dma {
+                self.drain_rx_fifo(cs);
+            }
+
+            if oep != 0 && self.allocator.dma {
+                for ep in self.allocator.endpoints_out.iter().flatten() {
+                    ep.complete_dma(cs);
+                }
+            }
+
+            if iep != 0 {
+                use crate::ral::endpoint_in;
+
+                let ep_in_complete = self.ep_in_complete.borrow(cs);
+                for ep in self.allocator.endpoints_in.iter().flatten() {
+                    let ep_regs = regs.endpoint_in(ep.address().index());
+                    if read_reg!(endpoint_in, ep_regs, DIEPINT, XFRC) != 0 {
+                        write_reg!(endpoint_in, ep_regs, DIEPINT, XFRC: 1);
+
+                        // The status stage of SET_FEATURE(TEST_MODE) has been sent
+                        if ep.address().index() == 0 {
+                            if let Some(mode) = self.test_mode.borrow(cs).take() {
+                                modify_reg!(otg_device, regs.device(), DCTL, TCTL: mode.tctl());
+                            }
+                        }
+
+                        if ep.complete(cs) {
+                            ep_in_complete.set(ep_in_complete.get() | 1 << ep.address().index());
+                        }
+                    }
+                    if ep.needs_refill(cs) && read_reg!(endpoint_in, ep_regs, DIEPINT, TXFE) != 0 {
+                        ep.refill(cs);
+                    }
+                }
+            }
+
+            if incomplete_iso_in != 0 {
+                write_reg!(otg_global, regs.global(), GINTSTS, IISOIXFR: 1);
+
+                // The endpoint is free again, as if the packet had been sent
+                let ep_in_complete = self.ep_in_complete.borrow(cs);
+                for ep in self.allocator.endpoints_in.iter().flatten() {
+                    if ep.drop_incomplete_iso(cs) {
+                        ep_in_complete.set(ep_in_complete.get() | 1 << ep.address().index());
+                    }
+                }
+            }
 
-        UsbBusAllocator::new(bus)
-    }
+            if incomplete_iso_out != 0 {
+                #[cfg(not(feature = "hs"))]
+                write_reg!(otg_global, regs.global(), GINTSTS, IPXFR_INCOMPISOOUT: 1);
+                #[cfg(feature = "hs")]
+                write_reg!(otg_global, regs.global(), GINTSTS, PXFR_INCOMPISOOUT: 1);
 
-    pub fn free(self) -> USB {
-        self.peripheral
+                for ep in self.allocator.endpoints_out.iter().flatten() {
+                    ep.reschedule_incomplete_iso();
+                }
+            }
+        });
     }
 
-    fn configure_all(&self, cs: CriticalSection<'_>) {
+    /// Restores the core's clocks gated by `suspend`.
+    fn restore_clocks(&self, cs: CriticalSection<'_>) {
         let regs = self.regs.borrow(cs);
+        if read_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, STPPCLK, GATEHCLK) == (0, 0) {
+            return;
+        }
 
-        // Rx FIFO
-        // This calculation doesn't correspond to one in a Reference Manual.
-        // In fact, the required number of words is higher than indicated in RM.
-        // The following numbers are pessimistic and were figured out empirically.
-        let rx_fifo_size = if USB::HIGH_SPEED {
-            self.allocator.memory_allocator.total_rx_buffer_size_words() + 30
-        } else {
-            // F429 requires 35+ words for the (EP0[8] + EP2[64]) setup
-            // F446 requires 39+ words for the same setup
-            self.allocator.memory_allocator.total_rx_buffer_size_words() + 30
-        };
-        write_reg!(otg_global, regs.global(), GRXFSIZ, rx_fifo_size as u32);
-        let mut fifo_top = rx_fifo_size;
-
-        // Tx FIFO #0
-        let fifo_size = self.allocator.memory_allocator.tx_fifo_size_words(0);
+        self.peripheral.exit_suspend();
 
-        #[cfg(feature = "fs")]
-        write_reg!(otg_global, regs.global(), DIEPTXF0,
-            TX0FD: fifo_size as u32,
-            TX0FSA: fifo_top as u32
-        );
-        #[cfg(feature = "hs")]
-        write_reg!(otg_global, regs.global(), GNPTXFSIZ,
-            TX0FD: fifo_size as u32,
-            TX0FSA: fifo_top as u32
-        );
+        modify_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, GATEHCLK: 0);
+        modify_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, STPPCLK: 0);
+    }
 
-        fifo_top += fifo_size;
+    /// Deconfigures the endpoints after the device has been unplugged.
+    ///
+    /// Pending bus events are superseded by the disconnect.
+    fn disconnect(&self, cs: CriticalSection<'_>) {
+        if !self.connected.borrow(cs).replace(false) {
+            return;
+        }
 
-        // Tx FIFOs
-        for i in 1..USB::ENDPOINT_COUNT {
-            let fifo_size = self.allocator.memory_allocator.tx_fifo_size_words(i);
+        self.deconfigure_all(cs);
+        self.ep_in_complete.borrow(cs).set(0);
+        self.events.borrow(cs).set(EVENT_DISCONNECT);
+    }
 
-            let dieptxfx = regs.dieptxfx(i);
-            write_reg!(otg_global_dieptxfx, dieptxfx, DIEPTXFx,
-                INEPTXFD: fifo_size as u32,
-                INEPTXSA: fifo_top as u32
-            );
+    /// Records a bus event for `poll`.
+    ///
+    /// Suspend and resume cancel each other, only the latest of them is reported.
+    fn add_event(&self, cs: CriticalSection<'_>, event: u8) {
+        let events = self.events.borrow(cs);
+        let pending = match event {
+            EVENT_SUSPEND => events.get() & !EVENT_RESUME,
+            EVENT_RESUME => events.get() & !EVENT_SUSPEND,
+            _ => events.get(),
+        };
+        events.set(pending | event);
+    }
 
-            fifo_top += fifo_size;
-        }
+    /// Moves received packets from the Rx FIFO into the endpoint buffers.
+    ///
+    /// A packet for an endpoint without a free buffer stays in the FIFO and blocks the ones
+    /// behind it, the Rx FIFO interrupt is then masked until the endpoint is read.
+    fn drain_rx_fifo(&self, cs: CriticalSection<'_>) {
+        use crate::ral::endpoint_in;
 
-        assert!(fifo_top as usize <= USB::FIFO_DEPTH_WORDS);
+        let regs = self.regs.borrow(cs);
+        let core_id = read_reg!(otg_global, regs.global(), CID);
 
-        // Flush Rx & Tx FIFOs
-        modify_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH: 1, TXFFLSH: 1, TXFNUM: 0x10);
-        while read_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH, TXFFLSH) != (0, 0) {}
+        while read_reg!(otg_global, regs.global(), GINTSTS, RXFLVL) != 0 {
+            let (epnum, data_size, dpid, status) = read_reg!(
+                otg_global,
+                regs.global(),
+                GRXSTSR,
+                EPNUM,
+                BCNT,
+                DPID,
+                PKTSTS
+            );
+            match status {
+                0x02 | 0x06 => {
+                    // OUT received | SETUP received
+                    let ep = match &self.allocator.endpoints_out[epnum as usize] {
+                        Some(ep) => ep,
+                        None => {
+                            modify_reg!(otg_global, regs.global(), GINTMSK, RXFLVLM: 0);
+                            return;
+                        }
+                    };
+                    let mut buffer = ep.buffer.borrow_ref_mut(cs);
+                    if buffer.is_full() {
+                        modify_reg!(otg_global, regs.global(), GINTMSK, RXFLVLM: 0);
+                        return;
+                    }
 
-        for ep in &self.allocator.endpoints_in {
-            if let Some(ep) = ep {
-                // enabling EP TX interrupt
-                modify_reg!(otg_device, regs.device(), DAINTMSK, |v| v
-                    | (0x0001 << ep.address().index()));
+                    let is_setup = status == 0x06;
+                    if is_setup {
+                        // flushing TX if something stuck in control endpoint
+                        let ep = regs.endpoint_in(epnum as usize);
+                        if read_reg!(endpoint_in, ep, DIEPTSIZ, PKTCNT) != 0 {
+                            modify_reg!(otg_global, regs.global(), GRSTCTL, TXFNUM: epnum, TXFFLSH: 1);
+                            while read_reg!(otg_global, regs.global(), GRSTCTL, TXFFLSH) == 1 {}
+                        }
+                    }
 
-                ep.configure(cs);
+                    // High-bandwidth transfers continue after MDATA packets
+                    let last = dpid != 0b11;
+
+                    read_reg!(otg_global, regs.global(), GRXSTSP); // pop GRXSTSP
+                    buffer
+                        .fill_from_fifo(*regs, data_size as u16, is_setup, last)
+                        .ok();
+
+                    // Re-enable the endpoint, F446-like chips only
+                    if last
+                        && matches!(
+                            core_id,
+                            0x0000_2000 | 0x0000_2100 | 0x0000_2300 | 0x0000_3000 | 0x0000_3100
+                        )
+                    {
+                        ep.enable();
+                    }
+                }
+                0x03 | 0x04 => {
+                    // OUT completed | SETUP completed
+                    // Re-enable the endpoint, F429-like chips only
+                    if core_id == 0x0000_1200 || core_id == 0x0000_1100 {
+                        if let Some(ep) = &self.allocator.endpoints_out[epnum as usize] {
+                            ep.enable();
+                        }
+                    }
+                    read_reg!(otg_global, regs.global(), GRXSTSP); // pop GRXSTSP
+                }
+                _ => {
+                    read_reg!(otg_global, regs.global(), GRXSTSP); // pop GRXSTSP
+                }
             }
         }
+    }
 
-        for ep in &self.allocator.endpoints_out {
-            if let Some(ep) = ep {
-                if ep.address().index() == 0 {
-                    // enabling RX interrupt from EP0
-                    modify_reg!(otg_device, regs.device(), DAINTMSK, |v| v | 0x00010000);
-                }
+    /// Signals remote wakeup to the host while the bus is suspended.
+    ///
+    /// Resume signalling is driven for 10 ms (1..15 ms according to the USB specification), the
+    /// next `poll` then reports the device as resumed. Only call this when the host has enabled
+    /// the feature, see `UsbDevice::remote_wakeup_enabled`.
+    ///
+    /// Returns `UsbError::InvalidState` if the bus isn't suspended.
+    pub fn remote_wakeup(&self, delay: &mut impl DelayMs<u32>) -> Result<()> {
+        critical_section::with(|cs| self.start_remote_wakeup(cs))?;
 
-                ep.configure(cs);
-            }
+        delay.delay_ms(10);
+
+        critical_section::with(|cs| {
+            self.stop_remote_wakeup(cs);
+
+            // The host doesn't signal resume itself, so there is no wakeup interrupt
+            self.add_event(cs, EVENT_RESUME);
+        });
+        Ok(())
+    }
+
+    /// Starts driving resume signalling, see [`remote_wakeup`](Self::remote_wakeup).
+    pub(crate) fn start_remote_wakeup(&self, cs: CriticalSection<'_>) -> Result<()> {
+        let regs = self.regs.borrow(cs);
+        self.restore_clocks(cs);
+        if read_reg!(otg_device, regs.device(), DSTS, SUSPSTS) == 0 {
+            return Err(UsbError::InvalidState);
         }
+        modify_reg!(otg_device, regs.device(), DCTL, RWUSIG: 1);
+        Ok(())
     }
 
-    fn deconfigure_all(&self, cs: CriticalSection<'_>) {
+    /// Stops driving resume signalling.
+    pub(crate) fn stop_remote_wakeup(&self, cs: CriticalSection<'_>) {
         let regs = self.regs.borrow(cs);
+        modify_reg!(otg_device, regs.device(), DCTL, RWUSIG: 0);
+    }
 
-        // disable interrupts
-        modify_reg!(otg_device, regs.device(), DAINTMSK, IEPM: 0, OEPM: 0);
+    /// Detects the type of the port the device is plugged into (USB Battery Charging 1.2).
+    ///
+    /// Runs the data contact, primary and secondary detection of the battery charging detector
+    /// in the full-speed PHY. This takes about 400 ms. Call it while VBUS is present and before
+    /// the bus is enabled by `UsbDeviceBuilder::build`, since the D+ pull-up must not be
+    /// connected. The core is reset and left disconnected, `enable` initializes it again.
+    ///
+    /// Returns `UsbError::Unsupported` if the core has no charger detector (F429-like cores) or
+    /// the peripheral doesn't use the internal full-speed PHY.
+    pub fn detect_charger(peripheral: &USB, delay: &mut impl DelayMs<u32>) -> Result<ChargerType> {
+        if peripheral.phy_type() != PhyType::InternalFullSpeed {
+            return Err(UsbError::Unsupported);
+        }
 
-        for ep in &self.allocator.endpoints_in {
-            if let Some(ep) = ep {
-                ep.deconfigure(cs);
-            }
+        // Enable USB_OTG in RCC
+        USB::enable();
+
+        let regs = UsbRegisters::new::<USB>();
+        match read_reg!(otg_global, regs.global(), CID) {
+            0x0000_2000 | 0x0000_2100 | 0x0000_2300 | 0x0000_3000 | 0x0000_3100 => {}
+            _ => return Err(UsbError::Unsupported),
         }
 
-        for ep in &self.allocator.endpoints_out {
-            if let Some(ep) = ep {
-                ep.deconfigure(cs);
+        let otg = OtgCapabilities::default();
+        crate::target::init_core(peripheral, regs, CoreMode::Device, otg, 1);
+
+        // Keep D+ released while the detector drives the data lines
+        modify_reg!(otg_device, regs.device(), DCTL, SDIS: 1);
+
+        // The detector works with the transceiver powered down
+        modify_reg!(otg_global, regs.global(), GCCFG, PWRDWN: 0);
+        modify_reg!(otg_global, regs.global(), GCCFG, BCDEN: 1);
+        delay.delay_ms(50);
+
+        // Data contact detection. Without contact (e.g. a proprietary charger) the detection
+        // continues after the timeout.
+        modify_reg!(otg_global, regs.global(), GCCFG, DCDEN: 1);
+        for _ in 0..30 {
+            delay.delay_ms(10);
+            if read_reg!(otg_global, regs.global(), GCCFG, DCDET) != 0 {
+                break;
             }
         }
+        modify_reg!(otg_global, regs.global(), GCCFG, DCDEN: 0);
+        delay.delay_ms(50);
+
+        // Primary detection: standard downstream port or charging port
+        modify_reg!(otg_global, regs.global(), GCCFG, PDEN: 1);
+        delay.delay_ms(50);
+        let (primary, ps2) = read_reg!(otg_global, regs.global(), GCCFG, PDET, PS2DET);
+        modify_reg!(otg_global, regs.global(), GCCFG, PDEN: 0);
+        delay.delay_ms(50);
+
+        let charger = if primary == 0 {
+            if ps2 != 0 {
+                ChargerType::Proprietary
+            } else {
+                ChargerType::StandardDownstreamPort
+            }
+        } else {
+            // Secondary detection: dedicated or charging downstream port
+            modify_reg!(otg_global, regs.global(), GCCFG, SDEN: 1);
+            delay.delay_ms(50);
+            let secondary = read_reg!(otg_global, regs.global(), GCCFG, SDET);
+            modify_reg!(otg_global, regs.global(), GCCFG, SDEN: 0);
+
+            if secondary != 0 {
+                ChargerType::DedicatedChargingPort
+            } else {
+                ChargerType::ChargingDownstreamPort
+            }
+        };
+
+        // Disable the detector and power the transceiver up again
+        modify_reg!(otg_global, regs.global(), GCCFG, BCDEN: 0);
+        modify_reg!(otg_global, regs.global(), GCCFG, PWRDWN: 1);
+
+        Ok(charger)
     }
 
     pub fn force_reset(&self, delay: &mut impl DelayMs<u32>) -> Result<()> {
@@ -146,23 +1233,24 @@ impl<USB: UsbPeripheral> UsbBus<USB> {
     ///
     /// Interrupts are disabled for the duration of the function call.
     ///
-    /// **Panics:** if `phy_type` is not `PhyType::ExternalHighSpeed`.
+    /// Returns `UlpiError::UnsupportedPhy` if `phy_type` is not `PhyType::ExternalHighSpeed`.
     ///
     /// # Example
     ///
     /// ```
+    /// # use synopsys_usb_otg::bus::UlpiError;
     /// # use synopsys_usb_otg::{UsbPeripheral, UsbBus};
-    /// fn read_usb_vid_pid<USB: UsbPeripheral>(bus: &UsbBus<USB>) -> (u16, u16) {
-    ///     let mut vid: u16 = bus.ulpi_read(0x00) as u16;
-    ///     vid |= (bus.ulpi_read(0x01) as u16) << 8;
-    ///     let mut pid: u16 = bus.ulpi_read(0x02) as u16;
-    ///     pid |= (bus.ulpi_read(0x03) as u16) << 8;
-    ///     (vid, pid)
+    /// fn read_usb_vid_pid<USB: UsbPeripheral>(bus: &UsbBus<USB>) -> Result<(u16, u16), UlpiError> {
+    ///     let mut vid: u16 = bus.ulpi_read(0x00)? as u16;
+    ///     vid |= (bus.ulpi_read(0x01)? as u16) << 8;
+    ///     let mut pid: u16 = bus.ulpi_read(0x02)? as u16;
+    ///     pid |= (bus.ulpi_read(0x03)? as u16) << 8;
+    ///     Ok((vid, pid))
     /// }
     /// ```
     pub fn ulpi_read(&self, addr: u8) -> core::result::Result<u8, UlpiError> {
         if self.peripheral.phy_type() != PhyType::ExternalHighSpeed {
-            panic!("ulpi_read is only supported with external ULPI PHYs");
+            return Err(UlpiError::UnsupportedPhy);
         }
 
         critical_section::with(|cs| {
@@ -194,10 +1282,10 @@ impl<USB: UsbPeripheral> UsbBus<USB> {
     ///
     /// Interrupts are disabled for the duration of the function call.
     ///
-    /// **Panics:** if `phy_type` is not `PhyType::ExternalHighSpeed`.
+    /// Returns `UlpiError::UnsupportedPhy` if `phy_type` is not `PhyType::ExternalHighSpeed`.
     pub fn ulpi_write(&self, addr: u8, data: u8) -> core::result::Result<(), UlpiError> {
         if self.peripheral.phy_type() != PhyType::ExternalHighSpeed {
-            panic!("ulpi_write is only supported with external ULPI PHYs");
+            return Err(UlpiError::UnsupportedPhy);
         }
 
         critical_section::with(|cs| {
@@ -231,9 +1319,42 @@ impl<USB: UsbPeripheral> UsbBus<USB> {
 pub enum UlpiError {
     /// The action has timed out.
     Timeout,
+    /// The peripheral doesn't use an external ULPI PHY.
+    UnsupportedPhy,
+}
+
+/// Returns the USB turnaround time (GUSBCFG.TRDT) for the AHB frequency, `None` if the
+/// frequency is too low for the speed.
+fn turnaround_time(ahb_frequency_hz: u32, high_speed: bool) -> Option<u32> {
+    if high_speed {
+        // From RM0431 (F72xx), RM0090 (F429), RM0390 (F446)
+        return if ahb_frequency_hz >= 30_000_000 {
+            Some(0x9)
+        } else {
+            None
+        };
+    }
+
+    // From RM0431 (F72xx), RM0090 (F429)
+    let trdt = match ahb_frequency_hz {
+        0..=14_199_999 => return None,
+        14_200_000..=14_999_999 => 0xF,
+        15_000_000..=15_999_999 => 0xE,
+        16_000_000..=17_199_999 => 0xD,
+        17_200_000..=18_499_999 => 0xC,
+        18_500_000..=19_999_999 => 0xB,
+        20_000_000..=21_799_999 => 0xA,
+        21_800_000..=23_999_999 => 0x9,
+        24_000_000..=27_499_999 => 0x8,
+        27_500_000..=31_999_999 => 0x7, // 27.7..32 in code from CubeIDE
+        32_000_000..=u32::MAX => 0x6,
+    };
+    Some(trdt)
 }
 
 pub(crate) struct EndpointAllocator<USB> {
+    dma: bool,
+    out_buffers: usize,
     bitmap_in: u8,
     bitmap_out: u8,
     endpoints_in: [Option<EndpointIn>; 9],
@@ -243,15 +1364,17 @@ pub(crate) struct EndpointAllocator<USB> {
 }
 
 impl<USB: UsbPeripheral> EndpointAllocator<USB> {
-    fn new(memory: &'static mut [u32]) -> Self {
+    fn new(memory: &'static mut [u32], dma: bool, out_buffers: usize, rx_fifo_slack: u16) -> Self {
         assert!(USB::ENDPOINT_COUNT <= 9);
         Self {
+            dma,
+            out_buffers,
             bitmap_in: 0,
             bitmap_out: 0,
             // [None; 9] requires Copy
             endpoints_in: [None, None, None, None, None, None, None, None, None],
             endpoints_out: [None, None, None, None, None, None, None, None, None],
-            memory_allocator: EndpointMemoryAllocator::new(memory),
+            memory_allocator: EndpointMemoryAllocator::new(memory, rx_fifo_slack),
             _marker: PhantomData,
         }
     }
@@ -298,8 +1421,16 @@ impl<USB: UsbPeripheral> EndpointAllocator<USB> {
         let descr = Self::alloc(&mut self.bitmap_in, config, UsbDirection::In)?;
 
         self.memory_allocator
-            .allocate_tx_buffer(descr.address.index() as u8, descr.max_packet_size as usize)?;
-        let ep = EndpointIn::new::<USB>(descr);
+            .allocate_tx_buffer(descr.address.index() as u8, descr.max_payload_size())?;
+        let dma_buffer = if self.dma {
+            Some(
+                self.memory_allocator
+                    .allocate_dma_tx_buffer(descr.max_payload_size())?,
+            )
+        } else {
+            None
+        };
+        let ep = EndpointIn::new::<USB>(descr, dma_buffer);
 
         Ok(ep)
     }
@@ -307,10 +1438,20 @@ impl<USB: UsbPeripheral> EndpointAllocator<USB> {
     fn alloc_out(&mut self, config: &EndpointConfig) -> Result<EndpointOut> {
         let descr = Self::alloc(&mut self.bitmap_out, config, UsbDirection::Out)?;
 
-        let buffer = self
-            .memory_allocator
-            .allocate_rx_buffer(descr.max_packet_size as usize)?;
-        let ep = EndpointOut::new::<USB>(descr, buffer);
+        let size = if self.dma && descr.address.index() == 0 {
+            // Room for three back-to-back SETUP packets
+            core::cmp::max(descr.max_packet_size as usize, 24)
+        } else {
+            descr.max_payload_size()
+        };
+        let mut buffer = EndpointBufferQueue::new();
+        buffer.push_buffer(self.memory_allocator.allocate_rx_buffer(size)?);
+        if descr.address.index() != 0 {
+            for _ in 1..self.out_buffers {
+                buffer.push_buffer(self.memory_allocator.allocate_queued_rx_buffer(size)?);
+            }
+        }
+        let ep = EndpointOut::new::<USB>(descr, buffer, self.dma);
 
         Ok(ep)
     }
@@ -323,6 +1464,27 @@ impl<USB: UsbPeripheral> EndpointAllocator<USB> {
         max_packet_size: u16,
         interval: u8,
     ) -> Result<EndpointAddress> {
+        // Additional transactions per microframe (bits 12..11 of wMaxPacketSize) are only allowed
+        // for high-bandwidth isochronous and interrupt endpoints of high-speed cores
+        let additional_transactions = max_packet_size >> 11;
+        if additional_transactions != 0 {
+            let periodic = matches!(
+                ep_type,
+                EndpointType::Isochronous { .. } | EndpointType::Interrupt
+            );
+            if !USB::HIGH_SPEED
+                || !periodic
+                || additional_transactions > 0b10
+                || max_packet_size & 0x7ff > 1024
+            {
+                return Err(UsbError::Unsupported);
+            }
+        }
+
+        if matches!(ep_addr, Some(a) if a.index() == 0) && ep0_mpsiz(max_packet_size).is_none() {
+            return Err(UsbError::Unsupported);
+        }
+
         let ep_type = unsafe { core::mem::transmute(ep_type) };
         let number = ep_addr.map(|a| a.index() as u8);
 
@@ -359,6 +1521,17 @@ impl<USB: UsbPeripheral> usb_device::bus::UsbBus for UsbBus<USB> {
         max_packet_size: u16,
         interval: u8,
     ) -> Result<EndpointAddress> {
+        // The control endpoint is allocated first, an unsupported clock configuration fails the
+        // setup instead of the enumeration
+        if matches!(ep_addr, Some(a) if a.index() == 0) {
+            let high_speed = USB::HIGH_SPEED
+                && self.peripheral.phy_type() != PhyType::InternalFullSpeed
+                && self.config.max_speed == Speed::High;
+            if turnaround_time(self.peripheral.ahb_frequency_hz(), high_speed).is_none() {
+                return Err(UsbError::Unsupported);
+            }
+        }
+
         self.allocator
             .alloc_ep(ep_dir, ep_addr, ep_type, max_packet_size, interval)
     }
@@ -367,162 +1540,7 @@ impl<USB: UsbPeripheral> usb_device::bus::UsbBus for UsbBus<USB> {
         // Enable USB_OTG in RCC
         USB::enable();
 
-        critical_section::with(|cs| {
-            let regs = self.regs.borrow(cs);
-
-            let core_id = read_reg!(otg_global, regs.global(), CID);
-
-            // Wait for AHB ready
-            while read_reg!(otg_global, regs.global(), GRSTCTL, AHBIDL) == 0 {}
-
-            // Configure OTG as device
-            #[cfg(feature = "fs")]
-            modify_reg!(otg_global, regs.global(), GUSBCFG,
-                SRPCAP: 0, // SRP capability is not enabled
-                FDMOD: 1 // Force device mode
-            );
-            #[cfg(feature = "hs")]
-            modify_reg!(otg_global, regs.global(), GUSBCFG,
-                SRPCAP: 0, // SRP capability is not enabled
-                TOCAL: 0x1,
-                FDMOD: 1 // Force device mode
-            );
-
-            // Configure USB PHY
-            #[cfg(feature = "hs")]
-            match self.peripheral.phy_type() {
-                PhyType::InternalFullSpeed => {
-                    // Select FS Embedded PHY
-                    modify_reg!(otg_global, regs.global(), GUSBCFG, PHYSEL: 1);
-                }
-                PhyType::InternalHighSpeed => {
-                    // Turn off PHY
-                    modify_reg!(otg_global, regs.global(), GCCFG, PWRDWN: 0);
-
-                    // Init The UTMI Interface
-                    modify_reg!(otg_global, regs.global(), GUSBCFG,
-                        TSDPS: 0,
-                        ULPIFSLS: 0,
-                        PHYSEL: 0 // ULPI or UTMI
-                    );
-
-                    // Select VBUS source
-                    modify_reg!(otg_global, regs.global(), GUSBCFG,
-                        ULPIEVBUSD: 0,
-                        ULPIEVBUSI: 0
-                    );
-
-                    // Select UTMI Interace
-                    //modify_reg!(otg_global, regs.global(), GUSBCFG, ULPISEL: 0);
-                    modify_reg!(otg_global, regs.global(), GUSBCFG, |r| r & !(1 << 4));
-
-                    // This is a secret bit from ST that is not mentioned anywhere except
-                    // the driver code shipped with STM32CubeIDE.
-                    //modify_reg!(otg_global, regs.global(), GCCFG, PHYHSEN: 1);
-                    modify_reg!(otg_global, regs.global(), GCCFG, |r| r | (1 << 23));
-
-                    self.peripheral.setup_internal_hs_phy();
-                }
-                PhyType::ExternalHighSpeed => {
-                    // Turn off embedded PHY
-                    modify_reg!(otg_global, regs.global(), GCCFG, PWRDWN: 0);
-
-                    // Init The ULPI Interface
-                    modify_reg!(otg_global, regs.global(), GUSBCFG,
-                        TSDPS: 0,
-                        ULPIFSLS: 0,
-                        PHYSEL: 0 // ULPI or UTMI
-                    );
-
-                    // Select VBUS source
-                    modify_reg!(otg_global, regs.global(), GUSBCFG,
-                        ULPIEVBUSD: 0,
-                        ULPIEVBUSI: 0
-                    );
-                }
-            }
-
-            // Proposed fix for core reset hang on stm32h7 / cortex-m7
-            // https://github.com/stm32-rs/stm32h7xx-hal/issues/503
-            #[cfg(feature = "cortex-m")]
-            {
-                cortex_m::asm::isb();
-                cortex_m::asm::dsb();
-                cortex_m::asm::dmb();
-            }
-
-            // Perform core soft-reset
-            while read_reg!(otg_global, regs.global(), GRSTCTL, AHBIDL) == 0 {}
-            modify_reg!(otg_global, regs.global(), GRSTCTL, CSRST: 1);
-            while read_reg!(otg_global, regs.global(), GRSTCTL, CSRST) == 1 {}
-
-            if self.peripheral.phy_type() == PhyType::InternalFullSpeed {
-                // Activate the USB Transceiver
-                modify_reg!(otg_global, regs.global(), GCCFG, PWRDWN: 1);
-            }
-
-            // Configuring Vbus sense and SOF output
-            match core_id {
-                0x0000_1200 | 0x0000_1100 => {
-                    // F429-like chips have the GCCFG.NOVBUSSENS bit
-
-                    //modify_reg!(otg_global, regs.global, GCCFG, NOVBUSSENS: 1);
-                    modify_reg!(otg_global, regs.global(), GCCFG, |r| r | (1 << 21));
-
-                    modify_reg!(otg_global, regs.global(), GCCFG, VBUSASEN: 0, VBUSBSEN: 0, SOFOUTEN: 0);
-                }
-                0x0000_2000 | 0x0000_2100 | 0x0000_2300 | 0x0000_3000 | 0x0000_3100 => {
-                    // F446-like chips have the GCCFG.VBDEN bit with the opposite meaning
-
-                    //modify_reg!(otg_global, regs.global, GCCFG, VBDEN: 0);
-                    modify_reg!(otg_global, regs.global(), GCCFG, |r| r & !(1 << 21));
-
-                    // Force B-peripheral session
-                    //modify_reg!(otg_global, regs.global, GOTGCTL, BVALOEN: 1, BVALOVAL: 1);
-                    modify_reg!(otg_global, regs.global(), GOTGCTL, |r| r | (0b11 << 6));
-                }
-                _ => {}
-            }
-
-            // Enable PHY clock
-            write_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, 0);
-
-            // Soft disconnect device
-            modify_reg!(otg_device, regs.device(), DCTL, SDIS: 1);
-
-            // Setup USB speed and frame interval
-            let speed = match (USB::HIGH_SPEED, self.peripheral.phy_type()) {
-                (false, _) => 0b11,
-                (true, PhyType::InternalFullSpeed) => 0b11,
-                (true, PhyType::InternalHighSpeed) => 0b00,
-                (true, PhyType::ExternalHighSpeed) => 0b00,
-            };
-            modify_reg!(otg_device, regs.device(), DCFG,
-                PFIVL: 0b00,
-                DSPD: speed
-            );
-            #[cfg(feature = "xcvrdly")]
-            modify_reg!(otg_device, regs.device(), DCFG, XCVRDLY: 1);
-
-            // unmask EP interrupts
-            write_reg!(otg_device, regs.device(), DIEPMSK, XFRCM: 1);
-
-            // unmask core interrupts
-            write_reg!(otg_global, regs.global(), GINTMSK,
-                USBRST: 1, ENUMDNEM: 1,
-                USBSUSPM: 1, WUIM: 1,
-                IEPINT: 1, RXFLVLM: 1
-            );
-
-            // clear pending interrupts
-            write_reg!(otg_global, regs.global(), GINTSTS, 0xffffffff);
-
-            // unmask global interrupt
-            modify_reg!(otg_global, regs.global(), GAHBCFG, GINT: 1);
-
-            // connect(true)
-            modify_reg!(otg_device, regs.device(), DCTL, SDIS: 0);
-        });
+        self.init();
     }
 
     fn reset(&self) {
@@ -560,7 +1578,15 @@ impl<USB: UsbPeripheral> usb_device::bus::UsbBus for UsbBus<USB> {
         }
 
         if let Some(ep) = &self.allocator.endpoints_out[ep_addr.index()] {
-            ep.read(buf)
+            let result = ep.read(buf);
+            if result.is_ok() && !self.allocator.dma {
+                // A packet waiting for the freed buffer may be blocking the Rx FIFO
+                critical_section::with(|cs| {
+                    let regs = self.regs.borrow(cs);
+                    modify_reg!(otg_global, regs.global(), GINTMSK, RXFLVLM: 1);
+                });
+            }
+            result
         } else {
             Err(UsbError::InvalidEndpoint)
         }
@@ -585,180 +1611,68 @@ impl<USB: UsbPeripheral> usb_device::bus::UsbBus for UsbBus<USB> {
     }
 
     fn suspend(&self) {
-        // Nothing to do here?
-    }
-
-    fn resume(&self) {
-        // Nothing to do here?
-    }
-
-    fn poll(&self) -> PollResult {
         critical_section::with(|cs| {
             let regs = self.regs.borrow(cs);
 
-            let core_id = read_reg!(otg_global, regs.global(), CID);
-
-            let (wakeup, suspend, enum_done, reset, iep, rxflvl) = read_reg!(
-                otg_global,
-                regs.global(),
-                GINTSTS,
-                WKUPINT,
-                USBSUSP,
-                ENUMDNE,
-                USBRST,
-                IEPINT,
-                RXFLVL
-            );
+            // The host may have resumed the bus in the meantime
+            if read_reg!(otg_device, regs.device(), DSTS, SUSPSTS) == 0 {
+                return;
+            }
 
-            if reset != 0 {
-                write_reg!(otg_global, regs.global(), GINTSTS, USBRST: 1);
+            // Stop the PHY clock, an external PHY is suspended as well
+            modify_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, STPPCLK: 1);
+            if self.peripheral.phy_type() == PhyType::ExternalHighSpeed {
+                let mut timeout = 1000;
+                while read_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, PHYSUSP) == 0 && timeout > 0 {
+                    timeout -= 1;
+                }
+            }
 
-                self.deconfigure_all(cs);
+            // Gate HCLK to everything but the register interface and the wakeup logic
+            modify_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, GATEHCLK: 1);
 
-                // Flush RX
-                modify_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH: 1);
-                while read_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH) == 1 {}
-            }
+            self.peripheral.enter_suspend();
+        });
+    }
 
-            if enum_done != 0 {
-                write_reg!(otg_global, regs.global(), GINTSTS, ENUMDNE: 1);
+    fn resume(&self) {
+        critical_section::with(|cs| self.restore_clocks(cs));
+    }
 
-                let speed = read_reg!(otg_device, regs.device(), DSTS, ENUMSPD);
+    fn poll(&self) -> PollResult {
+        self.on_interrupt();
 
-                // Compute and update TRDT
-                let trdt;
-                match speed {
-                    0b00 => {
-                        // High speed
-
-                        // From RM0431 (F72xx), RM0090 (F429), RM0390 (F446)
-                        if self.peripheral.ahb_frequency_hz() >= 30_000_000 {
-                            trdt = 0x9;
-                        } else {
-                            panic!("AHB frequency is too low")
-                        }
-                    }
-                    0b01 | 0b11 => {
-                        // Full speed
-
-                        // From RM0431 (F72xx), RM0090 (F429)
-                        trdt = match self.peripheral.ahb_frequency_hz() {
-                            0..=14_199_999 => panic!("AHB frequency is too low"),
-                            14_200_000..=14_999_999 => 0xF,
-                            15_000_000..=15_999_999 => 0xE,
-                            16_000_000..=17_199_999 => 0xD,
-                            17_200_000..=18_499_999 => 0xC,
-                            18_500_000..=19_999_999 => 0xB,
-                            20_000_000..=21_799_999 => 0xA,
-                            21_800_000..=23_999_999 => 0x9,
-                            24_000_000..=27_499_999 => 0x8,
-                            27_500_000..=31_999_999 => 0x7, // 27.7..32 in code from CubeIDE
-                            32_000_000..=u32::MAX => 0x6,
-                        };
-                    }
-                    _ => unimplemented!(),
-                }
-                modify_reg!(otg_global, regs.global(), GUSBCFG, TRDT: trdt);
+        critical_section::with(|cs| {
+            let events = self.events.borrow(cs);
+            let pending = events.get();
 
+            if pending & EVENT_DISCONNECT != 0 {
+                // There is no disconnected state in usb-device, see `is_connected`
+                events.set(pending & !EVENT_DISCONNECT);
+                PollResult::Suspend
+            } else if pending & EVENT_RESET != 0 {
+                events.set(pending & !EVENT_RESET);
                 PollResult::Reset
-            } else if wakeup != 0 {
-                // Clear the interrupt
-                write_reg!(otg_global, regs.global(), GINTSTS, WKUPINT: 1);
-
+            } else if pending & EVENT_RESUME != 0 {
+                events.set(pending & !EVENT_RESUME);
                 PollResult::Resume
-            } else if suspend != 0 {
-                write_reg!(otg_global, regs.global(), GINTSTS, USBSUSP: 1);
-
+            } else if pending & EVENT_SUSPEND != 0 {
+                events.set(pending & !EVENT_SUSPEND);
                 PollResult::Suspend
             } else {
                 let mut ep_out = 0;
-                let mut ep_in_complete = 0;
                 let mut ep_setup = 0;
+                let ep_in_complete = self.ep_in_complete.borrow(cs).replace(0);
 
-                use crate::ral::{endpoint_in, endpoint_out};
-
-                // RXFLVL & IEPINT flags are read-only, there is no need to clear them
-                if rxflvl != 0 {
-                    let (epnum, data_size, status) =
-                        read_reg!(otg_global, regs.global(), GRXSTSR, EPNUM, BCNT, PKTSTS);
-                    match status {
-                        0x02 => {
-                            // OUT received
-                            ep_out |= 1 << epnum;
-                        }
-                        0x06 => {
-                            // SETUP received
-                            // flushing TX if something stuck in control endpoint
-                            let ep = regs.endpoint_in(epnum as usize);
-                            if read_reg!(endpoint_in, ep, DIEPTSIZ, PKTCNT) != 0 {
-                                modify_reg!(otg_global, regs.global(), GRSTCTL, TXFNUM: epnum, TXFFLSH: 1);
-                                while read_reg!(otg_global, regs.global(), GRSTCTL, TXFFLSH) == 1 {}
-                            }
-                            ep_setup |= 1 << epnum;
-                        }
-                        0x03 | 0x04 => {
-                            // OUT completed | SETUP completed
-                            // Re-enable the endpoint, F429-like chips only
-                            if core_id == 0x0000_1200 || core_id == 0x0000_1100 {
-                                let ep = regs.endpoint_out(epnum as usize);
-                                modify_reg!(endpoint_out, ep, DOEPCTL, CNAK: 1, EPENA: 1);
-                            }
-                            read_reg!(otg_global, regs.global(), GRXSTSP); // pop GRXSTSP
-                        }
-                        _ => {
-                            read_reg!(otg_global, regs.global(), GRXSTSP); // pop GRXSTSP
-                        }
-                    }
-
-                    if status == 0x02 || status == 0x06 {
-                        if let Some(ep) = &self.allocator.endpoints_out[epnum as usize] {
-                            let mut buffer = ep.buffer.borrow_ref_mut(cs);
-                            if buffer.state() == EndpointBufferState::Empty {
-                                read_reg!(otg_global, regs.global(), GRXSTSP); // pop GRXSTSP
-
-                                let is_setup = status == 0x06;
-                                buffer
-                                    .fill_from_fifo(*regs, data_size as u16, is_setup)
-                                    .ok();
-
-                                // Re-enable the endpoint, F446-like chips only
-                                if core_id == 0x0000_2000
-                                    || core_id == 0x0000_2100
-                                    || core_id == 0x0000_2300
-                                    || core_id == 0x0000_3000
-                                    || core_id == 0x0000_3100
-                                {
-                                    let ep = regs.endpoint_out(epnum as usize);
-                                    modify_reg!(endpoint_out, ep, DOEPCTL, CNAK: 1, EPENA: 1);
-                                }
-                            }
-                        }
-                    }
-                }
-
-                if iep != 0 {
-                    for ep in &self.allocator.endpoints_in {
-                        if let Some(ep) = ep {
-                            let ep_regs = regs.endpoint_in(ep.address().index());
-                            if read_reg!(endpoint_in, ep_regs, DIEPINT, XFRC) != 0 {
-                                write_reg!(endpoint_in, ep_regs, DIEPINT, XFRC: 1);
-                                ep_in_complete |= 1 << ep.address().index();
-                            }
+                for ep in self.allocator.endpoints_out.iter().flatten() {
+                    match ep.buffer_state() {
+                        EndpointBufferState::DataOut => {
+                            ep_out |= 1 << ep.address().index();
                         }
-                    }
-                }
-
-                for ep in &self.allocator.endpoints_out {
-                    if let Some(ep) = ep {
-                        match ep.buffer_state() {
-                            EndpointBufferState::DataOut => {
-                                ep_out |= 1 << ep.address().index();
-                            }
-                            EndpointBufferState::DataSetup => {
-                                ep_setup |= 1 << ep.address().index();
-                            }
-                            EndpointBufferState::Empty => {}
+                        EndpointBufferState::DataSetup => {
+                            ep_setup |= 1 << ep.address().index();
                         }
+                        EndpointBufferState::Empty => {}
                     }
                 }
 
@@ -0,0 +1,803 @@
+use crate::bus::{UsbBus, UsbBusConfig};
+use crate::endpoint_memory::EndpointBufferState;
+use crate::target::UsbRegisters;
+use crate::{UsbPeripheral, VbusSensing};
+use core::cell::{Cell, RefCell};
+use core::future::poll_fn;
+use core::task::{Poll, Waker};
+use critical_section::{CriticalSection, Mutex};
+use embassy_usb_driver::{
+    Direction, EndpointAddress, EndpointAllocError, EndpointError, EndpointInfo, EndpointType,
+    Event, Unsupported,
+};
+use embedded_hal_async::delay::DelayNs;
+use usb_device::bus::{PollResult, UsbBus as _};
+use usb_device::endpoint::{IsochronousSynchronizationType, IsochronousUsageType};
+use usb_device::{UsbDirection, UsbError};
+
+/// Number of endpoints per direction the state keeps track of
+const MAX_ENDPOINTS: usize = 9;
+
+const EVENT_RESET: u8 = 1 << 0;
+const EVENT_SUSPEND: u8 = 1 << 1;
+const EVENT_RESUME: u8 = 1 << 2;
+const EVENT_POWER_DETECTED: u8 = 1 << 3;
+const EVENT_POWER_REMOVED: u8 = 1 << 4;
+
+/// Waker of a task waiting for the interrupt handler
+struct WakerSlot(Mutex<RefCell<Option<Waker>>>);
+
+impl WakerSlot {
+    const fn new() -> Self {
+        Self(Mutex::new(RefCell::new(None)))
+    }
+
+    fn register(&self, cs: CriticalSection<'_>, waker: &Waker) {
+        let mut slot = self.0.borrow_ref_mut(cs);
+        match &*slot {
+            Some(registered) if registered.will_wake(waker) => {}
+            _ => *slot = Some(waker.clone()),
+        }
+    }
+
+    fn wake(&self, cs: CriticalSection<'_>) {
+        if let Some(waker) = self.0.borrow_ref_mut(cs).take() {
+            waker.wake();
+        }
+    }
+}
+
+/// State shared between the driver and the interrupt handler.
+///
+/// Place it in a `static` so that both the driver and [`on_interrupt`] can reach it.
+pub struct State<USB> {
+    bus: Mutex<RefCell<Option<UsbBus<USB>>>>,
+    events: Mutex<Cell<u8>>,
+    powered: Mutex<Cell<bool>>,
+    enabled_in: Mutex<Cell<u16>>,
+    enabled_out: Mutex<Cell<u16>>,
+    in_complete: Mutex<Cell<u16>>,
+    bus_waker: WakerSlot,
+    ep_in_wakers: [WakerSlot; MAX_ENDPOINTS],
+    ep_out_wakers: [WakerSlot; MAX_ENDPOINTS],
+}
+
+impl<USB: UsbPeripheral> State<USB> {
+    pub const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const WAKER: WakerSlot = WakerSlot::new();
+        Self {
+            bus: Mutex::new(RefCell::new(None)),
+            events: Mutex::new(Cell::new(0)),
+            powered: Mutex::new(Cell::new(false)),
+            // The control endpoint is always enabled
+            enabled_in: Mutex::new(Cell::new(1)),
+            enabled_out: Mutex::new(Cell::new(1)),
+            in_complete: Mutex::new(Cell::new(0)),
+            bus_waker: WakerSlot::new(),
+            ep_in_wakers: [WAKER; MAX_ENDPOINTS],
+            ep_out_wakers: [WAKER; MAX_ENDPOINTS],
+        }
+    }
+
+    fn is_enabled(&self, cs: CriticalSection<'_>, address: EndpointAddress) -> bool {
+        let enabled = match address.direction() {
+            Direction::Out => self.enabled_out.borrow(cs).get(),
+            Direction::In => self.enabled_in.borrow(cs).get(),
+        };
+        enabled & (1 << address.index()) != 0
+    }
+
+    fn add_event(&self, cs: CriticalSection<'_>, event: u8) {
+        let events = self.events.borrow(cs);
+        events.set(events.get() | event);
+        self.bus_waker.wake(cs);
+    }
+
+    /// Reports a change of VBUS as a power event, the latest one supersedes a pending one.
+    fn update_power(&self, cs: CriticalSection<'_>, bus: &UsbBus<USB>) {
+        let present = bus.vbus_present(cs);
+        if self.powered.borrow(cs).replace(present) == present {
+            return;
+        }
+
+        let (event, superseded) = if present {
+            (EVENT_POWER_DETECTED, EVENT_POWER_REMOVED)
+        } else {
+            (EVENT_POWER_REMOVED, EVENT_POWER_DETECTED)
+        };
+        let events = self.events.borrow(cs);
+        events.set(events.get() & !superseded);
+        self.add_event(cs, event);
+    }
+
+    /// Returns `true` if EP0 has received a SETUP packet that hasn't been read yet
+    fn setup_pending(&self, cs: CriticalSection<'_>) -> bool {
+        let bus = self.bus.borrow_ref(cs);
+        match bus.as_ref().and_then(|bus| bus.endpoint_out(0)) {
+            Some(ep) => ep.buffer.borrow_ref(cs).state() == EndpointBufferState::DataSetup,
+            None => false,
+        }
+    }
+
+    /// Reads a packet from an OUT endpoint.
+    fn read(
+        &self,
+        cs: CriticalSection<'_>,
+        index: usize,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, EndpointError>> {
+        let bus = self.bus.borrow_ref(cs);
+        let bus = match bus.as_ref() {
+            Some(bus) => bus,
+            None => return Poll::Ready(Err(EndpointError::Disabled)),
+        };
+
+        let address = usb_device::endpoint::EndpointAddress::from_parts(index, UsbDirection::Out);
+        match bus.read(address, buf) {
+            Ok(size) => Poll::Ready(Ok(size)),
+            Err(UsbError::InvalidEndpoint) => Poll::Ready(Err(EndpointError::Disabled)),
+            Err(UsbError::WouldBlock) => Poll::Pending,
+            Err(_) => Poll::Ready(Err(EndpointError::BufferOverflow)),
+        }
+    }
+
+    /// Queues a packet on an IN endpoint.
+    fn write(
+        &self,
+        cs: CriticalSection<'_>,
+        index: usize,
+        buf: &[u8],
+    ) -> Poll<Result<(), EndpointError>> {
+        let bus = self.bus.borrow_ref(cs);
+        let ep = match bus.as_ref().and_then(|bus| bus.endpoint_in(index)) {
+            Some(ep) => ep,
+            None => return Poll::Ready(Err(EndpointError::Disabled)),
+        };
+
+        match ep.write(buf) {
+            Ok(()) => Poll::Ready(Ok(())),
+            Err(UsbError::WouldBlock) => Poll::Pending,
+            Err(_) => Poll::Ready(Err(EndpointError::BufferOverflow)),
+        }
+    }
+}
+
+impl<USB: UsbPeripheral> crate::otg::sealed::OtgLatch for State<USB> {
+    fn take_otg_interrupts(&self, cs: CriticalSection<'_>) -> u32 {
+        match self.bus.borrow_ref(cs).as_ref() {
+            Some(bus) => bus.otg_interrupts(cs).take(),
+            None => 0,
+        }
+    }
+
+    fn keep_otg_interrupts(&self, cs: CriticalSection<'_>, pending: u32) {
+        if let Some(bus) = self.bus.borrow_ref(cs).as_ref() {
+            bus.otg_interrupts(cs).set(pending);
+        }
+    }
+}
+
+impl<USB: UsbPeripheral> crate::otg::OtgDriver<USB> for State<USB> {}
+
+impl<USB: UsbPeripheral> Default for State<USB> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Handles the USB interrupt with [`UsbBus::on_interrupt`] and wakes the tasks waiting for it.
+///
+/// Call this from the OTG interrupt handler.
+pub fn on_interrupt<USB: UsbPeripheral>(state: &State<USB>) {
+    critical_section::with(|cs| {
+        let bus = state.bus.borrow_ref(cs);
+        let bus = match bus.as_ref() {
+            Some(bus) => bus,
+            None => return,
+        };
+
+        // Bus events are reported before endpoint data, one per call
+        loop {
+            match bus.poll() {
+                PollResult::Reset => {
+                    bus.reset();
+
+                    // Only the control endpoint stays enabled until the device is configured again
+                    state.enabled_in.borrow(cs).set(1);
+                    state.enabled_out.borrow(cs).set(1);
+                    state.in_complete.borrow(cs).set(0);
+                    for waker in state.ep_in_wakers.iter().chain(&state.ep_out_wakers) {
+                        waker.wake(cs);
+                    }
+
+                    state.add_event(cs, EVENT_RESET);
+                }
+                PollResult::Suspend => {
+                    bus.suspend();
+                    state.add_event(cs, EVENT_SUSPEND);
+                }
+                PollResult::Resume => {
+                    bus.resume();
+                    state.add_event(cs, EVENT_RESUME);
+                }
+                PollResult::Data {
+                    ep_out,
+                    ep_in_complete,
+                    ep_setup,
+                } => {
+                    let in_complete = state.in_complete.borrow(cs);
+                    in_complete.set(in_complete.get() | ep_in_complete);
+
+                    for index in 0..MAX_ENDPOINTS {
+                        if ep_in_complete & (1 << index) != 0 {
+                            state.ep_in_wakers[index].wake(cs);
+                        }
+                        if (ep_out | ep_setup) & (1 << index) != 0 {
+                            state.ep_out_wakers[index].wake(cs);
+                        }
+                    }
+                    break;
+                }
+                PollResult::None => break,
+            }
+        }
+
+        // A disconnect is reported as a suspend by `poll`, VBUS tells them apart
+        state.update_power(cs, bus);
+    });
+}
+
+/// Reports a change of VBUS with [`VbusSensing::Gpio`], see
+/// [`UsbBus::vbus_changed`].
+///
+/// Call this when [`UsbPeripheral::vbus_present`] changes, e.g. from the GPIO interrupt.
+pub fn vbus_changed<USB: UsbPeripheral>(state: &State<USB>) {
+    critical_section::with(|cs| {
+        if let Some(bus) = state.bus.borrow_ref(cs).as_ref() {
+            bus.vbus_changed();
+            state.update_power(cs, bus);
+        }
+    });
+}
+
+/// Delay type of a [`Driver`] without remote wakeup, see [`Driver::remote_wakeup_delay`].
+pub enum NoDelay {}
+
+impl DelayNs for NoDelay {
+    async fn delay_ns(&mut self, _ns: u32) {
+        match *self {}
+    }
+}
+
+/// `embassy-usb` driver for Synopsys USB OTG peripherals.
+pub struct Driver<'d, USB, D = NoDelay> {
+    state: &'d State<USB>,
+    wakeup_delay: Option<D>,
+}
+
+impl<'d, USB: UsbPeripheral> Driver<'d, USB> {
+    /// Constructs a new driver.
+    ///
+    /// `ep_memory` holds the packet buffers of the OUT endpoints, as with [`UsbBus::new`].
+    pub fn new(state: &'d State<USB>, peripheral: USB, ep_memory: &'static mut [u32]) -> Self {
+        Self::with_config(state, peripheral, ep_memory, UsbBusConfig::default())
+    }
+
+    /// Constructs a new driver with the given settings, see [`UsbBus::with_config`].
+    ///
+    /// This driver always moves the packets through the Rx/Tx FIFOs, a DMA setting made with
+    /// `UsbBusConfig::dma` (`hs` feature) is overridden and the thresholds are unused.
+    pub fn with_config(
+        state: &'d State<USB>,
+        peripheral: USB,
+        ep_memory: &'static mut [u32],
+        config: UsbBusConfig,
+    ) -> Self {
+        #[cfg(feature = "hs")]
+        let config = config.dma(false);
+        critical_section::with(move |cs| {
+            state
+                .bus
+                .borrow_ref_mut(cs)
+                .replace(UsbBus::new_bus(peripheral, ep_memory, config));
+        });
+        Self {
+            state,
+            wakeup_delay: None,
+        }
+    }
+
+    /// Sets the delay used to time the resume signalling of a remote wakeup.
+    ///
+    /// The resume signalling is driven for 10 ms, as with [`UsbBus::remote_wakeup`], while the
+    /// delay is awaited. Without it, remote wakeup is reported as unsupported.
+    pub fn remote_wakeup_delay<D: DelayNs>(self, delay: D) -> Driver<'d, USB, D> {
+        Driver {
+            state: self.state,
+            wakeup_delay: Some(delay),
+        }
+    }
+}
+
+impl<'d, USB: UsbPeripheral, D> Driver<'d, USB, D> {
+    fn alloc_endpoint(
+        &mut self,
+        direction: UsbDirection,
+        ep_type: EndpointType,
+        ep_addr: Option<EndpointAddress>,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<EndpointInfo, EndpointAllocError> {
+        let usb_ep_type = match ep_type {
+            EndpointType::Control => usb_device::endpoint::EndpointType::Control,
+            EndpointType::Isochronous => usb_device::endpoint::EndpointType::Isochronous {
+                synchronization: IsochronousSynchronizationType::NoSynchronization,
+                usage: IsochronousUsageType::Data,
+            },
+            EndpointType::Bulk => usb_device::endpoint::EndpointType::Bulk,
+            EndpointType::Interrupt => usb_device::endpoint::EndpointType::Interrupt,
+        };
+        let ep_addr = ep_addr.map(|address| u8::from(address).into());
+
+        let address = critical_section::with(|cs| {
+            let mut bus = self.state.bus.borrow_ref_mut(cs);
+            match bus.as_mut() {
+                Some(bus) => bus
+                    .alloc_ep(
+                        direction,
+                        ep_addr,
+                        usb_ep_type,
+                        max_packet_size,
+                        interval_ms,
+                    )
+                    .map_err(|_| EndpointAllocError),
+                None => Err(EndpointAllocError),
+            }
+        })?;
+
+        Ok(EndpointInfo {
+            addr: EndpointAddress::from(u8::from(address)),
+            ep_type,
+            max_packet_size,
+            interval_ms,
+        })
+    }
+}
+
+impl<'d, USB: UsbPeripheral + 'd, D: DelayNs + 'd> embassy_usb_driver::Driver<'d>
+    for Driver<'d, USB, D>
+{
+    type EndpointOut = EndpointOut<'d, USB>;
+    type EndpointIn = EndpointIn<'d, USB>;
+    type ControlPipe = ControlPipe<'d, USB>;
+    type Bus = Bus<'d, USB, D>;
+
+    fn alloc_endpoint_out(
+        &mut self,
+        ep_type: EndpointType,
+        ep_addr: Option<EndpointAddress>,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointOut, EndpointAllocError> {
+        let info = self.alloc_endpoint(
+            UsbDirection::Out,
+            ep_type,
+            ep_addr,
+            max_packet_size,
+            interval_ms,
+        )?;
+        Ok(EndpointOut {
+            state: self.state,
+            info,
+        })
+    }
+
+    fn alloc_endpoint_in(
+        &mut self,
+        ep_type: EndpointType,
+        ep_addr: Option<EndpointAddress>,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointIn, EndpointAllocError> {
+        let info = self.alloc_endpoint(
+            UsbDirection::In,
+            ep_type,
+            ep_addr,
+            max_packet_size,
+            interval_ms,
+        )?;
+        Ok(EndpointIn {
+            state: self.state,
+            info,
+        })
+    }
+
+    fn start(mut self, control_max_packet_size: u16) -> (Self::Bus, Self::ControlPipe) {
+        for direction in [Direction::Out, Direction::In] {
+            let usb_direction = match direction {
+                Direction::Out => UsbDirection::Out,
+                Direction::In => UsbDirection::In,
+            };
+            // EP0 is always available, allocation can't fail
+            self.alloc_endpoint(
+                usb_direction,
+                EndpointType::Control,
+                Some(EndpointAddress::from_parts(0, direction)),
+                control_max_packet_size,
+                0,
+            )
+            .ok();
+        }
+
+        (
+            Bus {
+                state: self.state,
+                inited: false,
+                wakeup_delay: self.wakeup_delay,
+            },
+            ControlPipe {
+                state: self.state,
+                max_packet_size: control_max_packet_size,
+            },
+        )
+    }
+}
+
+/// Bus control of the `embassy-usb` driver.
+pub struct Bus<'d, USB, D = NoDelay> {
+    state: &'d State<USB>,
+    inited: bool,
+    wakeup_delay: Option<D>,
+}
+
+impl<'d, USB: UsbPeripheral, D: DelayNs> embassy_usb_driver::Bus for Bus<'d, USB, D> {
+    async fn enable(&mut self) {
+        critical_section::with(|cs| {
+            if let Some(bus) = self.state.bus.borrow_ref_mut(cs).as_mut() {
+                bus.enable();
+            }
+        });
+    }
+
+    async fn disable(&mut self) {
+        critical_section::with(|cs| {
+            if let Some(bus) = self.state.bus.borrow_ref(cs).as_ref() {
+                bus.detach();
+                bus.listen_for_session(cs);
+            }
+        });
+    }
+
+    async fn poll(&mut self) -> Event {
+        // The internal VBUS comparator isn't set up before the bus is enabled, so the bus is
+        // assumed to be powered unless VBUS is sensed by the HAL
+        if !self.inited {
+            self.inited = true;
+            critical_section::with(|cs| {
+                let bus = self.state.bus.borrow_ref(cs);
+                let present = match bus.as_ref() {
+                    Some(bus) => bus.vbus_sensing() != VbusSensing::Gpio || bus.vbus_present(cs),
+                    None => true,
+                };
+                if present {
+                    self.state.powered.borrow(cs).set(true);
+                    self.state.add_event(cs, EVENT_POWER_DETECTED);
+                }
+            });
+        }
+
+        poll_fn(|cx| {
+            critical_section::with(|cs| {
+                self.state.bus_waker.register(cs, cx.waker());
+
+                let events = self.state.events.borrow(cs);
+                let pending = events.get();
+                let (event, flag) = if pending & EVENT_POWER_REMOVED != 0 {
+                    (Event::PowerRemoved, EVENT_POWER_REMOVED)
+                } else if pending & EVENT_POWER_DETECTED != 0 {
+                    (Event::PowerDetected, EVENT_POWER_DETECTED)
+                } else if pending & EVENT_RESET != 0 {
+                    (Event::Reset, EVENT_RESET)
+                } else if pending & EVENT_SUSPEND != 0 {
+                    (Event::Suspend, EVENT_SUSPEND)
+                } else if pending & EVENT_RESUME != 0 {
+                    (Event::Resume, EVENT_RESUME)
+                } else {
+                    return Poll::Pending;
+                };
+                events.set(pending & !flag);
+                Poll::Ready(event)
+            })
+        })
+        .await
+    }
+
+    fn endpoint_set_enabled(&mut self, ep_addr: EndpointAddress, enabled: bool) {
+        let index = ep_addr.index();
+        if index >= MAX_ENDPOINTS {
+            return;
+        }
+
+        critical_section::with(|cs| {
+            let bus = self.state.bus.borrow_ref(cs);
+            let bus = match bus.as_ref() {
+                Some(bus) => bus,
+                None => return,
+            };
+
+            let (mask, wakers) = match ep_addr.direction() {
+                Direction::Out => {
+                    if let Some(ep) = bus.endpoint_out(index) {
+                        if enabled {
+                            ep.configure(cs);
+                        } else {
+                            ep.deconfigure(cs);
+                        }
+                    }
+                    (&self.state.enabled_out, &self.state.ep_out_wakers)
+                }
+                Direction::In => {
+                    if let Some(ep) = bus.endpoint_in(index) {
+                        if enabled {
+                            ep.configure(cs);
+                        } else {
+                            ep.deconfigure(cs);
+                        }
+                    }
+                    (&self.state.enabled_in, &self.state.ep_in_wakers)
+                }
+            };
+
+            let mask = mask.borrow(cs);
+            if enabled {
+                mask.set(mask.get() | (1 << index));
+            } else {
+                mask.set(mask.get() & !(1 << index));
+            }
+            wakers[index].wake(cs);
+        });
+    }
+
+    fn endpoint_set_stalled(&mut self, ep_addr: EndpointAddress, stalled: bool) {
+        let regs = UsbRegisters::new::<USB>();
+        crate::endpoint::set_stalled(regs, u8::from(ep_addr).into(), stalled)
+    }
+
+    fn endpoint_is_stalled(&mut self, ep_addr: EndpointAddress) -> bool {
+        let regs = UsbRegisters::new::<USB>();
+        crate::endpoint::is_stalled(regs, u8::from(ep_addr).into())
+    }
+
+    async fn remote_wakeup(&mut self) -> Result<(), Unsupported> {
+        let state = self.state;
+        let delay = self.wakeup_delay.as_mut().ok_or(Unsupported)?;
+
+        let started = critical_section::with(|cs| match state.bus.borrow_ref(cs).as_ref() {
+            Some(bus) => bus.start_remote_wakeup(cs).is_ok(),
+            None => false,
+        });
+        // The bus isn't suspended, there is nothing to wake up
+        if !started {
+            return Ok(());
+        }
+
+        delay.delay_ms(10).await;
+
+        critical_section::with(|cs| {
+            if let Some(bus) = state.bus.borrow_ref(cs).as_ref() {
+                bus.stop_remote_wakeup(cs);
+            }
+            // The host doesn't signal resume itself, so there is no wakeup interrupt
+            state.add_event(cs, EVENT_RESUME);
+        });
+        Ok(())
+    }
+}
+
+/// OUT endpoint of the `embassy-usb` driver.
+pub struct EndpointOut<'d, USB> {
+    state: &'d State<USB>,
+    info: EndpointInfo,
+}
+
+impl<'d, USB: UsbPeripheral> embassy_usb_driver::Endpoint for EndpointOut<'d, USB> {
+    fn info(&self) -> &EndpointInfo {
+        &self.info
+    }
+
+    async fn wait_enabled(&mut self) {
+        wait_enabled(self.state, self.info.addr).await
+    }
+}
+
+impl<'d, USB: UsbPeripheral> embassy_usb_driver::EndpointOut for EndpointOut<'d, USB> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError> {
+        let index = self.info.addr.index();
+        poll_fn(|cx| {
+            critical_section::with(|cs| {
+                self.state.ep_out_wakers[index].register(cs, cx.waker());
+                if !self.state.is_enabled(cs, self.info.addr) {
+                    return Poll::Ready(Err(EndpointError::Disabled));
+                }
+                self.state.read(cs, index, buf)
+            })
+        })
+        .await
+    }
+}
+
+/// IN endpoint of the `embassy-usb` driver.
+pub struct EndpointIn<'d, USB> {
+    state: &'d State<USB>,
+    info: EndpointInfo,
+}
+
+impl<'d, USB: UsbPeripheral> embassy_usb_driver::Endpoint for EndpointIn<'d, USB> {
+    fn info(&self) -> &EndpointInfo {
+        &self.info
+    }
+
+    async fn wait_enabled(&mut self) {
+        wait_enabled(self.state, self.info.addr).await
+    }
+}
+
+impl<'d, USB: UsbPeripheral> embassy_usb_driver::EndpointIn for EndpointIn<'d, USB> {
+    async fn write(&mut self, buf: &[u8]) -> Result<(), EndpointError> {
+        let index = self.info.addr.index();
+        poll_fn(|cx| {
+            critical_section::with(|cs| {
+                self.state.ep_in_wakers[index].register(cs, cx.waker());
+                if !self.state.is_enabled(cs, self.info.addr) {
+                    return Poll::Ready(Err(EndpointError::Disabled));
+                }
+                self.state.write(cs, index, buf)
+            })
+        })
+        .await
+    }
+}
+
+async fn wait_enabled<USB: UsbPeripheral>(state: &State<USB>, address: EndpointAddress) {
+    let index = address.index();
+    poll_fn(|cx| {
+        critical_section::with(|cs| {
+            match address.direction() {
+                Direction::Out => state.ep_out_wakers[index].register(cs, cx.waker()),
+                Direction::In => state.ep_in_wakers[index].register(cs, cx.waker()),
+            }
+            if state.is_enabled(cs, address) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+    })
+    .await
+}
+
+/// Control pipe of the `embassy-usb` driver.
+pub struct ControlPipe<'d, USB> {
+    state: &'d State<USB>,
+    max_packet_size: u16,
+}
+
+impl<'d, USB: UsbPeripheral> ControlPipe<'d, USB> {
+    /// Sends a packet on EP0 and waits until the host has received it.
+    ///
+    /// Fails with `EndpointError::Disabled` if a new SETUP packet arrives in the meantime.
+    async fn write_packet(&mut self, data: &[u8]) -> Result<(), EndpointError> {
+        poll_fn(|cx| {
+            critical_section::with(|cs| {
+                self.state.ep_in_wakers[0].register(cs, cx.waker());
+                self.state.ep_out_wakers[0].register(cs, cx.waker());
+                if self.state.setup_pending(cs) {
+                    return Poll::Ready(Err(EndpointError::Disabled));
+                }
+                let in_complete = self.state.in_complete.borrow(cs);
+                in_complete.set(in_complete.get() & !1);
+                self.state.write(cs, 0, data)
+            })
+        })
+        .await?;
+
+        poll_fn(|cx| {
+            critical_section::with(|cs| {
+                self.state.ep_in_wakers[0].register(cs, cx.waker());
+                self.state.ep_out_wakers[0].register(cs, cx.waker());
+                let in_complete = self.state.in_complete.borrow(cs);
+                if in_complete.get() & 1 != 0 {
+                    in_complete.set(in_complete.get() & !1);
+                    Poll::Ready(Ok(()))
+                } else if self.state.setup_pending(cs) {
+                    Poll::Ready(Err(EndpointError::Disabled))
+                } else {
+                    Poll::Pending
+                }
+            })
+        })
+        .await
+    }
+}
+
+impl<'d, USB: UsbPeripheral> embassy_usb_driver::ControlPipe for ControlPipe<'d, USB> {
+    fn max_packet_size(&self) -> usize {
+        self.max_packet_size as usize
+    }
+
+    async fn setup(&mut self) -> [u8; 8] {
+        poll_fn(|cx| {
+            critical_section::with(|cs| {
+                self.state.ep_out_wakers[0].register(cs, cx.waker());
+                loop {
+                    if self.state.setup_pending(cs) {
+                        let mut setup = [0u8; 8];
+                        return match self.state.read(cs, 0, &mut setup) {
+                            Poll::Ready(Ok(8)) => Poll::Ready(setup),
+                            _ => Poll::Pending,
+                        };
+                    }
+
+                    // Drop the status stage packet of the previous request
+                    let mut status = [0u8; 64];
+                    match self.state.read(cs, 0, &mut status) {
+                        Poll::Ready(Ok(_)) => continue,
+                        _ => return Poll::Pending,
+                    }
+                }
+            })
+        })
+        .await
+    }
+
+    async fn data_out(
+        &mut self,
+        buf: &mut [u8],
+        _first: bool,
+        _last: bool,
+    ) -> Result<usize, EndpointError> {
+        poll_fn(|cx| {
+            critical_section::with(|cs| {
+                self.state.ep_out_wakers[0].register(cs, cx.waker());
+                if self.state.setup_pending(cs) {
+                    return Poll::Ready(Err(EndpointError::Disabled));
+                }
+                self.state.read(cs, 0, buf)
+            })
+        })
+        .await
+    }
+
+    async fn data_in(
+        &mut self,
+        data: &[u8],
+        _first: bool,
+        _last: bool,
+    ) -> Result<(), EndpointError> {
+        // The status stage packet is received into the EP0 buffer and dropped by `setup`
+        self.write_packet(data).await
+    }
+
+    async fn accept(&mut self) {
+        self.write_packet(&[]).await.ok();
+    }
+
+    async fn reject(&mut self) {
+        let regs = UsbRegisters::new::<USB>();
+        for direction in [UsbDirection::Out, UsbDirection::In] {
+            let address = usb_device::endpoint::EndpointAddress::from_parts(0, direction);
+            crate::endpoint::set_stalled(regs, address, true);
+        }
+    }
+
+    async fn accept_set_address(&mut self, addr: u8) {
+        // The core expects the address to be set before the status stage
+        critical_section::with(|cs| {
+            if let Some(bus) = self.state.bus.borrow_ref(cs).as_ref() {
+                bus.set_device_address(addr);
+            }
+        });
+        self.accept().await;
+    }
+}
@@ -1,14 +1,30 @@
-use crate::endpoint_memory::{EndpointBuffer, EndpointBufferState};
-use crate::ral::{endpoint0_out, endpoint_in, endpoint_out, modify_reg, read_reg, write_reg};
+use crate::endpoint_memory::{EndpointBuffer, EndpointBufferQueue, EndpointBufferState};
+use crate::ral::{
+    endpoint0_out, endpoint_in, endpoint_out, modify_reg, otg_device, otg_global, read_reg,
+    write_reg,
+};
 use crate::target::{fifo_write, UsbRegisters};
 use crate::transition::EndpointDescriptor;
 use crate::UsbPeripheral;
-use core::cell::RefCell;
+use core::cell::{Cell, RefCell};
 use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{fence, Ordering};
 use critical_section::{CriticalSection, Mutex};
-use usb_device::endpoint::EndpointAddress;
+use usb_device::endpoint::{EndpointAddress, EndpointType};
 use usb_device::{Result, UsbDirection, UsbError};
 
+/// Encodes the maximum packet size of EP0 for DIEPCTL0/DOEPCTL0.MPSIZ, `None` if the core
+/// doesn't support it
+pub fn ep0_mpsiz(max_packet_size: u16) -> Option<u32> {
+    match max_packet_size {
+        8 => Some(0b11),
+        16 => Some(0b10),
+        32 => Some(0b01),
+        64 => Some(0b00),
+        _ => None,
+    }
+}
+
 pub fn set_stalled(usb: UsbRegisters, address: EndpointAddress, stalled: bool) {
     critical_section::with(|_| match address.direction() {
         UsbDirection::Out => {
@@ -36,10 +52,17 @@ pub fn is_stalled(usb: UsbRegisters, address: EndpointAddress) -> bool {
     stall != 0
 }
 
+/// Returns `true` if the current (micro)frame number is odd.
+fn odd_frame(usb: UsbRegisters) -> bool {
+    read_reg!(otg_device, usb.device(), DSTS, FNSOF) & 1 != 0
+}
+
 /// Arbitrates access to the endpoint-specific registers and packet buffer memory.
 pub struct Endpoint {
     descriptor: EndpointDescriptor,
     usb: UsbRegisters,
+    #[cfg(feature = "hs")]
+    high_speed: bool,
 }
 
 impl Endpoint {
@@ -47,6 +70,8 @@ impl Endpoint {
         Endpoint {
             descriptor,
             usb: UsbRegisters::new::<USB>(),
+            #[cfg(feature = "hs")]
+            high_speed: USB::HIGH_SPEED,
         }
     }
 
@@ -58,31 +83,53 @@ impl Endpoint {
     fn index(&self) -> u8 {
         self.descriptor.address.index() as u8
     }
+
+    fn is_isochronous(&self) -> bool {
+        matches!(self.descriptor.ep_type, EndpointType::Isochronous { .. })
+    }
+}
+
+/// Maximum number of packets in a single transfer (DIEPTSIZ.PKTCNT)
+const MAX_TRANSFER_PACKETS: usize = 0x3ff;
+
+/// Maximum size of a single transfer in bytes (DIEPTSIZ.XFRSIZ)
+const MAX_TRANSFER_SIZE: usize = 0x7ffff;
+
+/// Multi-packet IN transfer in progress
+#[derive(Copy, Clone)]
+struct InTransfer {
+    /// Data that hasn't been written to the TX FIFO yet
+    remaining: &'static [u8],
+    /// A zero-length packet has to follow the transfer
+    zlp: bool,
 }
 
 pub struct EndpointIn {
     common: Endpoint,
+    /// Staging buffer for packets fetched by the DMA engine, `None` in FIFO mode
+    dma_buffer: Option<Mutex<RefCell<EndpointBuffer>>>,
+    transfer: Mutex<Cell<Option<InTransfer>>>,
 }
 
 impl EndpointIn {
-    pub fn new<USB: UsbPeripheral>(descriptor: EndpointDescriptor) -> EndpointIn {
+    pub fn new<USB: UsbPeripheral>(
+        descriptor: EndpointDescriptor,
+        dma_buffer: Option<EndpointBuffer>,
+    ) -> EndpointIn {
         EndpointIn {
             common: Endpoint::new::<USB>(descriptor),
+            dma_buffer: dma_buffer.map(|buffer| Mutex::new(RefCell::new(buffer))),
+            transfer: Mutex::new(Cell::new(None)),
         }
     }
 
     pub fn configure(&self, _cs: CriticalSection<'_>) {
         if self.index() == 0 {
-            let mpsiz = match self.descriptor.max_packet_size {
-                8 => 0b11,
-                16 => 0b10,
-                32 => 0b01,
-                64 => 0b00,
-                other => panic!("Unsupported EP0 size: {}", other),
-            };
+            // The size has been validated on allocation
+            let mpsiz = ep0_mpsiz(self.descriptor.max_packet_size).unwrap_or(0b00);
 
             let regs = self.usb.endpoint_in(self.index() as usize);
-            write_reg!(endpoint_in, regs, DIEPCTL, MPSIZ: mpsiz as u32, SNAK: 1);
+            write_reg!(endpoint_in, regs, DIEPCTL, MPSIZ: mpsiz, SNAK: 1);
             write_reg!(endpoint_in, regs, DIEPTSIZ, PKTCNT: 0, XFRSIZ: self.descriptor.max_packet_size as u32);
         } else {
             let regs = self.usb.endpoint_in(self.index() as usize);
@@ -92,12 +139,12 @@ impl EndpointIn {
                 EPTYP: self.descriptor.ep_type.to_bm_attributes() as u32,
                 SD0PID_SEVNFRM: 1,
                 TXFNUM: self.index() as u32,
-                MPSIZ: self.descriptor.max_packet_size as u32
+                MPSIZ: self.descriptor.packet_size() as u32
             );
         }
     }
 
-    pub fn deconfigure(&self, _cs: CriticalSection<'_>) {
+    pub fn deconfigure(&self, cs: CriticalSection<'_>) {
         let regs = self.usb.endpoint_in(self.index() as usize);
 
         // deactivating endpoint
@@ -113,6 +160,11 @@ impl EndpointIn {
         // clean EP interrupts
         write_reg!(endpoint_in, regs, DIEPINT, 0xff);
 
+        // abort a pending transfer
+        self.transfer.borrow(cs).set(None);
+        let device = self.usb.device();
+        modify_reg!(otg_device, device, DIEPEMPMSK, |v| v & !(1 << self.index()));
+
         // TODO: deconfiguring TX FIFO
     }
 
@@ -122,11 +174,12 @@ impl EndpointIn {
             return Err(UsbError::WouldBlock);
         }
 
-        if buf.len() > self.descriptor.max_packet_size as usize {
+        // High-bandwidth endpoints send up to three packets per microframe
+        if buf.len() > self.descriptor.max_payload_size() {
             return Err(UsbError::BufferOverflow);
         }
 
-        if !buf.is_empty() {
+        if self.dma_buffer.is_none() && !buf.is_empty() {
             // Check for FIFO free space
             let size_words = (buf.len() + 3) / 4;
             if size_words > read_reg!(endpoint_in, ep, DTXFSTS, INEPTFSAV) as usize {
@@ -134,59 +187,371 @@ impl EndpointIn {
             }
         }
 
-        #[cfg(feature = "fs")]
-        write_reg!(endpoint_in, ep, DIEPTSIZ, PKTCNT: 1, XFRSIZ: buf.len() as u32);
+        let packet_size = self.descriptor.packet_size() as usize;
+        let packets = core::cmp::max(buf.len().div_ceil(packet_size), 1);
+
+        #[cfg(not(feature = "hs"))]
+        write_reg!(endpoint_in, ep, DIEPTSIZ, PKTCNT: packets as u32, XFRSIZ: buf.len() as u32);
         #[cfg(feature = "hs")]
-        write_reg!(endpoint_in, ep, DIEPTSIZ, MCNT: 1, PKTCNT: 1, XFRSIZ: buf.len() as u32);
+        if self.high_speed {
+            write_reg!(endpoint_in, ep, DIEPTSIZ, MCNT: packets as u32, PKTCNT: packets as u32, XFRSIZ: buf.len() as u32);
+        } else {
+            write_reg!(endpoint_in, ep, DIEPTSIZ, PKTCNT: packets as u32, XFRSIZ: buf.len() as u32);
+        }
+
+        if let Some(dma_buffer) = &self.dma_buffer {
+            let address = critical_section::with(|cs| {
+                let mut buffer = dma_buffer.borrow_ref_mut(cs);
+                buffer.write_packet(buf).map(|_| buffer.dma_address())
+            })?;
+            write_reg!(endpoint_in, ep, DIEPDMA, address);
 
-        modify_reg!(endpoint_in, ep, DIEPCTL, CNAK: 1, EPENA: 1);
+            // Make sure the packet is in memory before the core starts fetching it
+            fence(Ordering::SeqCst);
 
-        fifo_write(self.usb, self.index(), buf);
+            self.enable();
+        } else {
+            self.enable();
+
+            fifo_write(self.usb, self.index(), buf);
+        }
 
         Ok(())
     }
+
+    /// Starts a transfer of `data` as a single multi-packet hardware transfer.
+    ///
+    /// In FIFO mode the TX FIFO is refilled from [`refill`](Self::refill) as it drains. In DMA
+    /// mode the core fetches `data` directly, so it must be word-aligned.
+    pub fn write_transfer(&self, data: &'static [u8], zlp: bool) -> Result<()> {
+        if self.index() == 0 {
+            return Err(UsbError::InvalidEndpoint);
+        }
+
+        let max_packet_size = self.descriptor.packet_size() as usize;
+        let packets = core::cmp::max(data.len().div_ceil(max_packet_size), 1);
+        if packets > MAX_TRANSFER_PACKETS || data.len() > MAX_TRANSFER_SIZE {
+            return Err(UsbError::BufferOverflow);
+        }
+        if self.dma_buffer.is_some() && data.as_ptr() as usize & 0b11 != 0 {
+            return Err(UsbError::InvalidState);
+        }
+
+        critical_section::with(|cs| {
+            let ep = self.usb.endpoint_in(self.index() as usize);
+            if read_reg!(endpoint_in, ep, DIEPCTL, EPENA) != 0
+                || self.transfer.borrow(cs).get().is_some()
+            {
+                return Err(UsbError::WouldBlock);
+            }
+
+            #[cfg(not(feature = "hs"))]
+            write_reg!(endpoint_in, ep, DIEPTSIZ, PKTCNT: packets as u32, XFRSIZ: data.len() as u32);
+            #[cfg(feature = "hs")]
+            if self.high_speed {
+                write_reg!(endpoint_in, ep, DIEPTSIZ, MCNT: self.descriptor.transactions() as u32, PKTCNT: packets as u32, XFRSIZ: data.len() as u32);
+            } else {
+                write_reg!(endpoint_in, ep, DIEPTSIZ, PKTCNT: packets as u32, XFRSIZ: data.len() as u32);
+            }
+
+            // A transfer ending with a full packet needs a zero-length packet to terminate it
+            let zlp = zlp && !data.is_empty() && packets * max_packet_size == data.len();
+
+            if self.dma_buffer.is_some() {
+                write_reg!(endpoint_in, ep, DIEPDMA, data.as_ptr() as u32);
+
+                // Make sure the data is in memory before the core starts fetching it
+                fence(Ordering::SeqCst);
+
+                self.enable();
+
+                self.transfer.borrow(cs).set(Some(InTransfer {
+                    remaining: &[],
+                    zlp,
+                }));
+            } else {
+                self.enable();
+
+                self.transfer.borrow(cs).set(Some(InTransfer {
+                    remaining: data,
+                    zlp,
+                }));
+                self.refill(cs);
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Enables the endpoint to send the packets set up in DIEPTSIZ.
+    ///
+    /// Isochronous packets are scheduled for the next (micro)frame.
+    fn enable(&self) {
+        let ep = self.usb.endpoint_in(self.index() as usize);
+        if !self.is_isochronous() {
+            modify_reg!(endpoint_in, ep, DIEPCTL, CNAK: 1, EPENA: 1);
+        } else if odd_frame(self.usb) {
+            modify_reg!(endpoint_in, ep, DIEPCTL, SD0PID_SEVNFRM: 1, CNAK: 1, EPENA: 1);
+        } else {
+            #[cfg(not(feature = "hs"))]
+            modify_reg!(endpoint_in, ep, DIEPCTL, SODDFRM_SD1PID: 1, CNAK: 1, EPENA: 1);
+            #[cfg(feature = "hs")]
+            modify_reg!(endpoint_in, ep, DIEPCTL, SODDFRM: 1, CNAK: 1, EPENA: 1);
+        }
+    }
+
+    /// Writes as many packets of the pending transfer into the TX FIFO as fit.
+    ///
+    /// The TX FIFO empty interrupt stays enabled until all the data has been written.
+    pub fn refill(&self, cs: CriticalSection<'_>) {
+        let mut transfer = match self.transfer.borrow(cs).get() {
+            Some(transfer) => transfer,
+            None => return,
+        };
+
+        let ep = self.usb.endpoint_in(self.index() as usize);
+        let max_packet_size = self.descriptor.packet_size() as usize;
+        while !transfer.remaining.is_empty() {
+            let (packet, rest) = transfer
+                .remaining
+                .split_at(core::cmp::min(transfer.remaining.len(), max_packet_size));
+            if packet.len().div_ceil(4) > read_reg!(endpoint_in, ep, DTXFSTS, INEPTFSAV) as usize {
+                break;
+            }
+            fifo_write(self.usb, self.index(), packet);
+            transfer.remaining = rest;
+        }
+        self.transfer.borrow(cs).set(Some(transfer));
+
+        let device = self.usb.device();
+        if transfer.remaining.is_empty() {
+            modify_reg!(otg_device, device, DIEPEMPMSK, |v| v & !(1 << self.index()));
+        } else {
+            modify_reg!(otg_device, device, DIEPEMPMSK, |v| v | (1 << self.index()));
+        }
+    }
+
+    /// Returns `true` if the endpoint waits for TX FIFO space to continue a transfer.
+    pub fn needs_refill(&self, cs: CriticalSection<'_>) -> bool {
+        matches!(self.transfer.borrow(cs).get(), Some(transfer) if !transfer.remaining.is_empty())
+    }
+
+    /// Handles the transfer completed interrupt.
+    ///
+    /// Returns `false` if a terminating zero-length packet has been queued and the transfer
+    /// isn't complete yet.
+    pub fn complete(&self, cs: CriticalSection<'_>) -> bool {
+        match self.transfer.borrow(cs).take() {
+            Some(InTransfer { zlp: true, .. }) if self.write(&[]).is_ok() => {
+                self.transfer.borrow(cs).set(Some(InTransfer {
+                    remaining: &[],
+                    zlp: false,
+                }));
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Handles the incomplete isochronous IN transfer interrupt (GINTSTS.IISOIXFR).
+    ///
+    /// A packet scheduled for the frame that just ended, which the host hasn't fetched, is
+    /// dropped so the endpoint accepts the next one. Returns `true` if a packet was dropped.
+    pub fn drop_incomplete_iso(&self, cs: CriticalSection<'_>) -> bool {
+        if !self.is_isochronous() {
+            return false;
+        }
+
+        let ep = self.usb.endpoint_in(self.index() as usize);
+        let (enabled, odd) = read_reg!(endpoint_in, ep, DIEPCTL, EPENA, EONUM_DPID);
+        if enabled == 0 || (odd != 0) != odd_frame(self.usb) {
+            return false;
+        }
+
+        modify_reg!(endpoint_in, ep, DIEPCTL, SNAK: 1, EPDIS: 1);
+        // The core disables the endpoint within a few PHY clocks. If it doesn't, the packet
+        // stays queued rather than flushing the FIFO of an active endpoint.
+        let mut timeout = 1000;
+        while read_reg!(endpoint_in, ep, DIEPINT, EPDISD) == 0 {
+            if timeout == 0 {
+                return false;
+            }
+            timeout -= 1;
+        }
+        write_reg!(endpoint_in, ep, DIEPINT, EPDISD: 1);
+
+        // Flush the packet from the TX FIFO
+        let global = self.usb.global();
+        modify_reg!(otg_global, global, GRSTCTL, TXFNUM: self.index() as u32, TXFFLSH: 1);
+        while read_reg!(otg_global, global, GRSTCTL, TXFFLSH) == 1 {}
+
+        self.transfer.borrow(cs).set(None);
+        let device = self.usb.device();
+        modify_reg!(otg_device, device, DIEPEMPMSK, |v| v & !(1 << self.index()));
+
+        true
+    }
 }
 
 pub struct EndpointOut {
     common: Endpoint,
-    pub(crate) buffer: Mutex<RefCell<EndpointBuffer>>,
+    pub(crate) buffer: Mutex<RefCell<EndpointBufferQueue>>,
+    /// Packets are written into `buffer` by the DMA engine
+    dma: bool,
 }
 
 impl EndpointOut {
     pub fn new<USB: UsbPeripheral>(
         descriptor: EndpointDescriptor,
-        buffer: EndpointBuffer,
+        buffer: EndpointBufferQueue,
+        dma: bool,
     ) -> EndpointOut {
         EndpointOut {
             common: Endpoint::new::<USB>(descriptor),
             buffer: Mutex::new(RefCell::new(buffer)),
+            dma,
         }
     }
 
-    pub fn configure(&self, _cs: CriticalSection<'_>) {
+    pub fn configure(&self, cs: CriticalSection<'_>) {
+        // In DMA mode the endpoint is enabled once the buffer address is set
+        let enable = !self.dma as u32;
+
         if self.index() == 0 {
-            let mpsiz = match self.descriptor.max_packet_size {
-                8 => 0b11,
-                16 => 0b10,
-                32 => 0b01,
-                64 => 0b00,
-                other => panic!("Unsupported EP0 size: {}", other),
-            };
+            // The size has been validated on allocation
+            let mpsiz = ep0_mpsiz(self.descriptor.max_packet_size).unwrap_or(0b00);
 
             let regs = self.usb.endpoint0_out();
             write_reg!(endpoint0_out, regs, DOEPTSIZ0, STUPCNT: 1, PKTCNT: 1, XFRSIZ: self.descriptor.max_packet_size as u32);
-            modify_reg!(endpoint0_out, regs, DOEPCTL0, MPSIZ: mpsiz as u32, EPENA: 1, CNAK: 1);
+            modify_reg!(endpoint0_out, regs, DOEPCTL0, MPSIZ: mpsiz, EPENA: enable, CNAK: enable);
         } else {
+            // Isochronous endpoints are enabled for a specific frame below
+            let iso = self.is_isochronous();
+
             let regs = self.usb.endpoint_out(self.index() as usize);
             write_reg!(endpoint_out, regs, DOEPCTL,
                 SD0PID_SEVNFRM: 1,
-                CNAK: 1,
-                EPENA: 1,
+                CNAK: enable & !iso as u32,
+                EPENA: enable & !iso as u32,
                 USBAEP: 1,
                 EPTYP: self.descriptor.ep_type.to_bm_attributes() as u32,
-                MPSIZ: self.descriptor.max_packet_size as u32
+                MPSIZ: self.descriptor.packet_size() as u32
+            );
+
+            if iso && !self.dma {
+                self.enable();
+            }
+        }
+
+        if self.dma {
+            self.start_dma(cs);
+        }
+    }
+
+    /// Points the DMA engine at the next free packet buffer and enables the endpoint.
+    fn start_dma(&self, cs: CriticalSection<'_>) {
+        let address = self.buffer.borrow_ref(cs).dma_address();
+        let max_packet_size = self.descriptor.max_packet_size as u32;
+        let max_payload_size = self.descriptor.max_payload_size() as u32;
+
+        if self.index() == 0 {
+            let regs = self.usb.endpoint0_out();
+            write_reg!(endpoint0_out, regs, DOEPDMA0, address);
+            write_reg!(endpoint0_out, regs, DOEPTSIZ0, STUPCNT: 3, PKTCNT: 1, XFRSIZ: max_packet_size);
+            modify_reg!(endpoint0_out, regs, DOEPCTL0, EPENA: 1, CNAK: 1);
+        } else {
+            let regs = self.usb.endpoint_out(self.index() as usize);
+            write_reg!(endpoint_out, regs, DOEPDMA, address);
+            write_reg!(endpoint_out, regs, DOEPTSIZ,
+                PKTCNT: self.descriptor.transactions() as u32,
+                XFRSIZ: max_payload_size
+            );
+            self.enable();
+        }
+    }
+
+    /// Enables the endpoint to receive the next packet.
+    ///
+    /// Isochronous endpoints are scheduled for the next (micro)frame.
+    pub fn enable(&self) {
+        let regs = self.usb.endpoint_out(self.index() as usize);
+
+        // All packets of a high-bandwidth microframe are received as one transfer
+        let transactions = self.descriptor.transactions();
+        if !self.dma && transactions > 1 {
+            write_reg!(endpoint_out, regs, DOEPTSIZ,
+                PKTCNT: transactions as u32,
+                XFRSIZ: self.descriptor.max_payload_size() as u32
             );
         }
+
+        if !self.is_isochronous() {
+            modify_reg!(endpoint_out, regs, DOEPCTL, CNAK: 1, EPENA: 1);
+        } else if odd_frame(self.usb) {
+            modify_reg!(endpoint_out, regs, DOEPCTL, SD0PID_SEVNFRM: 1, CNAK: 1, EPENA: 1);
+        } else {
+            modify_reg!(endpoint_out, regs, DOEPCTL, SODDFRM: 1, CNAK: 1, EPENA: 1);
+        }
+    }
+
+    /// Handles the incomplete isochronous OUT transfer interrupt (GINTSTS.INCOMPISOOUT).
+    ///
+    /// An endpoint still waiting for a packet in the frame that just ended is rescheduled for
+    /// the next frame, so a missed packet doesn't stall the stream.
+    pub fn reschedule_incomplete_iso(&self) {
+        if !self.is_isochronous() {
+            return;
+        }
+
+        let regs = self.usb.endpoint_out(self.index() as usize);
+        let (enabled, odd) = read_reg!(endpoint_out, regs, DOEPCTL, EPENA, EONUM_DPID);
+        if enabled != 0 && (odd != 0) == odd_frame(self.usb) {
+            self.enable();
+        }
+    }
+
+    /// Handles the endpoint interrupts in DMA mode, marking the received packet as available.
+    pub fn complete_dma(&self, cs: CriticalSection<'_>) {
+        let mut buffer = self.buffer.borrow_ref_mut(cs);
+        let max_packet_size = self.descriptor.max_packet_size as u32;
+
+        if self.index() == 0 {
+            let regs = self.usb.endpoint0_out();
+            let (setup, complete) = read_reg!(endpoint0_out, regs, DOEPINT0, STUP, XFRC);
+            if setup != 0 {
+                write_reg!(endpoint0_out, regs, DOEPINT0, STUP: 1, XFRC: 1);
+
+                // Back-to-back SETUP packets are stored one after another, only the last is valid
+                let end = read_reg!(endpoint0_out, regs, DOEPDMA0);
+                let offset_words = (end.wrapping_sub(buffer.dma_address()) / 4).saturating_sub(2);
+                buffer.fill_from_dma(offset_words as usize, 8, true).ok();
+            } else if complete != 0 {
+                write_reg!(endpoint0_out, regs, DOEPINT0, XFRC: 1);
+
+                let remaining = read_reg!(endpoint0_out, regs, DOEPTSIZ0, XFRSIZ);
+                let data_size = max_packet_size.saturating_sub(remaining);
+                buffer.fill_from_dma(0, data_size as u16, false).ok();
+            } else {
+                return;
+            }
+        } else {
+            let regs = self.usb.endpoint_out(self.index() as usize);
+            if read_reg!(endpoint_out, regs, DOEPINT, XFRC) == 0 {
+                return;
+            }
+            write_reg!(endpoint_out, regs, DOEPINT, XFRC: 1);
+
+            let remaining = read_reg!(endpoint_out, regs, DOEPTSIZ, XFRSIZ);
+            let data_size = (self.descriptor.max_payload_size() as u32).saturating_sub(remaining);
+            buffer.fill_from_dma(0, data_size as u16, false).ok();
+        }
+
+        // Keep receiving while there are free buffers
+        let full = buffer.is_full();
+        drop(buffer);
+        if !full {
+            self.start_dma(cs);
+        }
     }
 
     pub fn deconfigure(&self, _cs: CriticalSection<'_>) {
@@ -205,7 +570,18 @@ impl EndpointOut {
     }
 
     pub fn read(&self, buf: &mut [u8]) -> Result<usize> {
-        critical_section::with(|cs| self.buffer.borrow_ref_mut(cs).read_packet(buf))
+        critical_section::with(|cs| {
+            let mut buffer = self.buffer.borrow_ref_mut(cs);
+            let was_full = buffer.is_full();
+            let size = buffer.read_packet(buf)?;
+            drop(buffer);
+
+            // The endpoint has been left disabled when the last free buffer was filled
+            if self.dma && was_full {
+                self.start_dma(cs);
+            }
+            Ok(size)
+        })
     }
 
     pub fn buffer_state(&self) -> EndpointBufferState {
@@ -1,5 +1,5 @@
 #![allow(dead_code)]
-use crate::target::{fifo_read_into, UsbRegisters};
+use crate::target::{fifo_read, fifo_read_into, UsbRegisters};
 use crate::UsbPeripheral;
 use core::marker::PhantomData;
 use core::slice;
@@ -16,6 +16,10 @@ pub enum EndpointBufferState {
 pub struct EndpointBuffer {
     buffer: &'static mut [VolatileCell<u32>],
     data_size: u16,
+    /// Size of the packets of an unfinished high-bandwidth transfer
+    received: u16,
+    /// The current transfer doesn't fit, its packets are dropped
+    overflow: bool,
     has_data: bool,
     is_setup: bool,
 }
@@ -25,6 +29,8 @@ impl EndpointBuffer {
         Self {
             buffer: unsafe { &mut *(buffer as *mut [u32] as *mut [VolatileCell<u32>]) },
             data_size: 0,
+            received: 0,
+            overflow: false,
             has_data: false,
             is_setup: false,
         }
@@ -35,6 +41,12 @@ impl EndpointBuffer {
             return Err(UsbError::WouldBlock);
         }
 
+        if self.overflow {
+            self.overflow = false;
+            self.has_data = false;
+            return Err(UsbError::BufferOverflow);
+        }
+
         let data_size = self.data_size as usize;
 
         if buf.len() < data_size {
@@ -64,22 +76,95 @@ impl EndpointBuffer {
         Ok(data_size)
     }
 
+    /// Reads a packet from the Rx FIFO.
+    ///
+    /// Packets of a high-bandwidth transfer are appended to each other, the data becomes
+    /// available once the `last` one has been read. A transfer that doesn't fit is dropped up to
+    /// its `last` packet, reading the buffer then reports `BufferOverflow` once.
     pub fn fill_from_fifo(
         &mut self,
         usb: UsbRegisters,
         data_size: u16,
         is_setup: bool,
+        last: bool,
+    ) -> Result<()> {
+        if self.has_data {
+            return Err(UsbError::WouldBlock);
+        }
+
+        let offset = self.received as usize;
+        if self.overflow || offset + data_size as usize > self.capacity() {
+            // Drop the packet and the rest of the transfer
+            for _ in 0..(data_size as usize).div_ceil(4) {
+                fifo_read(usb, &mut [0; 4]);
+            }
+            self.overflow = true;
+            if last {
+                self.is_setup = false;
+                self.data_size = 0;
+                self.received = 0;
+                self.has_data = true;
+            }
+            return Err(UsbError::BufferOverflow);
+        }
+
+        if offset & 0b11 == 0 {
+            let start = offset / 4;
+            let words = (data_size as usize + 3) / 4;
+            fifo_read_into(usb, &self.buffer[start..start + words]);
+        } else {
+            let mut index = offset;
+            for _ in 0..(data_size as usize).div_ceil(4) {
+                let mut bytes = [0; 4];
+                fifo_read(usb, &mut bytes);
+                for byte in bytes.iter().take(offset + data_size as usize - index) {
+                    self.set_byte(index, *byte);
+                    index += 1;
+                }
+            }
+        }
+
+        if last {
+            self.is_setup = is_setup;
+            self.data_size = (offset + data_size as usize) as u16;
+            self.received = 0;
+            self.has_data = true;
+        } else {
+            self.received += data_size;
+        }
+
+        Ok(())
+    }
+
+    fn set_byte(&self, index: usize, byte: u8) {
+        let word = &self.buffer[index / 4];
+        let mut bytes = word.get().to_ne_bytes();
+        bytes[index % 4] = byte;
+        word.set(u32::from_ne_bytes(bytes));
+    }
+
+    /// Marks data written by the core's DMA engine as received.
+    ///
+    /// `offset_words` is where the packet starts in the buffer, it is moved to the beginning.
+    pub fn fill_from_dma(
+        &mut self,
+        offset_words: usize,
+        data_size: u16,
+        is_setup: bool,
     ) -> Result<()> {
         if self.has_data {
             return Err(UsbError::WouldBlock);
         }
 
-        if data_size as usize > self.capacity() {
+        if offset_words * 4 + data_size as usize > self.capacity() {
             return Err(UsbError::BufferOverflow);
         }
 
-        let words = (data_size as usize + 3) / 4;
-        fifo_read_into(usb, &self.buffer[..words]);
+        if offset_words != 0 {
+            for index in 0..(data_size as usize).div_ceil(4) {
+                self.buffer[index].set(self.buffer[offset_words + index].get());
+            }
+        }
 
         self.is_setup = is_setup;
         self.data_size = data_size;
@@ -88,6 +173,26 @@ impl EndpointBuffer {
         Ok(())
     }
 
+    /// Copies a packet into the buffer for the core's DMA engine to fetch.
+    pub fn write_packet(&mut self, buf: &[u8]) -> Result<()> {
+        if buf.len() > self.capacity() {
+            return Err(UsbError::BufferOverflow);
+        }
+
+        for (word, bytes) in self.buffer.iter().zip(buf.chunks(4)) {
+            let mut u32_bytes = [0u8; 4];
+            u32_bytes[..bytes.len()].copy_from_slice(bytes);
+            word.set(u32::from_ne_bytes(u32_bytes));
+        }
+
+        Ok(())
+    }
+
+    /// Address of the buffer as seen by the core's DMA engine
+    pub fn dma_address(&self) -> u32 {
+        self.buffer.as_ptr() as u32
+    }
+
     pub fn state(&self) -> EndpointBufferState {
         if self.has_data {
             if self.is_setup {
@@ -111,8 +216,114 @@ impl Default for EndpointBuffer {
     }
 }
 
+/// Maximum number of packet buffers per OUT endpoint
+pub const MAX_OUT_BUFFERS: usize = 8;
+
+/// Ring of packet buffers of an OUT endpoint, filled and read in order
+pub struct EndpointBufferQueue {
+    buffers: [EndpointBuffer; MAX_OUT_BUFFERS],
+    count: usize,
+    read_index: usize,
+    filled: usize,
+}
+
+impl EndpointBufferQueue {
+    pub fn new() -> Self {
+        Self {
+            buffers: Default::default(),
+            count: 0,
+            read_index: 0,
+            filled: 0,
+        }
+    }
+
+    pub fn push_buffer(&mut self, buffer: EndpointBuffer) {
+        assert!(self.count < MAX_OUT_BUFFERS);
+        self.buffers[self.count] = buffer;
+        self.count += 1;
+    }
+
+    fn write_index(&self) -> usize {
+        (self.read_index + self.filled) % self.count
+    }
+
+    pub fn read_packet(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.filled == 0 {
+            return Err(UsbError::WouldBlock);
+        }
+
+        let result = self.buffers[self.read_index].read_packet(buf);
+        // The packet stays queued if `buf` is too small for it
+        if !self.buffers[self.read_index].has_data {
+            self.read_index = (self.read_index + 1) % self.count;
+            self.filled -= 1;
+        }
+
+        result
+    }
+
+    pub fn fill_from_fifo(
+        &mut self,
+        usb: UsbRegisters,
+        data_size: u16,
+        is_setup: bool,
+        last: bool,
+    ) -> Result<()> {
+        if self.is_full() {
+            return Err(UsbError::WouldBlock);
+        }
+
+        let index = self.write_index();
+        let result = self.buffers[index].fill_from_fifo(usb, data_size, is_setup, last);
+        // A dropped transfer takes up the buffer as well, until the overflow has been reported
+        if last {
+            self.filled += 1;
+        }
+
+        result
+    }
+
+    pub fn fill_from_dma(
+        &mut self,
+        offset_words: usize,
+        data_size: u16,
+        is_setup: bool,
+    ) -> Result<()> {
+        if self.is_full() {
+            return Err(UsbError::WouldBlock);
+        }
+
+        let index = self.write_index();
+        self.buffers[index].fill_from_dma(offset_words, data_size, is_setup)?;
+        self.filled += 1;
+
+        Ok(())
+    }
+
+    /// Address of the buffer the next packet is received into
+    pub fn dma_address(&self) -> u32 {
+        self.buffers[self.write_index()].dma_address()
+    }
+
+    /// State of the oldest packet in the queue
+    pub fn state(&self) -> EndpointBufferState {
+        if self.filled == 0 {
+            EndpointBufferState::Empty
+        } else {
+            self.buffers[self.read_index].state()
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.filled == self.count
+    }
+}
+
 pub struct EndpointMemoryAllocator<USB> {
     next_free_offset: usize,
+    rx_size_words: usize,
+    /// Words of the Rx FIFO in addition to the OUT packet buffers
+    rx_fifo_slack: u16,
     max_size_words: usize,
     memory: &'static mut [u32],
     tx_fifo_size_words: [u16; 9],
@@ -120,9 +331,11 @@ pub struct EndpointMemoryAllocator<USB> {
 }
 
 impl<USB: UsbPeripheral> EndpointMemoryAllocator<USB> {
-    pub fn new(memory: &'static mut [u32]) -> Self {
+    pub fn new(memory: &'static mut [u32], rx_fifo_slack: u16) -> Self {
         Self {
             next_free_offset: 0,
+            rx_size_words: 0,
+            rx_fifo_slack,
             max_size_words: 0,
             memory,
             tx_fifo_size_words: [0; 9],
@@ -131,7 +344,34 @@ impl<USB: UsbPeripheral> EndpointMemoryAllocator<USB> {
     }
 
     pub fn allocate_rx_buffer(&mut self, size: usize) -> Result<EndpointBuffer> {
-        let size_words = (size + 3) / 4;
+        // The Rx FIFO grows with each buffer, it has to fit next to the Tx FIFOs
+        if self.fifo_words_used() + size.div_ceil(4) > USB::FIFO_DEPTH_WORDS {
+            return Err(UsbError::EndpointMemoryOverflow);
+        }
+
+        let buffer = self.allocate_buffer(size)?;
+
+        let size_words = buffer.capacity() / 4;
+        self.rx_size_words += size_words;
+        self.max_size_words = core::cmp::max(self.max_size_words, size_words);
+
+        Ok(buffer)
+    }
+
+    /// Allocates a buffer the core's DMA engine fetches IN packets from
+    pub fn allocate_dma_tx_buffer(&mut self, size: usize) -> Result<EndpointBuffer> {
+        self.allocate_buffer(size)
+    }
+
+    /// Allocates an additional packet buffer for an OUT endpoint queue.
+    ///
+    /// Unlike [`allocate_rx_buffer`](Self::allocate_rx_buffer) this doesn't grow the Rx FIFO.
+    pub fn allocate_queued_rx_buffer(&mut self, size: usize) -> Result<EndpointBuffer> {
+        self.allocate_buffer(size)
+    }
+
+    fn allocate_buffer(&mut self, size: usize) -> Result<EndpointBuffer> {
+        let size_words = size.div_ceil(4);
 
         let offset = self.next_free_offset;
         if offset + size_words > self.memory.len() {
@@ -139,7 +379,6 @@ impl<USB: UsbPeripheral> EndpointMemoryAllocator<USB> {
         }
 
         self.next_free_offset += size_words;
-        self.max_size_words = core::cmp::max(self.max_size_words, size_words);
 
         let buffer = unsafe {
             let ptr = self.memory.as_mut_ptr().add(offset);
@@ -156,12 +395,8 @@ impl<USB: UsbPeripheral> EndpointMemoryAllocator<USB> {
             return Err(UsbError::InvalidEndpoint);
         }
 
-        let used = 30
-            + self.total_rx_buffer_size_words() as usize
-            + self.tx_fifo_size_words.iter().sum::<u16>() as usize;
-
         let size_words = core::cmp::max((size + 3) / 4, 16);
-        if (used + size_words) > USB::FIFO_DEPTH_WORDS {
+        if (self.fifo_words_used() + size_words) > USB::FIFO_DEPTH_WORDS {
             return Err(UsbError::EndpointMemoryOverflow);
         }
 
@@ -170,9 +405,16 @@ impl<USB: UsbPeripheral> EndpointMemoryAllocator<USB> {
         Ok(())
     }
 
+    /// Returns the number of FIFO words taken by the Rx FIFO and the Tx FIFOs
+    fn fifo_words_used(&self) -> usize {
+        self.rx_fifo_slack as usize
+            + self.total_rx_buffer_size_words() as usize
+            + self.tx_fifo_size_words.iter().sum::<u16>() as usize
+    }
+
     /// Returns the size of memory allocated for OUT endpoints in words
     pub fn total_rx_buffer_size_words(&self) -> u16 {
-        self.next_free_offset as u16
+        self.rx_size_words as u16
     }
 
     pub fn tx_fifo_size_words(&self, ep_number: usize) -> u16 {
@@ -0,0 +1,580 @@
+use crate::host::{DeviceSpeed, HostError, Pipe, PipeConfig, UsbHost};
+use crate::UsbPeripheral;
+use embedded_hal::blocking::delay::DelayMs;
+use usb_device::control::Request;
+use usb_device::descriptor::descriptor_type;
+use usb_device::endpoint::{
+    EndpointAddress, EndpointType, IsochronousSynchronizationType, IsochronousUsageType,
+};
+use usb_device::UsbDirection;
+
+/// Maximum number of interfaces (including alternate settings) kept for a configuration.
+/// Further interfaces are skipped together with their endpoints.
+pub const MAX_INTERFACES: usize = 8;
+
+/// Maximum number of endpoints kept for an interface. Further endpoints are skipped.
+pub const MAX_ENDPOINTS: usize = 4;
+
+/// Errors that can occur during enumeration.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum EnumerationError {
+    /// A transfer failed in the given enumeration step.
+    Host(EnumerationState, HostError),
+    /// The device returned a malformed descriptor.
+    InvalidDescriptor,
+    /// The configuration descriptor does not fit into the provided buffer.
+    BufferOverflow,
+    /// All 127 device addresses are in use.
+    AddressOverflow,
+}
+
+/// Steps of the enumeration sequence.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum EnumerationState {
+    /// Resetting the port before talking to the device.
+    Reset,
+    /// Reading the first 8 bytes of the device descriptor to learn the EP0 packet size.
+    GetMaxPacketSize,
+    /// Resetting the port a second time.
+    SecondReset,
+    /// Assigning the device address.
+    SetAddress,
+    /// Reading the full device descriptor.
+    GetDeviceDescriptor,
+    /// Reading the configuration descriptor and all its subordinate descriptors.
+    GetConfigurationDescriptor,
+    /// Selecting the configuration.
+    SetConfiguration,
+    /// Enumeration finished.
+    Configured,
+}
+
+/// Standard device descriptor.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct DeviceDescriptor {
+    /// USB specification release number in BCD (`bcdUSB`).
+    pub usb_release: u16,
+    /// Class code (`bDeviceClass`), 0 if the class is defined per interface.
+    pub device_class: u8,
+    /// Subclass code (`bDeviceSubClass`).
+    pub device_sub_class: u8,
+    /// Protocol code (`bDeviceProtocol`).
+    pub device_protocol: u8,
+    /// Maximum packet size of endpoint 0 (`bMaxPacketSize0`).
+    pub max_packet_size_0: u8,
+    /// Vendor ID (`idVendor`).
+    pub vendor_id: u16,
+    /// Product ID (`idProduct`).
+    pub product_id: u16,
+    /// Device release number in BCD (`bcdDevice`).
+    pub device_release: u16,
+    /// Index of the manufacturer string descriptor, 0 if there is none.
+    pub manufacturer_index: u8,
+    /// Index of the product string descriptor, 0 if there is none.
+    pub product_index: u8,
+    /// Index of the serial number string descriptor, 0 if there is none.
+    pub serial_number_index: u8,
+    /// Number of configurations (`bNumConfigurations`).
+    pub num_configurations: u8,
+}
+
+/// Endpoint of an interface.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct EndpointInfo {
+    /// Address and direction of the endpoint.
+    pub address: EndpointAddress,
+    /// Transfer type of the endpoint.
+    pub ep_type: EndpointType,
+    /// Maximum packet size (`wMaxPacketSize`), including the additional transactions bits
+    /// of high-bandwidth endpoints.
+    pub max_packet_size: u16,
+    /// Polling interval (`bInterval`) for periodic endpoints.
+    pub interval: u8,
+}
+
+/// Interface (or alternate setting) of a configuration.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct InterfaceInfo {
+    /// Interface number (`bInterfaceNumber`).
+    pub number: u8,
+    /// Alternate setting (`bAlternateSetting`).
+    pub alternate_setting: u8,
+    /// Class code (`bInterfaceClass`).
+    pub class: u8,
+    /// Subclass code (`bInterfaceSubClass`).
+    pub sub_class: u8,
+    /// Protocol code (`bInterfaceProtocol`).
+    pub protocol: u8,
+    endpoints: [Option<EndpointInfo>; MAX_ENDPOINTS],
+    num_endpoints: usize,
+}
+
+impl InterfaceInfo {
+    /// Returns the endpoints of the interface.
+    pub fn endpoints(&self) -> impl Iterator<Item = &EndpointInfo> {
+        self.endpoints[..self.num_endpoints].iter().flatten()
+    }
+}
+
+/// Configuration selected during enumeration.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct ConfigurationInfo {
+    /// Value selecting the configuration with SET_CONFIGURATION (`bConfigurationValue`).
+    pub value: u8,
+    /// Self-powered and remote wakeup attributes (`bmAttributes`).
+    pub attributes: u8,
+    /// Maximum bus power consumption in units of 2 mA (`bMaxPower`).
+    pub max_power: u8,
+    interfaces: [InterfaceInfo; MAX_INTERFACES],
+    num_interfaces: usize,
+    truncated: bool,
+}
+
+impl ConfigurationInfo {
+    /// Returns the interfaces and alternate settings of the configuration.
+    pub fn interfaces(&self) -> &[InterfaceInfo] {
+        &self.interfaces[..self.num_interfaces]
+    }
+
+    /// Returns `true` if interfaces or endpoints beyond [`MAX_INTERFACES`] or
+    /// [`MAX_ENDPOINTS`] have been skipped.
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// Parses a configuration descriptor together with its interface and endpoint descriptors.
+    pub fn parse(data: &[u8]) -> Result<Self, EnumerationError> {
+        let mut config = ConfigurationInfo::default();
+        let mut offset = 0;
+        // Endpoints following a skipped interface belong to it
+        let mut skipping = false;
+
+        while offset + 2 <= data.len() {
+            let length = data[offset] as usize;
+            let kind = data[offset + 1];
+            if length < 2 || offset + length > data.len() {
+                return Err(EnumerationError::InvalidDescriptor);
+            }
+            let desc = &data[offset..offset + length];
+
+            match kind {
+                descriptor_type::CONFIGURATION if length >= 9 => {
+                    config.value = desc[5];
+                    config.attributes = desc[7];
+                    config.max_power = desc[8];
+                }
+                descriptor_type::INTERFACE if length >= 9 => {
+                    skipping = config.num_interfaces == MAX_INTERFACES;
+                    if skipping {
+                        config.truncated = true;
+                        offset += length;
+                        continue;
+                    }
+                    config.interfaces[config.num_interfaces] = InterfaceInfo {
+                        number: desc[2],
+                        alternate_setting: desc[3],
+                        class: desc[5],
+                        sub_class: desc[6],
+                        protocol: desc[7],
+                        ..Default::default()
+                    };
+                    config.num_interfaces += 1;
+                }
+                descriptor_type::ENDPOINT if length >= 7 => {
+                    if config.num_interfaces == 0 {
+                        return Err(EnumerationError::InvalidDescriptor);
+                    }
+                    let interface = &mut config.interfaces[config.num_interfaces - 1];
+                    if skipping || interface.num_endpoints == MAX_ENDPOINTS {
+                        config.truncated = true;
+                        offset += length;
+                        continue;
+                    }
+                    interface.endpoints[interface.num_endpoints] = Some(EndpointInfo {
+                        address: EndpointAddress::from(desc[2]),
+                        ep_type: decode_ep_type(desc[3]),
+                        max_packet_size: u16::from_le_bytes([desc[4], desc[5]]),
+                        interval: desc[6],
+                    });
+                    interface.num_endpoints += 1;
+                }
+                _ => {}
+            }
+
+            offset += length;
+        }
+
+        Ok(config)
+    }
+}
+
+impl DeviceDescriptor {
+    /// Parses a standard device descriptor.
+    pub fn parse(data: &[u8]) -> Result<Self, EnumerationError> {
+        if data.len() < 18 || data[0] < 18 || data[1] != descriptor_type::DEVICE {
+            return Err(EnumerationError::InvalidDescriptor);
+        }
+        Ok(DeviceDescriptor {
+            usb_release: u16::from_le_bytes([data[2], data[3]]),
+            device_class: data[4],
+            device_sub_class: data[5],
+            device_protocol: data[6],
+            max_packet_size_0: data[7],
+            vendor_id: u16::from_le_bytes([data[8], data[9]]),
+            product_id: u16::from_le_bytes([data[10], data[11]]),
+            device_release: u16::from_le_bytes([data[12], data[13]]),
+            manufacturer_index: data[14],
+            product_index: data[15],
+            serial_number_index: data[16],
+            num_configurations: data[17],
+        })
+    }
+}
+
+/// A device that has been enumerated and configured.
+#[derive(Copy, Clone, Debug)]
+pub struct EnumeratedDevice {
+    /// Address assigned to the device.
+    pub address: u8,
+    /// Speed of the device.
+    pub speed: DeviceSpeed,
+    /// Control pipe to the default endpoint of the device.
+    pub control_pipe: Pipe,
+    /// The device descriptor.
+    pub device: DeviceDescriptor,
+    /// The selected (first) configuration.
+    pub configuration: ConfigurationInfo,
+}
+
+impl EnumeratedDevice {
+    /// Returns a pipe configuration for one of the endpoints of the device.
+    ///
+    /// Pipes run one transaction per (micro)frame, the additional transactions bits of
+    /// high-bandwidth endpoints are dropped from the maximum packet size.
+    pub fn pipe_config(&self, endpoint: &EndpointInfo) -> PipeConfig {
+        PipeConfig {
+            device_address: self.address,
+            endpoint: endpoint.address,
+            ep_type: endpoint.ep_type,
+            max_packet_size: endpoint.max_packet_size & 0x7ff,
+            speed: self.speed,
+        }
+    }
+}
+
+/// Port and control pipe operations the enumeration sequence runs on.
+///
+/// Implemented by [`UsbHost`], and by the virtual host of the simulator to check the sequence
+/// against the device driver.
+pub(crate) trait EnumerationHost {
+    fn reset_port(&mut self, delay: &mut impl DelayMs<u32>) -> Result<DeviceSpeed, HostError>;
+    fn alloc_pipe(&mut self, config: PipeConfig) -> Result<Pipe, HostError>;
+    fn free_pipe(&mut self, pipe: Pipe);
+    fn reconfigure_pipe(
+        &mut self,
+        pipe: Pipe,
+        device_address: u8,
+        max_packet_size: u16,
+    ) -> Result<(), HostError>;
+    fn control_in(
+        &mut self,
+        pipe: Pipe,
+        setup: &[u8; 8],
+        buf: &mut [u8],
+    ) -> Result<usize, HostError>;
+    fn control_out(&mut self, pipe: Pipe, setup: &[u8; 8], data: &[u8])
+        -> Result<usize, HostError>;
+}
+
+impl<USB: UsbPeripheral> EnumerationHost for UsbHost<USB> {
+    fn reset_port(&mut self, delay: &mut impl DelayMs<u32>) -> Result<DeviceSpeed, HostError> {
+        UsbHost::reset_port(self, delay)
+    }
+
+    fn alloc_pipe(&mut self, config: PipeConfig) -> Result<Pipe, HostError> {
+        UsbHost::alloc_pipe(self, config)
+    }
+
+    fn free_pipe(&mut self, pipe: Pipe) {
+        UsbHost::free_pipe(self, pipe)
+    }
+
+    fn reconfigure_pipe(
+        &mut self,
+        pipe: Pipe,
+        device_address: u8,
+        max_packet_size: u16,
+    ) -> Result<(), HostError> {
+        UsbHost::reconfigure_pipe(self, pipe, device_address, max_packet_size)
+    }
+
+    fn control_in(
+        &mut self,
+        pipe: Pipe,
+        setup: &[u8; 8],
+        buf: &mut [u8],
+    ) -> Result<usize, HostError> {
+        UsbHost::control_in(self, pipe, setup, buf)
+    }
+
+    fn control_out(
+        &mut self,
+        pipe: Pipe,
+        setup: &[u8; 8],
+        data: &[u8],
+    ) -> Result<usize, HostError> {
+        UsbHost::control_out(self, pipe, setup, data)
+    }
+}
+
+/// Drives the standard enumeration sequence for a newly attached device.
+///
+/// The enumerator hands out device addresses and keeps track of the ones in use.
+pub struct Enumerator {
+    /// Bit `n` is set if address `n` is assigned, address 0 is always reserved
+    used_addresses: u128,
+}
+
+impl Enumerator {
+    /// Creates an enumerator with all device addresses available.
+    pub fn new() -> Self {
+        Self { used_addresses: 1 }
+    }
+
+    /// Makes the address of a detached device available again.
+    ///
+    /// Call this when a device returned by [`enumerate`](Self::enumerate) is gone, otherwise
+    /// enumeration fails with [`EnumerationError::AddressOverflow`] after 127 devices.
+    pub fn release(&mut self, address: u8) {
+        if address != 0 && address <= 127 {
+            self.used_addresses &= !(1 << address);
+        }
+    }
+
+    fn free_address(&self) -> Option<u8> {
+        let address = (!self.used_addresses).trailing_zeros();
+        if address < 128 {
+            Some(address as u8)
+        } else {
+            None
+        }
+    }
+
+    /// Enumerates the device attached to the port and selects its first configuration.
+    ///
+    /// `buf` receives the full configuration descriptor and must be large enough to hold it.
+    /// On success the control pipe of the device stays allocated.
+    pub fn enumerate<USB: UsbPeripheral>(
+        &mut self,
+        host: &mut UsbHost<USB>,
+        delay: &mut impl DelayMs<u32>,
+        buf: &mut [u8],
+    ) -> Result<EnumeratedDevice, EnumerationError> {
+        self.enumerate_on(host, delay, buf)
+    }
+
+    pub(crate) fn enumerate_on(
+        &mut self,
+        host: &mut impl EnumerationHost,
+        delay: &mut impl DelayMs<u32>,
+        buf: &mut [u8],
+    ) -> Result<EnumeratedDevice, EnumerationError> {
+        let address = self
+            .free_address()
+            .ok_or(EnumerationError::AddressOverflow)?;
+
+        let mut state = EnumerationState::Reset;
+        let speed = host
+            .reset_port(delay)
+            .map_err(|e| EnumerationError::Host(state, e))?;
+
+        let pipe = host
+            .alloc_pipe(PipeConfig {
+                device_address: 0,
+                endpoint: EndpointAddress::from_parts(0, UsbDirection::Out),
+                ep_type: EndpointType::Control,
+                max_packet_size: if speed == DeviceSpeed::High { 64 } else { 8 },
+                speed,
+            })
+            .map_err(|e| EnumerationError::Host(state, e))?;
+
+        let result = Self::run(host, delay, buf, pipe, address, speed, &mut state);
+        match result {
+            Ok(_) => self.used_addresses |= 1 << address,
+            Err(_) => host.free_pipe(pipe),
+        }
+        result
+    }
+
+    fn run(
+        host: &mut impl EnumerationHost,
+        delay: &mut impl DelayMs<u32>,
+        buf: &mut [u8],
+        pipe: Pipe,
+        address: u8,
+        speed: DeviceSpeed,
+        state: &mut EnumerationState,
+    ) -> Result<EnumeratedDevice, EnumerationError> {
+        let mut max_packet_size = 8;
+        let mut device = DeviceDescriptor::default();
+        let mut configuration = ConfigurationInfo::default();
+
+        loop {
+            let step = *state;
+            let err = |e| EnumerationError::Host(step, e);
+
+            *state = match step {
+                EnumerationState::Reset => EnumerationState::GetMaxPacketSize,
+                EnumerationState::GetMaxPacketSize => {
+                    let mut header = [0u8; 8];
+                    host.control_in(
+                        pipe,
+                        &get_descriptor(descriptor_type::DEVICE, 8),
+                        &mut header,
+                    )
+                    .map_err(err)?;
+                    if header[7] == 0 {
+                        return Err(EnumerationError::InvalidDescriptor);
+                    }
+                    max_packet_size = header[7] as u16;
+                    host.reconfigure_pipe(pipe, 0, max_packet_size)
+                        .map_err(err)?;
+                    EnumerationState::SecondReset
+                }
+                EnumerationState::SecondReset => {
+                    host.reset_port(delay).map_err(err)?;
+                    EnumerationState::SetAddress
+                }
+                EnumerationState::SetAddress => {
+                    host.control_out(
+                        pipe,
+                        &setup_packet(0x00, Request::SET_ADDRESS, address as u16, 0, 0),
+                        &[],
+                    )
+                    .map_err(err)?;
+                    // Set-address recovery interval: give the device 2ms to apply the address
+                    delay.delay_ms(2);
+                    host.reconfigure_pipe(pipe, address, max_packet_size)
+                        .map_err(err)?;
+                    EnumerationState::GetDeviceDescriptor
+                }
+                EnumerationState::GetDeviceDescriptor => {
+                    let mut data = [0u8; 18];
+                    let len = host
+                        .control_in(
+                            pipe,
+                            &get_descriptor(descriptor_type::DEVICE, 18),
+                            &mut data,
+                        )
+                        .map_err(err)?;
+                    device = DeviceDescriptor::parse(&data[..len])?;
+                    EnumerationState::GetConfigurationDescriptor
+                }
+                EnumerationState::GetConfigurationDescriptor => {
+                    let mut header = [0u8; 9];
+                    let len = host
+                        .control_in(
+                            pipe,
+                            &get_descriptor(descriptor_type::CONFIGURATION, 9),
+                            &mut header,
+                        )
+                        .map_err(err)?;
+                    if len < 4 {
+                        return Err(EnumerationError::InvalidDescriptor);
+                    }
+                    let total_length = u16::from_le_bytes([header[2], header[3]]) as usize;
+                    if total_length > buf.len() {
+                        return Err(EnumerationError::BufferOverflow);
+                    }
+                    let len = host
+                        .control_in(
+                            pipe,
+                            &get_descriptor(descriptor_type::CONFIGURATION, total_length as u16),
+                            &mut buf[..total_length],
+                        )
+                        .map_err(err)?;
+                    configuration = ConfigurationInfo::parse(&buf[..len])?;
+                    EnumerationState::SetConfiguration
+                }
+                EnumerationState::SetConfiguration => {
+                    host.control_out(
+                        pipe,
+                        &setup_packet(
+                            0x00,
+                            Request::SET_CONFIGURATION,
+                            configuration.value as u16,
+                            0,
+                            0,
+                        ),
+                        &[],
+                    )
+                    .map_err(err)?;
+                    EnumerationState::Configured
+                }
+                EnumerationState::Configured => {
+                    return Ok(EnumeratedDevice {
+                        address,
+                        speed,
+                        control_pipe: pipe,
+                        device,
+                        configuration,
+                    });
+                }
+            };
+        }
+    }
+}
+
+impl Default for Enumerator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Builds a SETUP packet from the fields of a control request.
+pub fn setup_packet(request_type: u8, request: u8, value: u16, index: u16, length: u16) -> [u8; 8] {
+    let value = value.to_le_bytes();
+    let index = index.to_le_bytes();
+    let length = length.to_le_bytes();
+    [
+        request_type,
+        request,
+        value[0],
+        value[1],
+        index[0],
+        index[1],
+        length[0],
+        length[1],
+    ]
+}
+
+pub(crate) fn get_descriptor(descriptor_type: u8, length: u16) -> [u8; 8] {
+    setup_packet(
+        0x80,
+        Request::GET_DESCRIPTOR,
+        (descriptor_type as u16) << 8,
+        0,
+        length,
+    )
+}
+
+fn decode_ep_type(attributes: u8) -> EndpointType {
+    match attributes & 0b11 {
+        0b00 => EndpointType::Control,
+        0b01 => EndpointType::Isochronous {
+            synchronization: match (attributes >> 2) & 0b11 {
+                0b00 => IsochronousSynchronizationType::NoSynchronization,
+                0b01 => IsochronousSynchronizationType::Asynchronous,
+                0b10 => IsochronousSynchronizationType::Adaptive,
+                _ => IsochronousSynchronizationType::Synchronous,
+            },
+            usage: match (attributes >> 4) & 0b11 {
+                0b00 => IsochronousUsageType::Data,
+                0b01 => IsochronousUsageType::Feedback,
+                _ => IsochronousUsageType::ImplicitFeedbackData,
+            },
+        },
+        0b10 => EndpointType::Bulk,
+        _ => EndpointType::Interrupt,
+    }
+}
@@ -0,0 +1,550 @@
+use crate::host_channel::Channel;
+use crate::otg::OtgCapabilities;
+use crate::ral::{modify_reg, otg_global, otg_host, otg_pwrclk, read_reg, write_reg};
+use crate::target::{CoreMode, UsbRegisters};
+use crate::{PhyType, UsbPeripheral};
+use core::cell::Cell;
+use core::marker::PhantomData;
+use critical_section::{CriticalSection, Mutex};
+use embedded_hal::blocking::delay::DelayMs;
+use usb_device::endpoint::{EndpointAddress, EndpointType};
+
+/// HPRT bits that are cleared by writing 1. Writing 1 to PENA disables the port.
+const HPRT_W1C_MASK: u32 = otg_host::HPRT::PCDET::mask
+    | otg_host::HPRT::PENA::mask
+    | otg_host::HPRT::PENCHNG::mask
+    | otg_host::HPRT::POCCHNG::mask;
+
+/// Speed of the device attached to the host port.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DeviceSpeed {
+    /// High speed (480 Mbit/s)
+    High,
+    /// Full speed (12 Mbit/s)
+    Full,
+    /// Low speed (1.5 Mbit/s)
+    Low,
+}
+
+/// Port events reported by [`UsbHost::poll`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum HostEvent {
+    /// Nothing happened.
+    None,
+    /// A device has been attached. The port has to be reset before the device can be used.
+    DeviceConnected,
+    /// The device has been detached.
+    DeviceDisconnected,
+    /// The port has been enabled after a reset and the attached device runs at the given speed.
+    PortEnabled(DeviceSpeed),
+    /// The port has been disabled.
+    PortDisabled,
+    /// The port over-current condition has changed.
+    OverCurrent,
+}
+
+/// Errors that can occur while operating in host mode.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum HostError {
+    /// No device is attached to the port.
+    NotConnected,
+    /// The port is not enabled, or was disabled during a transaction (e.g. by unplugging the
+    /// device).
+    PortDisabled,
+    /// All host channels are in use.
+    ChannelOverflow,
+    /// The pipe does not exist or does not support the requested operation.
+    InvalidPipe,
+    /// The device responded with STALL.
+    Stall,
+    /// The device responded with NAK. Control and bulk transactions are retried a number of
+    /// times before this is reported.
+    Nak,
+    /// Transaction error: CRC failure, response timeout, bit stuff error or false EOP (XactErr).
+    TransactionError,
+    /// The device sent more data than the maximum packet size (BabbleErr).
+    Babble,
+    /// The device sent a packet with an unexpected data PID (DataToggleErr).
+    DataToggle,
+    /// A periodic transaction could not be completed within its (micro)frame.
+    FrameOverrun,
+    /// The received data does not fit into the provided buffer.
+    BufferOverflow,
+    /// The transaction did not finish in time.
+    Timeout,
+}
+
+type Result<T> = core::result::Result<T, HostError>;
+
+/// Configuration for a pipe allocation.
+#[derive(Copy, Clone, Debug)]
+pub struct PipeConfig {
+    /// Address of the device the pipe talks to.
+    pub device_address: u8,
+
+    /// Endpoint on the device. The direction is ignored for control pipes.
+    pub endpoint: EndpointAddress,
+
+    /// The transfer type of the endpoint.
+    pub ep_type: EndpointType,
+
+    /// Maximum packet size of the endpoint.
+    pub max_packet_size: u16,
+
+    /// Speed of the device.
+    pub speed: DeviceSpeed,
+}
+
+/// Handle to a host channel allocated with [`UsbHost::alloc_pipe`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Pipe(pub(crate) u8);
+
+impl Pipe {
+    /// Returns the index of the host channel backing this pipe.
+    pub fn channel(&self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// USB host driver for STM32 microcontrollers.
+pub struct UsbHost<USB> {
+    peripheral: USB,
+    regs: Mutex<UsbRegisters>,
+    allocator: ChannelAllocator<USB>,
+    otg: OtgCapabilities,
+    otg_interrupts: Mutex<Cell<u32>>,
+}
+
+impl<USB: UsbPeripheral> UsbHost<USB> {
+    /// Constructs a new USB host driver.
+    pub fn new(peripheral: USB) -> Self {
+        Self {
+            peripheral,
+            regs: Mutex::new(UsbRegisters::new::<USB>()),
+            allocator: ChannelAllocator::new(),
+            otg: OtgCapabilities::default(),
+            otg_interrupts: Mutex::new(Cell::new(0)),
+        }
+    }
+
+    /// OTG interrupts taken by `Negotiation::poll` that haven't been reported yet
+    pub(crate) fn otg_interrupts<'cs>(&'cs self, cs: CriticalSection<'cs>) -> &'cs Cell<u32> {
+        self.otg_interrupts.borrow(cs)
+    }
+
+    /// Releases the peripheral. The core is left in its current state.
+    pub fn free(self) -> USB {
+        self.peripheral
+    }
+
+    /// Sets the OTG protocols the core takes part in. Call this before [`enable`](Self::enable).
+    ///
+    /// HNP requires the core to pick its role from the ID pin and the negotiation, so host mode
+    /// is no longer forced.
+    pub fn set_otg_capabilities(&mut self, capabilities: OtgCapabilities) {
+        self.otg = capabilities;
+    }
+
+    /// Forces the core into host mode and powers the port.
+    pub fn enable(&mut self) {
+        // Enable USB_OTG in RCC
+        USB::enable();
+
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+            crate::target::init_core(&self.peripheral, *regs, CoreMode::Host, self.otg, 1);
+        });
+
+        self.start(true);
+    }
+
+    /// Continues in host mode after the core became host through HNP.
+    ///
+    /// Unlike [`enable`](Self::enable) this doesn't reset the core, and VBUS stays supplied by
+    /// the A-device.
+    pub(crate) fn enable_negotiated(&mut self) {
+        self.start(false);
+    }
+
+    fn start(&mut self, supply_vbus: bool) {
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+
+            let core_id = read_reg!(otg_global, regs.global(), CID);
+
+            // Host supplies VBUS itself, disable VBUS sensing
+            match core_id {
+                0x0000_1200 | 0x0000_1100 => {
+                    //modify_reg!(otg_global, regs.global, GCCFG, NOVBUSSENS: 1);
+                    modify_reg!(otg_global, regs.global(), GCCFG, |r| r | (1 << 21));
+
+                    modify_reg!(otg_global, regs.global(), GCCFG, VBUSASEN: 0, VBUSBSEN: 0, SOFOUTEN: 0);
+                }
+                0x0000_2000 | 0x0000_2100 | 0x0000_2300 | 0x0000_3000 | 0x0000_3100 => {
+                    //modify_reg!(otg_global, regs.global, GCCFG, VBDEN: 0);
+                    modify_reg!(otg_global, regs.global(), GCCFG, |r| r & !(1 << 21));
+                }
+                _ => {}
+            }
+
+            // Enable PHY clock
+            write_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, 0);
+
+            // Select PHY clock, 48 MHz for the FS PHY
+            if USB::HIGH_SPEED && self.peripheral.phy_type() != PhyType::InternalFullSpeed {
+                modify_reg!(otg_host, regs.host(), HCFG, FSLSS: 0, FSLSPCS: 0);
+            } else {
+                modify_reg!(otg_host, regs.host(), HCFG, FSLSS: 1, FSLSPCS: 1);
+            }
+
+            // Rx FIFO takes one half of the FIFO memory, the rest is split between
+            // non-periodic and periodic Tx FIFOs
+            let rx_fifo_size = USB::FIFO_DEPTH_WORDS / 2;
+            let np_tx_fifo_size = USB::FIFO_DEPTH_WORDS / 4;
+            let p_tx_fifo_size = USB::FIFO_DEPTH_WORDS - rx_fifo_size - np_tx_fifo_size;
+
+            write_reg!(otg_global, regs.global(), GRXFSIZ, rx_fifo_size as u32);
+            #[cfg(not(feature = "hs"))]
+            write_reg!(otg_global, regs.global(), DIEPTXF0,
+                NPTXFD: np_tx_fifo_size as u32,
+                NPTXFSA: rx_fifo_size as u32
+            );
+            #[cfg(feature = "hs")]
+            write_reg!(otg_global, regs.global(), GNPTXFSIZ,
+                NPTXFD: np_tx_fifo_size as u32,
+                NPTXFSA: rx_fifo_size as u32
+            );
+            #[cfg(not(feature = "hs"))]
+            write_reg!(otg_global, regs.global(), HPTXFSIZ,
+                PTXFSIZ: p_tx_fifo_size as u32,
+                PTXSA: (rx_fifo_size + np_tx_fifo_size) as u32
+            );
+            #[cfg(feature = "hs")]
+            write_reg!(otg_global, regs.global(), HPTXFSIZ,
+                PTXFD: p_tx_fifo_size as u32,
+                PTXSA: (rx_fifo_size + np_tx_fifo_size) as u32
+            );
+
+            // Flush Rx & Tx FIFOs
+            modify_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH: 1, TXFFLSH: 1, TXFNUM: 0x10);
+            while read_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH, TXFFLSH) != (0, 0) {}
+
+            // Clear and mask all channel interrupts
+            write_reg!(otg_host, regs.host(), HAINTMSK, 0);
+            for i in 0..USB::HOST_CHANNEL_COUNT {
+                let ch = regs.host_channel(i);
+                write_reg!(crate::ral::host_channel, ch, HCINTMSK, 0);
+                write_reg!(crate::ral::host_channel, ch, HCINT, 0xffffffff);
+            }
+
+            // unmask core interrupts
+            write_reg!(otg_global, regs.global(), GINTMSK,
+                PRTIM: 1, DISCINT: 1,
+                HCIM: 1, RXFLVLM: 1
+            );
+            if self.otg.srp || self.otg.hnp {
+                modify_reg!(otg_global, regs.global(), GINTMSK, OTGINT: 1, SRQIM: 1);
+            }
+
+            // clear pending interrupts
+            write_reg!(otg_global, regs.global(), GINTSTS, 0xffffffff);
+
+            // unmask global interrupt
+            modify_reg!(otg_global, regs.global(), GAHBCFG, GINT: 1);
+
+            // Power the port
+            modify_hprt(*regs, |r| r | otg_host::HPRT::PPWR::mask);
+        });
+
+        if supply_vbus {
+            self.peripheral.drive_vbus(true);
+        }
+    }
+
+    /// Releases all pipes and removes power from the port.
+    pub fn disable(&mut self) {
+        self.stop(true);
+    }
+
+    /// Stops host operation after the core became a peripheral through HNP.
+    ///
+    /// As the A-device, the core keeps supplying VBUS to the session.
+    pub(crate) fn disable_negotiated(&mut self) {
+        self.stop(false);
+    }
+
+    fn stop(&mut self, remove_vbus: bool) {
+        for index in 0..USB::HOST_CHANNEL_COUNT {
+            self.allocator.free(Pipe(index as u8));
+        }
+
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+
+            // mask core interrupts
+            write_reg!(otg_global, regs.global(), GINTMSK, 0);
+
+            if remove_vbus {
+                modify_hprt(*regs, |r| r & !otg_host::HPRT::PPWR::mask);
+            }
+        });
+
+        if remove_vbus {
+            self.peripheral.drive_vbus(false);
+        }
+    }
+
+    /// Returns true if a device is attached to the port.
+    pub fn is_connected(&self) -> bool {
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+            read_reg!(otg_host, regs.host(), HPRT, PCSTS) != 0
+        })
+    }
+
+    /// Returns the speed of the attached device if the port is enabled.
+    pub fn device_speed(&self) -> Option<DeviceSpeed> {
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+            let (enabled, speed) = read_reg!(otg_host, regs.host(), HPRT, PENA, PSPD);
+            if enabled != 0 {
+                Some(decode_speed(speed))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Drives reset signalling on the port and returns the speed of the attached device.
+    ///
+    /// Interrupts are not disabled while waiting for the reset to complete.
+    pub fn reset_port(&self, delay: &mut impl DelayMs<u32>) -> Result<DeviceSpeed> {
+        if !self.is_connected() {
+            return Err(HostError::NotConnected);
+        }
+
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+            modify_hprt(*regs, |r| r | otg_host::HPRT::PRST::mask);
+        });
+        // TDRSTR: reset from a root port lasts at least 50ms
+        delay.delay_ms(50);
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+            modify_hprt(*regs, |r| r & !otg_host::HPRT::PRST::mask);
+        });
+        // TRSTRCY: reset recovery time
+        delay.delay_ms(10);
+
+        match self.device_speed() {
+            Some(speed) => {
+                critical_section::with(|cs| {
+                    self.configure_frame_interval(*self.regs.borrow(cs), speed)
+                });
+                Ok(speed)
+            }
+            None => Err(HostError::PortDisabled),
+        }
+    }
+
+    /// Reports port state changes. Returns one event per call.
+    pub fn poll(&self) -> HostEvent {
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+
+            let (port, disconnect) =
+                read_reg!(otg_global, regs.global(), GINTSTS, HPRTINT, DISCINT);
+
+            if disconnect != 0 {
+                write_reg!(otg_global, regs.global(), GINTSTS, DISCINT: 1);
+
+                return HostEvent::DeviceDisconnected;
+            }
+
+            // HPRTINT is read-only, it is cleared by clearing the HPRT flags
+            if port != 0 {
+                let hprt = read_reg!(otg_host, regs.host(), HPRT);
+
+                if hprt & otg_host::HPRT::PCDET::mask != 0 {
+                    modify_hprt(*regs, |r| r | otg_host::HPRT::PCDET::mask);
+
+                    if hprt & otg_host::HPRT::PCSTS::mask != 0 {
+                        return HostEvent::DeviceConnected;
+                    }
+                } else if hprt & otg_host::HPRT::PENCHNG::mask != 0 {
+                    modify_hprt(*regs, |r| r | otg_host::HPRT::PENCHNG::mask);
+
+                    if hprt & otg_host::HPRT::PENA::mask != 0 {
+                        let speed = decode_speed(
+                            (hprt & otg_host::HPRT::PSPD::mask) >> otg_host::HPRT::PSPD::offset,
+                        );
+                        self.configure_frame_interval(*regs, speed);
+                        return HostEvent::PortEnabled(speed);
+                    } else {
+                        return HostEvent::PortDisabled;
+                    }
+                } else if hprt & otg_host::HPRT::POCCHNG::mask != 0 {
+                    modify_hprt(*regs, |r| r | otg_host::HPRT::POCCHNG::mask);
+
+                    return HostEvent::OverCurrent;
+                }
+            }
+
+            HostEvent::None
+        })
+    }
+
+    /// Allocates a host channel for the given endpoint.
+    pub fn alloc_pipe(&mut self, config: PipeConfig) -> Result<Pipe> {
+        self.allocator.alloc(config)
+    }
+
+    /// Releases a host channel, halting it if a transfer is in progress.
+    pub fn free_pipe(&mut self, pipe: Pipe) {
+        self.allocator.free(pipe)
+    }
+
+    /// Changes the device address and maximum packet size of an allocated pipe.
+    ///
+    /// This is used during enumeration, when the address and EP0 packet size of a device change.
+    pub fn reconfigure_pipe(
+        &mut self,
+        pipe: Pipe,
+        device_address: u8,
+        max_packet_size: u16,
+    ) -> Result<()> {
+        let channel = self.allocator.channel_mut(pipe)?;
+        channel.set_device_address(device_address);
+        channel.set_max_packet_size(max_packet_size);
+        channel.reset_data_toggle();
+        Ok(())
+    }
+
+    /// Resets the data toggle of a pipe to DATA0, e.g. after CLEAR_FEATURE(ENDPOINT_HALT).
+    pub fn reset_data_toggle(&self, pipe: Pipe) -> Result<()> {
+        self.allocator.channel(pipe)?.reset_data_toggle();
+        Ok(())
+    }
+
+    /// Performs a control transfer on a control pipe with an IN data stage.
+    ///
+    /// Returns the number of bytes received in the data stage.
+    pub fn control_in(&self, pipe: Pipe, setup: &[u8; 8], buf: &mut [u8]) -> Result<usize> {
+        let channel = self.allocator.channel(pipe)?;
+        if channel.config().ep_type != EndpointType::Control {
+            return Err(HostError::InvalidPipe);
+        }
+        channel.control_in(setup, buf)
+    }
+
+    /// Performs a control transfer on a control pipe with an optional OUT data stage.
+    pub fn control_out(&self, pipe: Pipe, setup: &[u8; 8], data: &[u8]) -> Result<usize> {
+        let channel = self.allocator.channel(pipe)?;
+        if channel.config().ep_type != EndpointType::Control {
+            return Err(HostError::InvalidPipe);
+        }
+        channel.control_out(setup, data)
+    }
+
+    /// Receives data from a bulk or interrupt IN pipe.
+    ///
+    /// The transfer ends with a short packet or when `buf` is full.
+    pub fn read(&self, pipe: Pipe, buf: &mut [u8]) -> Result<usize> {
+        let channel = self.allocator.channel(pipe)?;
+        if channel.config().ep_type == EndpointType::Control || !channel.config().endpoint.is_in() {
+            return Err(HostError::InvalidPipe);
+        }
+        channel.read(buf)
+    }
+
+    /// Sends data to a bulk or interrupt OUT pipe.
+    pub fn write(&self, pipe: Pipe, data: &[u8]) -> Result<usize> {
+        let channel = self.allocator.channel(pipe)?;
+        if channel.config().ep_type == EndpointType::Control || !channel.config().endpoint.is_out()
+        {
+            return Err(HostError::InvalidPipe);
+        }
+        channel.write(data)
+    }
+
+    fn configure_frame_interval(&self, regs: UsbRegisters, speed: DeviceSpeed) {
+        // FS PHY runs at 48 MHz for full-speed devices and at 6 MHz for low-speed ones
+        if !USB::HIGH_SPEED || self.peripheral.phy_type() == PhyType::InternalFullSpeed {
+            match speed {
+                DeviceSpeed::Low => {
+                    modify_reg!(otg_host, regs.host(), HCFG, FSLSPCS: 2);
+                    write_reg!(otg_host, regs.host(), HFIR, FRIVL: 6000);
+                }
+                _ => {
+                    modify_reg!(otg_host, regs.host(), HCFG, FSLSPCS: 1);
+                    write_reg!(otg_host, regs.host(), HFIR, FRIVL: 48000);
+                }
+            }
+        }
+    }
+}
+
+pub(crate) struct ChannelAllocator<USB> {
+    bitmap: u16,
+    channels: [Option<Channel>; 16],
+    _marker: PhantomData<USB>,
+}
+
+impl<USB: UsbPeripheral> ChannelAllocator<USB> {
+    fn new() -> Self {
+        assert!(USB::HOST_CHANNEL_COUNT <= 16);
+        Self {
+            bitmap: 0,
+            // [None; 16] requires Copy
+            channels: [
+                None, None, None, None, None, None, None, None, None, None, None, None, None, None,
+                None, None,
+            ],
+            _marker: PhantomData,
+        }
+    }
+
+    fn alloc(&mut self, config: PipeConfig) -> Result<Pipe> {
+        for number in 0..USB::HOST_CHANNEL_COUNT {
+            if self.bitmap & (1 << number) == 0 {
+                self.bitmap |= 1 << number;
+                self.channels[number] = Some(Channel::new::<USB>(number as u8, config));
+                return Ok(Pipe(number as u8));
+            }
+        }
+        Err(HostError::ChannelOverflow)
+    }
+
+    fn free(&mut self, pipe: Pipe) {
+        if let Some(channel) = self.channels[pipe.channel()].take() {
+            channel.deconfigure();
+            self.bitmap &= !(1 << pipe.channel());
+        }
+    }
+
+    fn channel(&self, pipe: Pipe) -> Result<&Channel> {
+        self.channels[pipe.channel()]
+            .as_ref()
+            .ok_or(HostError::InvalidPipe)
+    }
+
+    fn channel_mut(&mut self, pipe: Pipe) -> Result<&mut Channel> {
+        self.channels[pipe.channel()]
+            .as_mut()
+            .ok_or(HostError::InvalidPipe)
+    }
+}
+
+/// Modifies HPRT without accidentally clearing its status flags or disabling the port.
+fn modify_hprt(regs: UsbRegisters, f: impl FnOnce(u32) -> u32) {
+    let value = read_reg!(otg_host, regs.host(), HPRT) & !HPRT_W1C_MASK;
+    write_reg!(otg_host, regs.host(), HPRT, f(value));
+}
+
+fn decode_speed(pspd: u32) -> DeviceSpeed {
+    match pspd {
+        0b00 => DeviceSpeed::High,
+        0b01 => DeviceSpeed::Full,
+        _ => DeviceSpeed::Low,
+    }
+}
@@ -0,0 +1,370 @@
+use crate::host::{DeviceSpeed, HostError, PipeConfig};
+use crate::ral::{host_channel, modify_reg, otg_global, otg_host, read_reg, write_reg};
+use crate::target::{fifo_read, fifo_write, UsbRegisters};
+use crate::UsbPeripheral;
+use core::cell::Cell;
+use usb_device::endpoint::EndpointType;
+use usb_device::UsbDirection;
+
+type Result<T> = core::result::Result<T, HostError>;
+
+/// Number of NAK responses after which a control or bulk transaction is abandoned.
+const NAK_RETRY_LIMIT: u32 = 1000;
+
+/// Number of (micro)frames to wait for a transaction to finish before giving up.
+const TRANSACTION_TIMEOUT_FRAMES: u32 = 64;
+
+/// Number of register polls after which a wait is abandoned. HFNUM stops counting when the port
+/// is disabled, this bounds the waits regardless of the frame timeout.
+const TRANSACTION_TIMEOUT_POLLS: u32 = 1_000_000;
+
+/// Packet ID used for the data stage of a transaction (HCTSIZ.DPID encoding).
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum Pid {
+    Data0 = 0b00,
+    Data1 = 0b10,
+    Setup = 0b11,
+}
+
+/// Arbitrates access to the channel-specific registers and tracks the data toggle of a pipe.
+pub struct Channel {
+    index: u8,
+    config: PipeConfig,
+    usb: UsbRegisters,
+    #[cfg(feature = "hs")]
+    high_speed: bool,
+    data_toggle: Cell<bool>,
+}
+
+impl Channel {
+    pub fn new<USB: UsbPeripheral>(index: u8, config: PipeConfig) -> Channel {
+        Channel {
+            index,
+            config,
+            usb: UsbRegisters::new::<USB>(),
+            #[cfg(feature = "hs")]
+            high_speed: USB::HIGH_SPEED,
+            data_toggle: Cell::new(false),
+        }
+    }
+
+    pub fn config(&self) -> &PipeConfig {
+        &self.config
+    }
+
+    pub fn set_device_address(&mut self, address: u8) {
+        self.config.device_address = address;
+    }
+
+    pub fn set_max_packet_size(&mut self, max_packet_size: u16) {
+        self.config.max_packet_size = max_packet_size;
+    }
+
+    pub fn reset_data_toggle(&self) {
+        self.data_toggle.set(false);
+    }
+
+    pub fn deconfigure(&self) {
+        let regs = self.usb.host_channel(self.index as usize);
+        if read_reg!(host_channel, regs, HCCHAR, CHENA) != 0 {
+            self.halt();
+        }
+        write_reg!(host_channel, regs, HCINTMSK, 0);
+        write_reg!(host_channel, regs, HCINT, 0x7ff);
+    }
+
+    /// Performs a control transfer with an optional IN data stage.
+    pub fn control_in(&self, setup: &[u8; 8], buf: &mut [u8]) -> Result<usize> {
+        self.transaction_out(Pid::Setup, setup)?;
+
+        self.data_toggle.set(true);
+        let received = self.read_packets(buf)?;
+
+        // Status stage
+        self.transaction_out(Pid::Data1, &[])?;
+
+        Ok(received)
+    }
+
+    /// Performs a control transfer with an optional OUT data stage.
+    pub fn control_out(&self, setup: &[u8; 8], data: &[u8]) -> Result<usize> {
+        self.transaction_out(Pid::Setup, setup)?;
+
+        self.data_toggle.set(true);
+        if !data.is_empty() {
+            self.write_packets(data)?;
+        }
+
+        // Status stage
+        let mut status = [0u8; 0];
+        self.transaction_in(Pid::Data1, &mut status)?;
+
+        Ok(data.len())
+    }
+
+    /// Receives data from a bulk or interrupt IN endpoint.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize> {
+        self.read_packets(buf)
+    }
+
+    /// Sends data to a bulk or interrupt OUT endpoint.
+    pub fn write(&self, data: &[u8]) -> Result<usize> {
+        self.write_packets(data)
+    }
+
+    fn next_pid(&self) -> Pid {
+        if self.data_toggle.get() {
+            Pid::Data1
+        } else {
+            Pid::Data0
+        }
+    }
+
+    fn read_packets(&self, buf: &mut [u8]) -> Result<usize> {
+        let max_packet_size = self.config.max_packet_size as usize;
+        let mut received = 0;
+        loop {
+            let packet = &mut buf[received..];
+            let packet_len = packet.len().min(max_packet_size);
+            let count = self.transaction_in(self.next_pid(), &mut packet[..packet_len])?;
+            self.data_toggle.set(!self.data_toggle.get());
+            received += count;
+
+            // A short packet terminates the transfer
+            if count < max_packet_size || received == buf.len() {
+                return Ok(received);
+            }
+        }
+    }
+
+    fn write_packets(&self, data: &[u8]) -> Result<usize> {
+        let max_packet_size = self.config.max_packet_size as usize;
+        for packet in data.chunks(max_packet_size) {
+            self.transaction_out(self.next_pid(), packet)?;
+            self.data_toggle.set(!self.data_toggle.get());
+        }
+        Ok(data.len())
+    }
+
+    /// Runs a single OUT (or SETUP) transaction, retrying it if the device NAKs.
+    fn transaction_out(&self, pid: Pid, data: &[u8]) -> Result<()> {
+        let mut retries = 0;
+        loop {
+            self.start(UsbDirection::Out, pid, data.len());
+
+            if !data.is_empty() {
+                self.wait_tx_fifo_space(data.len())?;
+                fifo_write(self.usb, self.index, data);
+            }
+
+            match self.wait_complete(&mut []) {
+                Ok(_) => return Ok(()),
+                Err(HostError::Nak) if self.retries_nak(retries) => retries += 1,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Runs a single IN transaction, retrying it if the device NAKs.
+    fn transaction_in(&self, pid: Pid, buf: &mut [u8]) -> Result<usize> {
+        let mut retries = 0;
+        loop {
+            self.start(UsbDirection::In, pid, buf.len());
+
+            match self.wait_complete(buf) {
+                Ok(count) => return Ok(count),
+                Err(HostError::Nak) if self.retries_nak(retries) => retries += 1,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Interrupt endpoints report NAK to the caller, control and bulk endpoints are retried.
+    fn retries_nak(&self, retries: u32) -> bool {
+        match self.config.ep_type {
+            EndpointType::Control | EndpointType::Bulk => retries < NAK_RETRY_LIMIT,
+            _ => false,
+        }
+    }
+
+    fn start(&self, direction: UsbDirection, pid: Pid, size: usize) {
+        let regs = self.usb.host_channel(self.index as usize);
+
+        write_reg!(host_channel, regs, HCINT, 0x7ff);
+
+        #[cfg(feature = "hs")]
+        if self.high_speed {
+            write_reg!(host_channel, regs, HCSPLT, 0);
+        }
+
+        write_reg!(host_channel, regs, HCTSIZ,
+            DPID: pid as u32,
+            PKTCNT: 1,
+            XFRSIZ: size as u32
+        );
+
+        let is_periodic = matches!(
+            self.config.ep_type,
+            EndpointType::Interrupt | EndpointType::Isochronous { .. }
+        );
+        let odd_frame = is_periodic && read_reg!(otg_host, self.usb.host(), HFNUM, FRNUM) & 1 == 0;
+
+        #[cfg(not(feature = "hs"))]
+        write_reg!(host_channel, regs, HCCHAR,
+            DAD: self.config.device_address as u32,
+            MCNT: 1,
+            EPTYP: self.config.ep_type.to_bm_attributes() as u32,
+            LSDEV: (self.config.speed == DeviceSpeed::Low) as u32,
+            EPDIR: (direction == UsbDirection::In) as u32,
+            EPNUM: self.config.endpoint.index() as u32,
+            MPSIZ: self.config.max_packet_size as u32,
+            ODDFRM: odd_frame as u32,
+            CHENA: 1
+        );
+        #[cfg(feature = "hs")]
+        write_reg!(host_channel, regs, HCCHAR,
+            DAD: self.config.device_address as u32,
+            MC: 1,
+            EPTYP: self.config.ep_type.to_bm_attributes() as u32,
+            LSDEV: (self.config.speed == DeviceSpeed::Low) as u32,
+            EPDIR: (direction == UsbDirection::In) as u32,
+            EPNUM: self.config.endpoint.index() as u32,
+            MPSIZ: self.config.max_packet_size as u32,
+            ODDFRM: odd_frame as u32,
+            CHENA: 1
+        );
+    }
+
+    fn wait_tx_fifo_space(&self, size: usize) -> Result<()> {
+        let size_words = size.div_ceil(4);
+        let start = self.frame_number();
+        let mut polls = 0;
+        loop {
+            let available = match self.config.ep_type {
+                EndpointType::Control | EndpointType::Bulk => {
+                    read_reg!(otg_global, self.usb.global(), GNPTXSTS, NPTXFSAV)
+                }
+                _ => read_reg!(otg_host, self.usb.host(), HPTXSTS, PTXFSAVL),
+            };
+            if available as usize >= size_words {
+                return Ok(());
+            }
+            if self.port_lost() {
+                self.halt();
+                return Err(HostError::PortDisabled);
+            }
+            if self.timed_out(start, &mut polls) {
+                self.halt();
+                return Err(HostError::Timeout);
+            }
+        }
+    }
+
+    /// Waits for the transaction outcome, draining received IN data into `buf`.
+    fn wait_complete(&self, buf: &mut [u8]) -> Result<usize> {
+        let regs = self.usb.host_channel(self.index as usize);
+        let start = self.frame_number();
+        let mut polls = 0;
+        let mut received = 0;
+        let mut overflow = false;
+
+        loop {
+            if read_reg!(otg_global, self.usb.global(), GINTSTS, RXFLVL) != 0 {
+                let (channel, data_size, status) =
+                    read_reg!(otg_global, self.usb.global(), GRXSTSP, CHNUM, BCNT, PKTSTS);
+                let data_size = data_size as usize;
+                // IN data packet received
+                let ours = status == 0b0010 && channel == self.index as u32;
+                if ours && received + data_size <= buf.len() {
+                    fifo_read(self.usb, &mut buf[received..received + data_size]);
+                    received += data_size;
+                } else if data_size > 0 {
+                    // Entries of other channels have no reader, drop their data so the
+                    // next status entry is read from the right position
+                    overflow |= ours;
+                    self.discard_fifo_words(data_size);
+                }
+            }
+
+            let hcint = read_reg!(host_channel, regs, HCINT);
+
+            let result = if hcint & host_channel::HCINT::XFRC::mask != 0 {
+                if overflow {
+                    Err(HostError::BufferOverflow)
+                } else {
+                    Ok(received)
+                }
+            } else if hcint & host_channel::HCINT::STALL::mask != 0 {
+                Err(HostError::Stall)
+            } else if hcint & host_channel::HCINT::NAK::mask != 0 {
+                Err(HostError::Nak)
+            } else if hcint & host_channel::HCINT::TXERR::mask != 0 {
+                Err(HostError::TransactionError)
+            } else if hcint & host_channel::HCINT::BBERR::mask != 0 {
+                Err(HostError::Babble)
+            } else if hcint & host_channel::HCINT::DTERR::mask != 0 {
+                Err(HostError::DataToggle)
+            } else if hcint & host_channel::HCINT::FRMOR::mask != 0 {
+                Err(HostError::FrameOverrun)
+            } else if hcint & host_channel::HCINT::CHH::mask != 0 || self.port_lost() {
+                // Halted without an outcome, the port was disabled or the device unplugged
+                Err(HostError::PortDisabled)
+            } else if self.timed_out(start, &mut polls) {
+                Err(HostError::Timeout)
+            } else {
+                continue;
+            };
+
+            self.halt();
+            write_reg!(host_channel, regs, HCINT, 0x7ff);
+            return result;
+        }
+    }
+
+    fn discard_fifo_words(&self, data_size: usize) {
+        let fifo = self.usb.fifo(0);
+        for _ in 0..data_size.div_ceil(4) {
+            fifo.read();
+        }
+    }
+
+    /// Disables the channel and waits for it to halt.
+    fn halt(&self) {
+        let regs = self.usb.host_channel(self.index as usize);
+        if read_reg!(host_channel, regs, HCCHAR, CHENA) == 0 {
+            return;
+        }
+
+        modify_reg!(host_channel, regs, HCCHAR, CHDIS: 1, CHENA: 1);
+
+        let start = self.frame_number();
+        let mut polls = 0;
+        while read_reg!(host_channel, regs, HCINT, CHH) == 0 {
+            if self.port_lost() || self.timed_out(start, &mut polls) {
+                break;
+            }
+        }
+        write_reg!(host_channel, regs, HCINT, CHH: 1);
+    }
+
+    fn frame_number(&self) -> u32 {
+        read_reg!(otg_host, self.usb.host(), HFNUM, FRNUM)
+    }
+
+    fn frames_since(&self, start: u32) -> u32 {
+        self.frame_number().wrapping_sub(start) & 0x3fff
+    }
+
+    /// Counts a poll of a wait started in frame `start`, returns `true` once it took too long.
+    fn timed_out(&self, start: u32, polls: &mut u32) -> bool {
+        *polls += 1;
+        *polls > TRANSACTION_TIMEOUT_POLLS || self.frames_since(start) > TRANSACTION_TIMEOUT_FRAMES
+    }
+
+    /// Returns `true` if the port was disabled or the device disconnected.
+    fn port_lost(&self) -> bool {
+        let enabled = read_reg!(otg_host, self.usb.host(), HPRT, PENA);
+        let disconnected = read_reg!(otg_global, self.usb.global(), GINTSTS, DISCINT);
+        enabled == 0 || disconnected != 0
+    }
+}
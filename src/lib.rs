@@ -1,9 +1,6 @@
 //! USB peripheral driver for Synopsys USB OTG peripherals.
 
-#![no_std]
-
-#[cfg(all(feature = "fs", feature = "hs"))]
-compile_error!("choose only one USB mode");
+#![cfg_attr(not(feature = "__sim"), no_std)]
 
 #[cfg(not(any(feature = "fs", feature = "hs")))]
 compile_error!("select USB mode feature (fs/hs)");
@@ -16,7 +13,27 @@ mod target;
 /// USB peripheral driver.
 pub mod bus;
 
-pub use crate::bus::UsbBus;
+pub use crate::bus::{UsbBus, UsbBusConfig};
+
+/// USB host driver.
+pub mod host;
+mod host_channel;
+
+pub use crate::host::UsbHost;
+
+/// Host-side device enumeration.
+pub mod enumeration;
+
+/// Dual-role (OTG) operation.
+pub mod otg;
+
+/// Async driver for `embassy-usb`.
+#[cfg(feature = "embassy")]
+pub mod embassy;
+
+/// Simulated core for host-side tests.
+#[cfg(feature = "__sim")]
+pub mod sim;
 
 mod ral;
 mod transition;
@@ -37,6 +54,9 @@ pub unsafe trait UsbPeripheral: Send + Sync {
     /// Number of (bidirectional) endpoints
     const ENDPOINT_COUNT: usize;
 
+    /// Number of host channels
+    const HOST_CHANNEL_COUNT: usize = if Self::HIGH_SPEED { 12 } else { 8 };
+
     /// Enables USB device on its peripheral bus
     fn enable();
 
@@ -48,10 +68,82 @@ pub unsafe trait UsbPeripheral: Send + Sync {
         PhyType::InternalFullSpeed
     }
 
+    /// Returns how the presence of VBUS is detected in device mode
+    fn vbus_sensing(&self) -> VbusSensing {
+        VbusSensing::Disabled
+    }
+
+    /// Returns `true` if VBUS is present, used with [`VbusSensing::Gpio`]
+    fn vbus_present(&self) -> bool {
+        true
+    }
+
     /// Performs initial setup of the internal high-speed PHY
     ///
     /// This function should turn on LDO and PLL and wait for PHY clock to become stable.
     fn setup_internal_hs_phy(&self) {}
+
+    /// Switches the VBUS supply of the port in host mode
+    ///
+    /// Implement this if VBUS is powered through an external switch or charge pump.
+    fn drive_vbus(&self, _enabled: bool) {}
+
+    /// Called after the core's clocks have been gated because the bus is suspended
+    ///
+    /// Implement this to lower the system clocks or prepare a low-power mode, so that a
+    /// bus-powered device stays within the suspend current budget. The USB wakeup interrupt has
+    /// to remain enabled. Called from a critical section.
+    fn enter_suspend(&self) {}
+
+    /// Called on bus activity after a suspend, before the core's clocks are restored
+    ///
+    /// Implement this to undo [`enter_suspend`](Self::enter_suspend). The core needs the AHB
+    /// clock from [`ahb_frequency_hz`](Self::ahb_frequency_hz) when this returns.
+    fn exit_suspend(&self) {}
+}
+
+unsafe impl<USB: UsbPeripheral> UsbPeripheral for &USB {
+    const REGISTERS: *const () = USB::REGISTERS;
+    const HIGH_SPEED: bool = USB::HIGH_SPEED;
+    const FIFO_DEPTH_WORDS: usize = USB::FIFO_DEPTH_WORDS;
+    const ENDPOINT_COUNT: usize = USB::ENDPOINT_COUNT;
+    const HOST_CHANNEL_COUNT: usize = USB::HOST_CHANNEL_COUNT;
+
+    fn enable() {
+        USB::enable()
+    }
+
+    fn ahb_frequency_hz(&self) -> u32 {
+        (**self).ahb_frequency_hz()
+    }
+
+    fn phy_type(&self) -> PhyType {
+        (**self).phy_type()
+    }
+
+    fn vbus_sensing(&self) -> VbusSensing {
+        (**self).vbus_sensing()
+    }
+
+    fn vbus_present(&self) -> bool {
+        (**self).vbus_present()
+    }
+
+    fn setup_internal_hs_phy(&self) {
+        (**self).setup_internal_hs_phy()
+    }
+
+    fn drive_vbus(&self, enabled: bool) {
+        (**self).drive_vbus(enabled)
+    }
+
+    fn enter_suspend(&self) {
+        (**self).enter_suspend()
+    }
+
+    fn exit_suspend(&self) {
+        (**self).exit_suspend()
+    }
 }
 
 /// USB PHY type
@@ -68,3 +160,22 @@ pub enum PhyType {
     /// External ULPI High-Speed PHY
     ExternalHighSpeed,
 }
+
+/// VBUS sensing method in device mode
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum VbusSensing {
+    /// VBUS is not sensed, the device is always connected
+    ///
+    /// Suitable for bus-powered devices.
+    Disabled,
+    /// VBUS is sensed by the core's internal comparator on the VBUS pin
+    ///
+    /// D+ is only pulled up while VBUS is present.
+    Internal,
+    /// VBUS is sensed by the HAL, e.g. on a GPIO, and reported by
+    /// [`UsbPeripheral::vbus_present`]
+    ///
+    /// D+ is only pulled up while VBUS is present. Call `UsbBus::vbus_changed` when the state
+    /// changes.
+    Gpio,
+}
@@ -0,0 +1,370 @@
+use crate::bus::UsbBus;
+use crate::host::UsbHost;
+use crate::ral::{modify_reg, otg_global, read_reg, write_reg};
+use crate::target::UsbRegisters;
+use crate::UsbPeripheral;
+use core::marker::PhantomData;
+use critical_section::{CriticalSection, Mutex};
+
+/// OTG protocols the core takes part in.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct OtgCapabilities {
+    /// Session Request Protocol (GUSBCFG.SRPCAP)
+    pub srp: bool,
+    /// Host Negotiation Protocol (GUSBCFG.HNPCAP)
+    pub hnp: bool,
+}
+
+/// OTG protocol events reported by [`Negotiation::poll`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum OtgEvent {
+    /// The A-device detected a session request from the B-device (SRQINT).
+    SessionRequestDetected,
+    /// VBUS dropped below the B-device session valid level (GOTGINT.SEDET).
+    SessionEnd,
+    /// A session request initiated with [`Negotiation::request_session`] finished
+    /// (GOTGINT.SRSSCHG).
+    SessionRequestDone {
+        /// The A-device turned VBUS on.
+        success: bool,
+    },
+    /// A host negotiation initiated with [`Negotiation::request_host_role`] finished
+    /// (GOTGINT.HNSSCHG).
+    HostNegotiationDone {
+        /// The roles have been swapped.
+        success: bool,
+    },
+    /// The B-device requested the host role (GOTGINT.HNGDET).
+    HostNegotiationDetected,
+    /// The A-device timed out waiting for the B-device to connect (GOTGINT.ADTOCHG).
+    ADeviceTimeout,
+    /// Debounce of a device connection finished, the port may be reset (GOTGINT.DBCDNE).
+    DebounceDone,
+}
+
+/// GINTSTS.SRQINT, kept in a bit that is reserved in GOTGINT
+pub(crate) const SESSION_REQUEST: u32 = 1 << 31;
+
+/// Acknowledges the pending OTG interrupts.
+///
+/// Returns the GOTGINT flags, with [`SESSION_REQUEST`] set for a pending session request.
+pub(crate) fn take_interrupts(regs: UsbRegisters) -> u32 {
+    let mut pending = read_reg!(otg_global, regs.global(), GOTGINT);
+    write_reg!(otg_global, regs.global(), GOTGINT, pending);
+
+    if read_reg!(otg_global, regs.global(), GINTSTS, SRQINT) != 0 {
+        write_reg!(otg_global, regs.global(), GINTSTS, SRQINT: 1);
+        pending |= SESSION_REQUEST;
+    }
+    pending
+}
+
+pub(crate) mod sealed {
+    use critical_section::CriticalSection;
+
+    pub trait OtgLatch {
+        /// Takes the OTG interrupts acknowledged but not reported yet.
+        fn take_otg_interrupts(&self, cs: CriticalSection<'_>) -> u32;
+
+        /// Keeps OTG interrupts for the next `Negotiation::poll`.
+        fn keep_otg_interrupts(&self, cs: CriticalSection<'_>, pending: u32);
+    }
+}
+
+/// Driver of a core that keeps its OTG interrupts between the calls of [`Negotiation::poll`].
+///
+/// Implemented by [`UsbBus`], [`UsbHost`] and `embassy::State` of the same peripheral.
+pub trait OtgDriver<USB>: sealed::OtgLatch {}
+
+impl<USB: UsbPeripheral> sealed::OtgLatch for UsbBus<USB> {
+    fn take_otg_interrupts(&self, cs: CriticalSection<'_>) -> u32 {
+        self.otg_interrupts(cs).take()
+    }
+
+    fn keep_otg_interrupts(&self, cs: CriticalSection<'_>, pending: u32) {
+        self.otg_interrupts(cs).set(pending);
+    }
+}
+
+impl<USB: UsbPeripheral> OtgDriver<USB> for UsbBus<USB> {}
+
+impl<USB: UsbPeripheral> sealed::OtgLatch for UsbHost<USB> {
+    fn take_otg_interrupts(&self, cs: CriticalSection<'_>) -> u32 {
+        self.otg_interrupts(cs).take()
+    }
+
+    fn keep_otg_interrupts(&self, cs: CriticalSection<'_>, pending: u32) {
+        self.otg_interrupts(cs).set(pending);
+    }
+}
+
+impl<USB: UsbPeripheral> OtgDriver<USB> for UsbHost<USB> {}
+
+/// Session Request Protocol and Host Negotiation Protocol control.
+///
+/// The protocols have to be enabled first with `UsbBus::set_otg_capabilities` or
+/// `UsbHost::set_otg_capabilities`.
+pub struct Negotiation<USB> {
+    regs: Mutex<UsbRegisters>,
+    _marker: PhantomData<USB>,
+}
+
+impl<USB: UsbPeripheral> Negotiation<USB> {
+    /// Constructs a new protocol controller for the core of `USB`.
+    ///
+    /// It only accesses the OTG control registers, so it can be used next to the device or host
+    /// driver of the same core.
+    pub fn new() -> Self {
+        Self {
+            regs: Mutex::new(UsbRegisters::new::<USB>()),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Asks the A-device to turn VBUS on (B-device, SRP).
+    ///
+    /// The outcome is reported as [`OtgEvent::SessionRequestDone`].
+    pub fn request_session(&self) {
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+            modify_reg!(otg_global, regs.global(), GOTGCTL, SRQ: 1);
+        });
+    }
+
+    /// Allows the device to request the host role (B-device, HNP).
+    ///
+    /// Call this when the host has sent SET_FEATURE(b_hnp_enable). `usb-device` doesn't handle
+    /// this request itself, so it has to be accepted by a class.
+    pub fn enable_device_hnp(&self) {
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+            modify_reg!(otg_global, regs.global(), GOTGCTL, DHNPEN: 1);
+        });
+    }
+
+    /// Requests the host role once the A-device suspends the bus (B-device, HNP).
+    ///
+    /// The outcome is reported as [`OtgEvent::HostNegotiationDone`].
+    pub fn request_host_role(&self) {
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+            modify_reg!(otg_global, regs.global(), GOTGCTL, HNPRQ: 1);
+        });
+    }
+
+    /// Allows the attached B-device to take over the host role (A-device, HNP).
+    ///
+    /// Call this after SET_FEATURE(b_hnp_enable) has been sent to the device, then suspend the
+    /// bus. The swap is reported as [`OtgEvent::HostNegotiationDetected`].
+    pub fn enable_host_hnp(&self) {
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+            modify_reg!(otg_global, regs.global(), GOTGCTL, HSHNPEN: 1);
+        });
+    }
+
+    /// Reports OTG protocol events. Returns one event per call.
+    ///
+    /// `driver` is the driver currently running the core: the [`UsbBus`], the [`UsbHost`] or
+    /// the `embassy::State`. In device mode the interrupts are acknowledged by
+    /// [`UsbBus::on_interrupt`], which keeps them for this method. Interrupts raised in host mode
+    /// are taken from the core directly.
+    pub fn poll(&self, driver: &impl OtgDriver<USB>) -> Option<OtgEvent> {
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+
+            let pending = driver.take_otg_interrupts(cs) | take_interrupts(*regs);
+
+            let (srp_success, hnp_success) =
+                read_reg!(otg_global, regs.global(), GOTGCTL, SRQSCS, HNGSCS);
+
+            let (flag, event) = if pending & SESSION_REQUEST != 0 {
+                (SESSION_REQUEST, OtgEvent::SessionRequestDetected)
+            } else if pending & otg_global::GOTGINT::SEDET::mask != 0 {
+                (otg_global::GOTGINT::SEDET::mask, OtgEvent::SessionEnd)
+            } else if pending & otg_global::GOTGINT::SRSSCHG::mask != 0 {
+                modify_reg!(otg_global, regs.global(), GOTGCTL, SRQ: 0);
+                (
+                    otg_global::GOTGINT::SRSSCHG::mask,
+                    OtgEvent::SessionRequestDone {
+                        success: srp_success != 0,
+                    },
+                )
+            } else if pending & otg_global::GOTGINT::HNSSCHG::mask != 0 {
+                modify_reg!(otg_global, regs.global(), GOTGCTL, HNPRQ: 0);
+                (
+                    otg_global::GOTGINT::HNSSCHG::mask,
+                    OtgEvent::HostNegotiationDone {
+                        success: hnp_success != 0,
+                    },
+                )
+            } else if pending & otg_global::GOTGINT::HNGDET::mask != 0 {
+                (
+                    otg_global::GOTGINT::HNGDET::mask,
+                    OtgEvent::HostNegotiationDetected,
+                )
+            } else if pending & otg_global::GOTGINT::ADTOCHG::mask != 0 {
+                (otg_global::GOTGINT::ADTOCHG::mask, OtgEvent::ADeviceTimeout)
+            } else if pending & otg_global::GOTGINT::DBCDNE::mask != 0 {
+                (otg_global::GOTGINT::DBCDNE::mask, OtgEvent::DebounceDone)
+            } else {
+                return None;
+            };
+
+            // The remaining events are reported by the next calls
+            driver.keep_otg_interrupts(cs, pending & !flag);
+            Some(event)
+        })
+    }
+}
+
+impl<USB: UsbPeripheral> Default for Negotiation<USB> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Role of the core, selected by the connector ID pin.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Role {
+    /// B-device: ID pin floating, the core runs the `usb-device` stack.
+    Device,
+    /// A-device: ID pin grounded, the core runs the host driver and supplies VBUS.
+    Host,
+}
+
+/// Dual-role controller that switches between device and host mode when the cable is swapped.
+///
+/// Both drivers share the same core. The device side is the [`UsbBus`] owned by `usb-device`,
+/// the host side is owned by this controller. Since both need the peripheral, use a shared
+/// reference (`&USB` implements [`UsbPeripheral`]) for both of them.
+///
+/// The controller assumes that the bus has already been enabled by `usb-device`, so it starts
+/// in the device role.
+///
+/// Without HNP both drivers force the core into their mode (GUSBCFG.FDMOD/FHMOD) instead of
+/// letting it follow the ID pin. Every role switch reinitializes the core through the driver
+/// taking over, which rewrites the forced mode, so after a [`poll`](Self::poll) the forced mode
+/// always matches [`id_pin_role`](Self::id_pin_role). Call `poll` once right after
+/// construction to leave the forced device mode if the ID pin is grounded already.
+pub struct DualRole<USB> {
+    host: UsbHost<USB>,
+    role: Role,
+    regs: Mutex<UsbRegisters>,
+}
+
+impl<USB: UsbPeripheral> DualRole<USB> {
+    /// Constructs a new dual-role controller around a (disabled) host driver.
+    pub fn new(host: UsbHost<USB>) -> Self {
+        let controller = Self {
+            host,
+            role: Role::Device,
+            regs: Mutex::new(UsbRegisters::new::<USB>()),
+        };
+        controller.unmask_id_change();
+        controller
+    }
+
+    /// Releases the host driver.
+    ///
+    /// The core stays in its current role. In the host role, disable the returned driver and
+    /// attach the bus again to return to device operation.
+    pub fn free(self) -> UsbHost<USB> {
+        self.host
+    }
+
+    /// Returns the role the core is currently operating in.
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// Returns the role requested by the connector ID pin.
+    pub fn id_pin_role(&self) -> Role {
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+            if read_reg!(otg_global, regs.global(), GOTGCTL, CIDSTS) == 0 {
+                Role::Host
+            } else {
+                Role::Device
+            }
+        })
+    }
+
+    /// Returns the host driver if the core is operating in the host role.
+    pub fn host(&mut self) -> Option<&mut UsbHost<USB>> {
+        match self.role {
+            Role::Host => Some(&mut self.host),
+            Role::Device => None,
+        }
+    }
+
+    /// Checks the connector ID pin and switches roles if it has changed.
+    ///
+    /// When switching to the host role the device is detached from the bus and the host driver
+    /// is enabled. When switching back the host driver is disabled and the device is attached
+    /// again. If HNP is enabled, role swaps negotiated with the other side are followed as well.
+    /// Returns the new role if a switch happened.
+    pub fn poll(&mut self, bus: &UsbBus<USB>) -> Option<Role> {
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+            if read_reg!(otg_global, regs.global(), GINTSTS, CIDSCHG) != 0 {
+                write_reg!(otg_global, regs.global(), GINTSTS, CIDSCHG: 1);
+            }
+        });
+
+        let id_pin_role = self.id_pin_role();
+        let requested = self.core_role().unwrap_or(id_pin_role);
+        if requested == self.role {
+            return None;
+        }
+
+        // With HNP the roles are swapped while the session continues, the core must not be reset
+        let negotiated = requested != id_pin_role;
+        match (requested, negotiated) {
+            (Role::Host, false) => {
+                bus.detach();
+                self.host.enable();
+            }
+            (Role::Host, true) => {
+                bus.detach();
+                self.host.enable_negotiated();
+            }
+            (Role::Device, false) => {
+                self.host.disable();
+                bus.attach();
+            }
+            (Role::Device, true) => {
+                self.host.disable_negotiated();
+                bus.attach_negotiated();
+            }
+        }
+        self.role = requested;
+
+        // Both drivers rewrite GINTMSK during initialization
+        self.unmask_id_change();
+
+        Some(requested)
+    }
+
+    /// Returns the mode the core has chosen itself, or `None` if the mode is forced.
+    fn core_role(&self) -> Option<Role> {
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+            let (fdmod, fhmod) = read_reg!(otg_global, regs.global(), GUSBCFG, FDMOD, FHMOD);
+            if fdmod != 0 || fhmod != 0 {
+                None
+            } else if read_reg!(otg_global, regs.global(), GINTSTS, CMOD) != 0 {
+                Some(Role::Host)
+            } else {
+                Some(Role::Device)
+            }
+        })
+    }
+
+    fn unmask_id_change(&self) {
+        critical_section::with(|cs| {
+            let regs = self.regs.borrow(cs);
+            modify_reg!(otg_global, regs.global(), GINTMSK, CIDSCHGM: 1);
+        });
+    }
+}
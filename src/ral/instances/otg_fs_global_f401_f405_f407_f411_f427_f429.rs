@@ -8,8 +8,8 @@
 pub use super::super::peripherals::otg_fs_global_v1::Instance;
 pub use super::super::peripherals::otg_fs_global_v1::{RegisterBlock, ResetValues};
 pub use super::super::peripherals::otg_fs_global_v1::{
-    CID, DIEPTXF0, DIEPTXF1, DIEPTXF2, DIEPTXF3, GAHBCFG, GCCFG, GINTMSK, GINTSTS, GNPTXSTS,
-    GOTGCTL, GOTGINT, GRSTCTL, GRXFSIZ, GRXSTSP, GRXSTSR, GUSBCFG, HPTXFSIZ,
+    CID, DIEPTXF0, DIEPTXF1, DIEPTXF2, DIEPTXF3, GAHBCFG, GCCFG, GINTMSK, GINTSTS, GLPMCFG,
+    GNPTXSTS, GOTGCTL, GOTGINT, GRSTCTL, GRXFSIZ, GRXSTSP, GRXSTSR, GUSBCFG, HPTXFSIZ,
 };
 
 /// Access functions for the OTG_FS_GLOBAL peripheral instance
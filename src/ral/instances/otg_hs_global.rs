@@ -9,8 +9,8 @@ pub use super::super::peripherals::otg_hs_global::Instance;
 pub use super::super::peripherals::otg_hs_global::{RegisterBlock, ResetValues};
 pub use super::super::peripherals::otg_hs_global::{
     CID, DIEPTXF1, DIEPTXF2, DIEPTXF3, DIEPTXF4, DIEPTXF5, GAHBCFG, GCCFG, GINTMSK, GINTSTS,
-    GNPTXFSIZ, GNPTXSTS, GOTGCTL, GOTGINT, GRSTCTL, GRXFSIZ, GRXSTSP, GRXSTSR, GUSBCFG, HPTXFSIZ,
-    PHYCR,
+    GLPMCFG, GNPTXFSIZ, GNPTXSTS, GOTGCTL, GOTGINT, GRSTCTL, GRXFSIZ, GRXSTSP, GRXSTSR, GUSBCFG,
+    HPTXFSIZ, PHYCR,
 };
 
 /// Access functions for the OTG_HS_GLOBAL peripheral instance
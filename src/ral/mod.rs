@@ -4,13 +4,19 @@ pub mod instances;
 pub mod peripherals;
 pub mod stm32f429;
 
+#[cfg(feature = "__sim")]
+pub use crate::sim::register;
+#[cfg(not(feature = "__sim"))]
 pub use ral_registers as register;
 pub use ral_registers::{modify_reg, read_reg, write_reg};
 
+// With the `hs` feature the HS layout is used for every core, including the FS ones of a chip
+// driven side by side. It's a superset of the FS layout, the HS-only registers and fields are
+// only accessed if `UsbPeripheral::HIGH_SPEED` is set.
 pub mod otg_global {
-    #[cfg(feature = "fs")]
+    #[cfg(not(feature = "hs"))]
     pub use super::stm32f429::otg_fs_global::OTG_FS_GLOBAL as OTG_GLOBAL;
-    #[cfg(feature = "fs")]
+    #[cfg(not(feature = "hs"))]
     pub use super::stm32f429::otg_fs_global::*;
     #[cfg(feature = "hs")]
     pub use super::stm32f429::otg_hs_global::OTG_HS_GLOBAL as OTG_GLOBAL;
@@ -19,9 +25,9 @@ pub mod otg_global {
 }
 
 pub mod otg_device {
-    #[cfg(feature = "fs")]
+    #[cfg(not(feature = "hs"))]
     pub use super::stm32f429::otg_fs_device::OTG_FS_DEVICE as OTG_DEVICE;
-    #[cfg(feature = "fs")]
+    #[cfg(not(feature = "hs"))]
     pub use super::stm32f429::otg_fs_device::*;
     #[cfg(feature = "hs")]
     pub use super::stm32f429::otg_hs_device::OTG_HS_DEVICE as OTG_DEVICE;
@@ -30,7 +36,7 @@ pub mod otg_device {
 }
 
 pub mod otg_pwrclk {
-    #[cfg(feature = "fs")]
+    #[cfg(not(feature = "hs"))]
     pub use super::stm32f429::otg_s_pwrclk::OTG_FS_PWRCLK as OTG_PWRCLK;
     #[cfg(feature = "hs")]
     pub use super::stm32f429::otg_s_pwrclk::OTG_HS_PWRCLK as OTG_PWRCLK;
@@ -40,11 +46,12 @@ pub mod otg_pwrclk {
 pub mod otg_global_dieptxfx {
     use super::register::RWRegister;
 
-    #[cfg(feature = "fs")]
+    #[cfg(not(feature = "hs"))]
     pub use super::stm32f429::otg_fs_global::DIEPTXF1 as DIEPTXFx;
     #[cfg(feature = "hs")]
     pub use super::stm32f429::otg_fs_global::DIEPTXF1 as DIEPTXFx;
 
+    #[repr(C)]
     pub struct RegisterBlock {
         pub DIEPTXFx: RWRegister<u32>,
     }
@@ -53,7 +60,7 @@ pub mod otg_global_dieptxfx {
 pub mod endpoint_in {
     use super::register::RWRegister;
 
-    #[cfg(feature = "fs")]
+    #[cfg(not(feature = "hs"))]
     pub use super::stm32f429::otg_fs_device::{
         DIEPCTL1 as DIEPCTL, DIEPINT1 as DIEPINT, DIEPTSIZ1 as DIEPTSIZ, DTXFSTS1 as DTXFSTS,
     };
@@ -63,13 +70,15 @@ pub mod endpoint_in {
         DIEPCTL1 as DIEPCTL, DIEPINT1 as DIEPINT, DIEPTSIZ1 as DIEPTSIZ, DTXFSTS1 as DTXFSTS,
     };
 
+    #[repr(C)]
     pub struct RegisterBlock {
         pub DIEPCTL: RWRegister<u32>,
         _reserved0: u32,
         pub DIEPINT: RWRegister<u32>,
         _reserved1: u32,
         pub DIEPTSIZ: RWRegister<u32>,
-        _reserved2: u32,
+        /// DMA address register, HS cores only
+        pub DIEPDMA: RWRegister<u32>,
         pub DTXFSTS: RWRegister<u32>,
         _reserved3: u32,
     }
@@ -78,26 +87,29 @@ pub mod endpoint_in {
 pub mod endpoint0_out {
     use super::register::RWRegister;
 
-    #[cfg(feature = "fs")]
+    #[cfg(not(feature = "hs"))]
     pub use super::stm32f429::otg_fs_device::{DOEPCTL0, DOEPINT0, DOEPTSIZ0};
 
     #[cfg(feature = "hs")]
     pub use super::stm32f429::otg_hs_device::{DOEPCTL0, DOEPINT0, DOEPTSIZ0};
 
+    #[repr(C)]
     pub struct RegisterBlock {
         pub DOEPCTL0: RWRegister<u32>,
         _reserved0: u32,
         pub DOEPINT0: RWRegister<u32>,
         _reserved1: u32,
         pub DOEPTSIZ0: RWRegister<u32>,
-        _reserved2: [u32; 3],
+        /// DMA address register, HS cores only
+        pub DOEPDMA0: RWRegister<u32>,
+        _reserved2: [u32; 2],
     }
 }
 
 pub mod endpoint_out {
     use super::register::RWRegister;
 
-    #[cfg(feature = "fs")]
+    #[cfg(not(feature = "hs"))]
     pub use super::stm32f429::otg_fs_device::{
         DOEPCTL1 as DOEPCTL, DOEPINT1 as DOEPINT, DOEPTSIZ1 as DOEPTSIZ,
     };
@@ -107,12 +119,47 @@ pub mod endpoint_out {
         DOEPCTL1 as DOEPCTL, DOEPINT1 as DOEPINT, DOEPTSIZ1 as DOEPTSIZ,
     };
 
+    #[repr(C)]
     pub struct RegisterBlock {
         pub DOEPCTL: RWRegister<u32>,
         _reserved0: u32,
         pub DOEPINT: RWRegister<u32>,
         _reserved1: u32,
         pub DOEPTSIZ: RWRegister<u32>,
-        _reserved2: [u32; 3],
+        /// DMA address register, HS cores only
+        pub DOEPDMA: RWRegister<u32>,
+        _reserved2: [u32; 2],
+    }
+}
+
+pub mod otg_host {
+    #[cfg(not(feature = "hs"))]
+    pub use super::stm32f429::otg_fs_host::*;
+    #[cfg(feature = "hs")]
+    pub use super::stm32f429::otg_hs_host::*;
+}
+
+pub mod host_channel {
+    use super::register::RWRegister;
+
+    #[cfg(not(feature = "hs"))]
+    pub use super::stm32f429::otg_fs_host::{
+        HCCHAR0 as HCCHAR, HCINT0 as HCINT, HCTSIZ0 as HCTSIZ,
+    };
+
+    #[cfg(feature = "hs")]
+    pub use super::stm32f429::otg_hs_host::{
+        HCCHAR0 as HCCHAR, HCINT0 as HCINT, HCTSIZ0 as HCTSIZ,
+    };
+
+    #[repr(C)]
+    pub struct RegisterBlock {
+        pub HCCHAR: RWRegister<u32>,
+        pub HCSPLT: RWRegister<u32>,
+        pub HCINT: RWRegister<u32>,
+        pub HCINTMSK: RWRegister<u32>,
+        pub HCTSIZ: RWRegister<u32>,
+        pub HCDMA: RWRegister<u32>,
+        _reserved0: [u32; 2],
     }
 }
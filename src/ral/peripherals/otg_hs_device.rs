@@ -67,7 +67,6 @@ pub mod DCFG {
         pub mod RW {}
     }
 
-    #[cfg(feature = "xcvrdly")]
     /// Transceiver Delay
     pub mod XCVRDLY {
         /// Offset (14 bits)
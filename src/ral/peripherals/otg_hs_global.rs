@@ -997,6 +997,20 @@ pub mod GINTSTS {
         pub mod RW {}
     }
 
+    /// LPM interrupt
+    pub mod LPMINT {
+        /// Offset (27 bits)
+        pub const offset: u32 = 27;
+        /// Mask (1 bit: 1 << 27)
+        pub const mask: u32 = 1 << offset;
+        /// Read-only values (empty)
+        pub mod R {}
+        /// Write-only values (empty)
+        pub mod W {}
+        /// Read-write values (empty)
+        pub mod RW {}
+    }
+
     /// Connector ID status change
     pub mod CIDSCHG {
         /// Offset (28 bits)
@@ -1365,6 +1379,20 @@ pub mod GINTMSK {
         pub mod RW {}
     }
 
+    /// LPM interrupt mask
+    pub mod LPMINTM {
+        /// Offset (27 bits)
+        pub const offset: u32 = 27;
+        /// Mask (1 bit: 1 << 27)
+        pub const mask: u32 = 1 << offset;
+        /// Read-only values (empty)
+        pub mod R {}
+        /// Write-only values (empty)
+        pub mod W {}
+        /// Read-write values (empty)
+        pub mod RW {}
+    }
+
     /// Connector ID status change mask
     pub mod CIDSCHGM {
         /// Offset (28 bits)
@@ -1741,6 +1769,62 @@ pub mod PHYCR {
 /// OTG_HS general core configuration register
 pub mod GCCFG {
 
+    /// Data contact detection status (battery charging detector, newer cores)
+    pub mod DCDET {
+        /// Offset (0 bits)
+        pub const offset: u32 = 0;
+        /// Mask (1 bit: 1 << 0)
+        pub const mask: u32 = 1 << offset;
+        /// Read-only values (empty)
+        pub mod R {}
+        /// Write-only values (empty)
+        pub mod W {}
+        /// Read-write values (empty)
+        pub mod RW {}
+    }
+
+    /// Primary detection status (battery charging detector, newer cores)
+    pub mod PDET {
+        /// Offset (1 bits)
+        pub const offset: u32 = 1;
+        /// Mask (1 bit: 1 << 1)
+        pub const mask: u32 = 1 << offset;
+        /// Read-only values (empty)
+        pub mod R {}
+        /// Write-only values (empty)
+        pub mod W {}
+        /// Read-write values (empty)
+        pub mod RW {}
+    }
+
+    /// Secondary detection status (battery charging detector, newer cores)
+    pub mod SDET {
+        /// Offset (2 bits)
+        pub const offset: u32 = 2;
+        /// Mask (1 bit: 1 << 2)
+        pub const mask: u32 = 1 << offset;
+        /// Read-only values (empty)
+        pub mod R {}
+        /// Write-only values (empty)
+        pub mod W {}
+        /// Read-write values (empty)
+        pub mod RW {}
+    }
+
+    /// DM pull-up detection status (battery charging detector, newer cores)
+    pub mod PS2DET {
+        /// Offset (3 bits)
+        pub const offset: u32 = 3;
+        /// Mask (1 bit: 1 << 3)
+        pub const mask: u32 = 1 << offset;
+        /// Read-only values (empty)
+        pub mod R {}
+        /// Write-only values (empty)
+        pub mod W {}
+        /// Read-write values (empty)
+        pub mod RW {}
+    }
+
     /// Power down
     pub mod PWRDWN {
         /// Offset (16 bits)
@@ -1769,6 +1853,20 @@ pub mod GCCFG {
         pub mod RW {}
     }
 
+    /// Battery charging detector enable (newer cores)
+    pub mod BCDEN {
+        /// Offset (17 bits)
+        pub const offset: u32 = 17;
+        /// Mask (1 bit: 1 << 17)
+        pub const mask: u32 = 1 << offset;
+        /// Read-only values (empty)
+        pub mod R {}
+        /// Write-only values (empty)
+        pub mod W {}
+        /// Read-write values (empty)
+        pub mod RW {}
+    }
+
     /// Enable the VBUS sensing device
     pub mod VBUSASEN {
         /// Offset (18 bits)
@@ -1783,6 +1881,20 @@ pub mod GCCFG {
         pub mod RW {}
     }
 
+    /// Data contact detection mode enable (newer cores)
+    pub mod DCDEN {
+        /// Offset (18 bits)
+        pub const offset: u32 = 18;
+        /// Mask (1 bit: 1 << 18)
+        pub const mask: u32 = 1 << offset;
+        /// Read-only values (empty)
+        pub mod R {}
+        /// Write-only values (empty)
+        pub mod W {}
+        /// Read-write values (empty)
+        pub mod RW {}
+    }
+
     /// Enable the VBUS sensing device
     pub mod VBUSBSEN {
         /// Offset (19 bits)
@@ -1797,6 +1909,20 @@ pub mod GCCFG {
         pub mod RW {}
     }
 
+    /// Primary detection mode enable (newer cores)
+    pub mod PDEN {
+        /// Offset (19 bits)
+        pub const offset: u32 = 19;
+        /// Mask (1 bit: 1 << 19)
+        pub const mask: u32 = 1 << offset;
+        /// Read-only values (empty)
+        pub mod R {}
+        /// Write-only values (empty)
+        pub mod W {}
+        /// Read-write values (empty)
+        pub mod RW {}
+    }
+
     /// SOF output enable
     pub mod SOFOUTEN {
         /// Offset (20 bits)
@@ -1811,6 +1937,20 @@ pub mod GCCFG {
         pub mod RW {}
     }
 
+    /// Secondary detection mode enable (newer cores)
+    pub mod SDEN {
+        /// Offset (20 bits)
+        pub const offset: u32 = 20;
+        /// Mask (1 bit: 1 << 20)
+        pub const mask: u32 = 1 << offset;
+        /// Read-only values (empty)
+        pub mod R {}
+        /// Write-only values (empty)
+        pub mod W {}
+        /// Read-write values (empty)
+        pub mod RW {}
+    }
+
     /// VBUS sensing disable option
     pub mod NOVBUSSENS {
         /// Offset (21 bits)
@@ -1844,6 +1984,220 @@ pub mod CID {
     }
 }
 
+/// OTG_HS core LPM configuration register
+pub mod GLPMCFG {
+
+    /// LPM support enable
+    pub mod LPMEN {
+        /// Offset (0 bits)
+        pub const offset: u32 = 0;
+        /// Mask (1 bit: 1 << 0)
+        pub const mask: u32 = 1 << offset;
+        /// Read-only values (empty)
+        pub mod R {}
+        /// Write-only values (empty)
+        pub mod W {}
+        /// Read-write values (empty)
+        pub mod RW {}
+    }
+
+    /// LPM token acknowledge enable
+    pub mod LPMACK {
+        /// Offset (1 bits)
+        pub const offset: u32 = 1;
+        /// Mask (1 bit: 1 << 1)
+        pub const mask: u32 = 1 << offset;
+        /// Read-only values (empty)
+        pub mod R {}
+        /// Write-only values (empty)
+        pub mod W {}
+        /// Read-write values (empty)
+        pub mod RW {}
+    }
+
+    /// Best effort service latency
+    pub mod BESL {
+        /// Offset (2 bits)
+        pub const offset: u32 = 2;
+        /// Mask (4 bits: 0b1111 << 2)
+        pub const mask: u32 = 0b1111 << offset;
+        /// Read-only values (empty)
+        pub mod R {}
+        /// Write-only values (empty)
+        pub mod W {}
+        /// Read-write values (empty)
+        pub mod RW {}
+    }
+
+    /// bRemoteWake value
+    pub mod REMWAKE {
+        /// Offset (6 bits)
+        pub const offset: u32 = 6;
+        /// Mask (1 bit: 1 << 6)
+        pub const mask: u32 = 1 << offset;
+        /// Read-only values (empty)
+        pub mod R {}
+        /// Write-only values (empty)
+        pub mod W {}
+        /// Read-write values (empty)
+        pub mod RW {}
+    }
+
+    /// L1 Shallow Sleep enable
+    pub mod L1SSEN {
+        /// Offset (7 bits)
+        pub const offset: u32 = 7;
+        /// Mask (1 bit: 1 << 7)
+        pub const mask: u32 = 1 << offset;
+        /// Read-only values (empty)
+        pub mod R {}
+        /// Write-only values (empty)
+        pub mod W {}
+        /// Read-write values (empty)
+        pub mod RW {}
+    }
+
+    /// BESL threshold
+    pub mod BESLTHRS {
+        /// Offset (8 bits)
+        pub const offset: u32 = 8;
+        /// Mask (4 bits: 0b1111 << 8)
+        pub const mask: u32 = 0b1111 << offset;
+        /// Read-only values (empty)
+        pub mod R {}
+        /// Write-only values (empty)
+        pub mod W {}
+        /// Read-write values (empty)
+        pub mod RW {}
+    }
+
+    /// L1 deep sleep enable
+    pub mod L1DSEN {
+        /// Offset (12 bits)
+        pub const offset: u32 = 12;
+        /// Mask (1 bit: 1 << 12)
+        pub const mask: u32 = 1 << offset;
+        /// Read-only values (empty)
+        pub mod R {}
+        /// Write-only values (empty)
+        pub mod W {}
+        /// Read-write values (empty)
+        pub mod RW {}
+    }
+
+    /// LPM response
+    pub mod LPMRSP {
+        /// Offset (13 bits)
+        pub const offset: u32 = 13;
+        /// Mask (2 bits: 0b11 << 13)
+        pub const mask: u32 = 0b11 << offset;
+        /// Read-only values (empty)
+        pub mod R {}
+        /// Write-only values (empty)
+        pub mod W {}
+        /// Read-write values (empty)
+        pub mod RW {}
+    }
+
+    /// Port sleep status
+    pub mod SLPSTS {
+        /// Offset (15 bits)
+        pub const offset: u32 = 15;
+        /// Mask (1 bit: 1 << 15)
+        pub const mask: u32 = 1 << offset;
+        /// Read-only values (empty)
+        pub mod R {}
+        /// Write-only values (empty)
+        pub mod W {}
+        /// Read-write values (empty)
+        pub mod RW {}
+    }
+
+    /// Sleep State Resume OK
+    pub mod L1RSMOK {
+        /// Offset (16 bits)
+        pub const offset: u32 = 16;
+        /// Mask (1 bit: 1 << 16)
+        pub const mask: u32 = 1 << offset;
+        /// Read-only values (empty)
+        pub mod R {}
+        /// Write-only values (empty)
+        pub mod W {}
+        /// Read-write values (empty)
+        pub mod RW {}
+    }
+
+    /// LPM Channel Index
+    pub mod LPMCHIDX {
+        /// Offset (17 bits)
+        pub const offset: u32 = 17;
+        /// Mask (4 bits: 0b1111 << 17)
+        pub const mask: u32 = 0b1111 << offset;
+        /// Read-only values (empty)
+        pub mod R {}
+        /// Write-only values (empty)
+        pub mod W {}
+        /// Read-write values (empty)
+        pub mod RW {}
+    }
+
+    /// LPM retry count
+    pub mod LPMRCNT {
+        /// Offset (21 bits)
+        pub const offset: u32 = 21;
+        /// Mask (3 bits: 0b111 << 21)
+        pub const mask: u32 = 0b111 << offset;
+        /// Read-only values (empty)
+        pub mod R {}
+        /// Write-only values (empty)
+        pub mod W {}
+        /// Read-write values (empty)
+        pub mod RW {}
+    }
+
+    /// Send LPM transaction
+    pub mod SNDLPM {
+        /// Offset (24 bits)
+        pub const offset: u32 = 24;
+        /// Mask (1 bit: 1 << 24)
+        pub const mask: u32 = 1 << offset;
+        /// Read-only values (empty)
+        pub mod R {}
+        /// Write-only values (empty)
+        pub mod W {}
+        /// Read-write values (empty)
+        pub mod RW {}
+    }
+
+    /// LPM retry count status
+    pub mod LPMRCNTSTS {
+        /// Offset (25 bits)
+        pub const offset: u32 = 25;
+        /// Mask (3 bits: 0b111 << 25)
+        pub const mask: u32 = 0b111 << offset;
+        /// Read-only values (empty)
+        pub mod R {}
+        /// Write-only values (empty)
+        pub mod W {}
+        /// Read-write values (empty)
+        pub mod RW {}
+    }
+
+    /// Enable best effort service latency
+    pub mod ENBESL {
+        /// Offset (28 bits)
+        pub const offset: u32 = 28;
+        /// Mask (1 bit: 1 << 28)
+        pub const mask: u32 = 1 << offset;
+        /// Read-only values (empty)
+        pub mod R {}
+        /// Write-only values (empty)
+        pub mod W {}
+        /// Read-write values (empty)
+        pub mod RW {}
+    }
+}
+
 /// OTG_HS Host periodic transmit FIFO size register
 pub mod HPTXFSIZ {
 
@@ -1986,7 +2340,12 @@ pub struct RegisterBlock {
     /// OTG_HS core ID register
     pub CID: RWRegister<u32>,
 
-    _reserved2: [u32; 48],
+    _reserved2: [u32; 5],
+
+    /// OTG_HS core LPM configuration register
+    pub GLPMCFG: RWRegister<u32>,
+
+    _reserved3: [u32; 42],
 
     /// OTG_HS Host periodic transmit FIFO size register
     pub HPTXFSIZ: RWRegister<u32>,
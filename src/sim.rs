@@ -0,0 +1,675 @@
+//! Simulated OTG core for testing the driver on the host.
+//!
+//! With the internal `__sim` feature the register types of the RAL are replaced by ones that
+//! forward each access to a behavioural model of the core instead of touching memory.
+//! [`SimPeripheral`] and [`SimHsPeripheral`] are a full-speed and a high-speed peripheral backed
+//! by that model, and a [`Simulator`] plays the host side of the bus: it resets the bus and
+//! exchanges SETUP, OUT and IN packets with the endpoints.
+//!
+//! The model covers what the device driver relies on: the self-clearing GRSTCTL bits, the Rx
+//! status queue behind GRXSTSR/GRXSTSP, the Tx FIFOs and their free space in DTXFSTS, and the
+//! global and endpoint interrupt flags. DMA, host mode and OTG protocols aren't modelled.
+//!
+//! The register accesses of the driver can be recorded, see the [`trace`] module.
+//!
+//! There is a single simulated core, so a [`Simulator`] holds a global lock while it exists and
+//! tests using it run one after another.
+
+pub mod host;
+pub mod trace;
+
+use crate::{PhyType, UsbPeripheral};
+use std::collections::VecDeque;
+use std::sync::{Mutex, MutexGuard};
+use trace::{Access, AccessKind, Trace};
+
+/// Register types with accesses forwarded to the simulated core.
+pub mod register {
+    use core::cell::UnsafeCell;
+
+    /// A read-write register.
+    #[repr(transparent)]
+    pub struct RWRegister<T> {
+        register: UnsafeCell<T>,
+    }
+
+    impl RWRegister<u32> {
+        /// Reads the value of the register.
+        #[inline(always)]
+        pub fn read(&self) -> u32 {
+            super::read(self.register.get() as usize)
+        }
+
+        /// Writes a new value to the register.
+        #[inline(always)]
+        pub fn write(&self, val: u32) {
+            super::write(self.register.get() as usize, val)
+        }
+    }
+
+    /// A read-write register where access is unsafe.
+    #[repr(transparent)]
+    pub struct UnsafeRWRegister<T> {
+        register: UnsafeCell<T>,
+    }
+
+    impl UnsafeRWRegister<u32> {
+        /// Reads the value of the register.
+        ///
+        /// # Safety
+        /// The simulated register can always be accessed, the function is unsafe for parity with
+        /// the hardware register type.
+        #[inline(always)]
+        pub unsafe fn read(&self) -> u32 {
+            super::read(self.register.get() as usize)
+        }
+
+        /// Writes a new value to the register.
+        ///
+        /// # Safety
+        /// See [`read`](Self::read).
+        #[inline(always)]
+        pub unsafe fn write(&self, val: u32) {
+            super::write(self.register.get() as usize, val)
+        }
+    }
+
+    /// A read-only register.
+    #[repr(transparent)]
+    pub struct RORegister<T> {
+        register: UnsafeCell<T>,
+    }
+
+    impl RORegister<u32> {
+        /// Reads the value of the register.
+        #[inline(always)]
+        pub fn read(&self) -> u32 {
+            super::read(self.register.get() as usize)
+        }
+    }
+}
+
+/// Size of the simulated register space, up to the end of the last FIFO window
+const REGION_SIZE: usize = 0x11000;
+
+/// Address range occupied by the simulated core.
+///
+/// The driver forms references to register blocks in this range, the memory itself is never read
+/// or written.
+#[repr(C, align(4))]
+struct Region([u32; REGION_SIZE / 4]);
+
+static REGION: Region = Region([0; REGION_SIZE / 4]);
+
+static CORE: Mutex<Option<Core>> = Mutex::new(None);
+
+/// Serializes the users of the single simulated core
+static LOCK: Mutex<()> = Mutex::new(());
+
+// Register offsets
+const GOTGINT: usize = 0x004;
+const GAHBCFG: usize = 0x008;
+const GRSTCTL: usize = 0x010;
+const GINTSTS: usize = 0x014;
+const GINTMSK: usize = 0x018;
+const GRXSTSR: usize = 0x01c;
+const GRXSTSP: usize = 0x020;
+const DIEPTXF0: usize = 0x028;
+const CID: usize = 0x03c;
+const DIEPTXF1: usize = 0x104;
+const DCFG: usize = 0x800;
+const DCTL: usize = 0x804;
+const DSTS: usize = 0x808;
+const DIEPMSK: usize = 0x810;
+const DOEPMSK: usize = 0x814;
+const DAINT: usize = 0x818;
+const DAINTMSK: usize = 0x81c;
+const DIEPEMPMSK: usize = 0x834;
+const EP_IN: usize = 0x900;
+const EP_OUT: usize = 0xb00;
+const FIFO: usize = 0x1000;
+
+// Offsets within the endpoint register blocks
+const EPCTL: usize = 0x00;
+const EPINT: usize = 0x08;
+const EPTSIZ: usize = 0x10;
+const DTXFSTS: usize = 0x18;
+
+// GRSTCTL
+const CSRST: u32 = 1 << 0;
+const RXFFLSH: u32 = 1 << 4;
+const TXFFLSH: u32 = 1 << 5;
+const AHBIDL: u32 = 1 << 31;
+
+// GINTSTS
+const OTGINT: u32 = 1 << 2;
+const RXFLVL: u32 = 1 << 4;
+const USBSUSP: u32 = 1 << 11;
+const USBRST: u32 = 1 << 12;
+const ENUMDNE: u32 = 1 << 13;
+const IEPINT: u32 = 1 << 18;
+const OEPINT: u32 = 1 << 19;
+const WKUPINT: u32 = 1 << 31;
+
+// DCTL
+const SDIS: u32 = 1 << 1;
+
+// DSTS
+const SUSPSTS: u32 = 1 << 0;
+const ENUMSPD: u32 = 0b11 << 1;
+const ENUMSPD_HS: u32 = 0b00 << 1;
+const ENUMSPD_FS_HS_PHY: u32 = 0b01 << 1;
+const ENUMSPD_FS: u32 = 0b11 << 1;
+
+// DCFG
+const DSPD: u32 = 0b11;
+
+// DIEPCTL/DOEPCTL
+const EPENA: u32 = 1 << 31;
+const EPDIS: u32 = 1 << 30;
+const SODDFRM: u32 = 1 << 29;
+const SD0PID: u32 = 1 << 28;
+const SNAK: u32 = 1 << 27;
+const CNAK: u32 = 1 << 26;
+const STALL: u32 = 1 << 21;
+const NAKSTS: u32 = 1 << 17;
+
+// DIEPINT/DOEPINT
+const XFRC: u32 = 1 << 0;
+const EPDISD: u32 = 1 << 1;
+const TXFE: u32 = 1 << 7;
+
+// GRXSTSR.PKTSTS
+const OUT_RECEIVED: u32 = 0x2;
+const OUT_COMPLETED: u32 = 0x3;
+const SETUP_COMPLETED: u32 = 0x4;
+const SETUP_RECEIVED: u32 = 0x6;
+
+/// Core ID of the F429 OTG_FS core, the model follows its Rx FIFO behaviour
+const DEFAULT_CORE_ID: u32 = 0x0000_1200;
+
+fn lock_core() -> MutexGuard<'static, Option<Core>> {
+    CORE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn offset(address: usize) -> usize {
+    let base = SimPeripheral::REGISTERS as usize;
+    assert!(
+        address >= base && address < base + REGION_SIZE,
+        "access outside of the simulated core: {:#x}",
+        address
+    );
+    address - base
+}
+
+fn read(address: usize) -> u32 {
+    let offset = offset(address);
+    let mut core = lock_core();
+    let core = core
+        .as_mut()
+        .expect("simulated core accessed without a Simulator");
+    let value = core.read(offset);
+    core.record(AccessKind::Read, offset, value);
+    value
+}
+
+fn write(address: usize, value: u32) {
+    let offset = offset(address);
+    let mut core = lock_core();
+    let core = core
+        .as_mut()
+        .expect("simulated core accessed without a Simulator");
+    core.record(AccessKind::Write, offset, value);
+    core.write(offset, value)
+}
+
+/// Full-speed peripheral backed by the simulated core.
+///
+/// The PHY type is the one selected with [`Simulator::set_phy_type`].
+pub struct SimPeripheral;
+
+/// High-speed peripheral backed by the simulated core, for the init paths of HS cores.
+///
+/// The PHY type is the one selected with [`Simulator::set_phy_type`]. With a high-speed PHY, bus
+/// resets enumerate the device at high speed unless it is limited to full speed.
+pub struct SimHsPeripheral;
+
+unsafe impl UsbPeripheral for SimPeripheral {
+    const REGISTERS: *const () = &REGION as *const Region as *const ();
+
+    const HIGH_SPEED: bool = false;
+    const FIFO_DEPTH_WORDS: usize = 320;
+    const ENDPOINT_COUNT: usize = 4;
+
+    fn enable() {}
+
+    fn ahb_frequency_hz(&self) -> u32 {
+        48_000_000
+    }
+
+    fn phy_type(&self) -> PhyType {
+        lock_core()
+            .as_ref()
+            .map_or(PhyType::InternalFullSpeed, |core| core.phy_type)
+    }
+}
+
+unsafe impl UsbPeripheral for SimHsPeripheral {
+    const REGISTERS: *const () = SimPeripheral::REGISTERS;
+
+    const HIGH_SPEED: bool = true;
+    const FIFO_DEPTH_WORDS: usize = 1024;
+    const ENDPOINT_COUNT: usize = 6;
+
+    fn enable() {}
+
+    fn ahb_frequency_hz(&self) -> u32 {
+        168_000_000
+    }
+
+    fn phy_type(&self) -> PhyType {
+        SimPeripheral.phy_type()
+    }
+}
+
+/// Handshakes with which the device refuses a packet.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Handshake {
+    /// The endpoint isn't ready
+    Nak,
+    /// The endpoint is halted
+    Stall,
+}
+
+/// Host side of the simulated bus.
+///
+/// Creating a simulator resets the simulated core. Exchanging packets only changes the state of
+/// the core, the device driver has to be polled in between to process them.
+pub struct Simulator {
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl Simulator {
+    /// Creates a simulator with an F429-like full-speed core.
+    pub fn new() -> Self {
+        Self::with_core_id(DEFAULT_CORE_ID)
+    }
+
+    /// Creates a simulator with the given core ID (CID register).
+    ///
+    /// The core ID selects how the driver handles the Rx FIFO, e.g. `0x0000_2000` for F446-like
+    /// cores.
+    pub fn with_core_id(core_id: u32) -> Self {
+        let lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        *lock_core() = Some(Core::new(core_id));
+        Simulator { _lock: lock }
+    }
+
+    fn with_core<R>(&self, f: impl FnOnce(&mut Core) -> R) -> R {
+        f(lock_core().as_mut().unwrap())
+    }
+
+    /// Selects the PHY type reported by [`SimPeripheral`] and [`SimHsPeripheral`], the internal
+    /// full-speed PHY by default.
+    ///
+    /// The PHY is only configured by the driver with the `hs` feature.
+    pub fn set_phy_type(&self, phy_type: PhyType) {
+        self.with_core(|core| core.phy_type = phy_type);
+    }
+
+    /// Starts recording the register accesses of the driver, discarding a previous recording.
+    pub fn start_trace(&self) {
+        self.with_core(|core| core.trace = Some(Trace::new(core.regs[CID / 4])));
+    }
+
+    /// Stops recording and returns the accesses recorded since [`start_trace`](Self::start_trace).
+    ///
+    /// **Panics:** if no recording was started.
+    pub fn take_trace(&self) -> Trace {
+        self.with_core(|core| core.trace.take())
+            .expect("register trace not started")
+    }
+
+    /// Returns the raw value of a register without side effects.
+    pub fn register(&self, offset: usize) -> u32 {
+        self.with_core(|core| core.regs[offset / 4])
+    }
+
+    /// Returns `true` if the device has connected its pull-up (DCTL.SDIS cleared).
+    pub fn is_connected(&self) -> bool {
+        self.register(DCTL) & SDIS == 0
+    }
+
+    /// Returns the address assigned to the device (DCFG.DAD).
+    pub fn device_address(&self) -> u8 {
+        ((self.register(DCFG) >> 4) & 0x7f) as u8
+    }
+
+    /// Returns `true` if an unmasked interrupt is pending and the global interrupt is enabled.
+    pub fn interrupt_pending(&self) -> bool {
+        self.with_core(|core| {
+            core.regs[GAHBCFG / 4] & 1 != 0 && core.gintsts() & core.regs[GINTMSK / 4] != 0
+        })
+    }
+
+    /// Resets the bus, reporting the reset and a completed enumeration at the speed requested
+    /// by the device (DCFG.DSPD).
+    pub fn bus_reset(&self) {
+        self.with_core(|core| {
+            core.regs[GINTSTS / 4] |= USBRST | ENUMDNE;
+            // The host supports high speed, the device enumerates at the speed set in DCFG.DSPD
+            core.regs[DSTS / 4] = match core.regs[DCFG / 4] & DSPD {
+                0b00 => ENUMSPD_HS,
+                0b01 => ENUMSPD_FS_HS_PHY,
+                _ => ENUMSPD_FS,
+            };
+        });
+    }
+
+    /// Suspends the bus.
+    pub fn suspend(&self) {
+        self.with_core(|core| {
+            core.regs[GINTSTS / 4] |= USBSUSP;
+            core.regs[DSTS / 4] |= SUSPSTS;
+        });
+    }
+
+    /// Resumes the suspended bus.
+    pub fn resume(&self) {
+        self.with_core(|core| {
+            core.regs[GINTSTS / 4] |= WKUPINT;
+            core.regs[DSTS / 4] &= !SUSPSTS;
+        });
+    }
+
+    /// Sends a SETUP packet to a control endpoint.
+    ///
+    /// SETUP packets are always accepted, they clear a halt of endpoint 0.
+    pub fn send_setup(&self, ep: u8, packet: [u8; 8]) {
+        let ep = ep as usize;
+        self.with_core(|core| {
+            core.receive(ep, SETUP_RECEIVED, &packet);
+            core.receive(ep, SETUP_COMPLETED, &[]);
+            if ep == 0 {
+                core.regs[(EP_IN + EPCTL) / 4] &= !STALL;
+                core.regs[(EP_OUT + EPCTL) / 4] &= !STALL;
+            }
+        });
+    }
+
+    /// Sends an OUT packet.
+    ///
+    /// The endpoint takes a single packet each time it is enabled.
+    pub fn send_out(&self, ep: u8, data: &[u8]) -> Result<(), Handshake> {
+        let ep = ep as usize;
+        self.with_core(|core| {
+            let ctl = core.regs[(EP_OUT + 0x20 * ep + EPCTL) / 4];
+            if ctl & STALL != 0 {
+                return Err(Handshake::Stall);
+            }
+            if ctl & EPENA == 0 || ctl & NAKSTS != 0 {
+                return Err(Handshake::Nak);
+            }
+            assert!(
+                data.len() <= max_packet_size(ep, ctl),
+                "OUT packet exceeds the maximum packet size"
+            );
+
+            core.receive(ep, OUT_RECEIVED, data);
+            core.receive(ep, OUT_COMPLETED, &[]);
+            core.regs[(EP_OUT + 0x20 * ep + EPCTL) / 4] &= !EPENA;
+            core.regs[(EP_OUT + 0x20 * ep + EPINT) / 4] |= XFRC;
+            Ok(())
+        })
+    }
+
+    /// Requests an IN packet.
+    ///
+    /// A packet is only returned once the driver has written all of it to the Tx FIFO.
+    pub fn receive_in(&self, ep: u8) -> Result<Vec<u8>, Handshake> {
+        let ep = ep as usize;
+        self.with_core(|core| {
+            let ctl = core.regs[(EP_IN + 0x20 * ep + EPCTL) / 4];
+            if ctl & STALL != 0 {
+                return Err(Handshake::Stall);
+            }
+            if ctl & EPENA == 0 {
+                return Err(Handshake::Nak);
+            }
+
+            let tsiz = core.regs[(EP_IN + 0x20 * ep + EPTSIZ) / 4];
+            let xfrsiz = tsiz & 0x7ffff;
+            let pktcnt = (tsiz >> 19) & 0x3ff;
+            let size = core::cmp::min(xfrsiz as usize, max_packet_size(ep, ctl));
+            let words = size.div_ceil(4);
+            if core.tx_fifos[ep].len() < words {
+                return Err(Handshake::Nak);
+            }
+
+            let mut packet: Vec<u8> = core.tx_fifos[ep]
+                .drain(..words)
+                .flat_map(u32::to_le_bytes)
+                .collect();
+            packet.truncate(size);
+
+            let pktcnt = pktcnt.saturating_sub(1);
+            let xfrsiz = xfrsiz - size as u32;
+            core.regs[(EP_IN + 0x20 * ep + EPTSIZ) / 4] =
+                (tsiz & !0x1fff_ffff) | (pktcnt << 19) | xfrsiz;
+            if pktcnt == 0 {
+                core.regs[(EP_IN + 0x20 * ep + EPCTL) / 4] &= !EPENA;
+                core.regs[(EP_IN + 0x20 * ep + EPINT) / 4] |= XFRC;
+            }
+            Ok(packet)
+        })
+    }
+}
+
+impl Default for Simulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Simulator {
+    fn drop(&mut self) {
+        *lock_core() = None;
+    }
+}
+
+/// Returns the maximum packet size from DIEPCTL/DOEPCTL.MPSIZ.
+fn max_packet_size(ep: usize, ctl: u32) -> usize {
+    if ep == 0 {
+        match ctl & 0b11 {
+            0b00 => 64,
+            0b01 => 32,
+            0b10 => 16,
+            _ => 8,
+        }
+    } else {
+        (ctl & 0x7ff) as usize
+    }
+}
+
+/// State of the simulated core.
+struct Core {
+    /// Stored register values below the FIFO windows
+    regs: Vec<u32>,
+    /// Entries of the Rx FIFO status queue with their data
+    rx_status: VecDeque<(u32, Vec<u32>)>,
+    /// Data of the popped Rx FIFO entry
+    rx_data: VecDeque<u32>,
+    tx_fifos: Vec<VecDeque<u32>>,
+    phy_type: PhyType,
+    trace: Option<Trace>,
+}
+
+impl Core {
+    fn new(core_id: u32) -> Self {
+        let mut regs = vec![0; FIFO / 4];
+        regs[CID / 4] = core_id;
+        regs[DCTL / 4] = SDIS;
+        Core {
+            regs,
+            rx_status: VecDeque::new(),
+            rx_data: VecDeque::new(),
+            tx_fifos: vec![VecDeque::new(); 16],
+            phy_type: PhyType::InternalFullSpeed,
+            trace: None,
+        }
+    }
+
+    fn record(&mut self, kind: AccessKind, offset: usize, value: u32) {
+        if let Some(trace) = &mut self.trace {
+            trace.push(Access {
+                kind,
+                offset,
+                value,
+            });
+        }
+    }
+
+    /// Queues a packet in the Rx FIFO.
+    fn receive(&mut self, ep: usize, status: u32, data: &[u8]) {
+        let words = data
+            .chunks(4)
+            .map(|chunk| {
+                let mut bytes = [0; 4];
+                bytes[..chunk.len()].copy_from_slice(chunk);
+                u32::from_le_bytes(bytes)
+            })
+            .collect();
+        let entry = (status << 17) | ((data.len() as u32) << 4) | ep as u32;
+        self.rx_status.push_back((entry, words));
+    }
+
+    fn gintsts(&self) -> u32 {
+        let mut value = self.regs[GINTSTS / 4];
+        if !self.rx_status.is_empty() {
+            value |= RXFLVL;
+        }
+        if self.regs[GOTGINT / 4] != 0 {
+            value |= OTGINT;
+        }
+        let daint = self.daint() & self.regs[DAINTMSK / 4];
+        if daint & 0xffff != 0 {
+            value |= IEPINT;
+        }
+        if daint >> 16 != 0 {
+            value |= OEPINT;
+        }
+        value
+    }
+
+    fn diepint(&self, ep: usize) -> u32 {
+        let value = self.regs[(EP_IN + 0x20 * ep + EPINT) / 4];
+        if self.tx_fifos[ep].is_empty() {
+            value | TXFE
+        } else {
+            value
+        }
+    }
+
+    fn daint(&self) -> u32 {
+        let mut value = 0;
+        for ep in 0..16 {
+            let mut mask = self.regs[DIEPMSK / 4];
+            if self.regs[DIEPEMPMSK / 4] & (1 << ep) != 0 {
+                mask |= TXFE;
+            }
+            if self.diepint(ep) & mask != 0 {
+                value |= 1 << ep;
+            }
+            let doepint = self.regs[(EP_OUT + 0x20 * ep + EPINT) / 4];
+            if doepint & self.regs[DOEPMSK / 4] != 0 {
+                value |= 1 << (16 + ep);
+            }
+        }
+        value
+    }
+
+    /// Returns the Tx FIFO size of an IN endpoint in words.
+    fn tx_fifo_size(&self, ep: usize) -> usize {
+        let fifo_size = if ep == 0 {
+            self.regs[DIEPTXF0 / 4]
+        } else {
+            self.regs[(DIEPTXF1 + 4 * (ep - 1)) / 4]
+        };
+        (fifo_size >> 16) as usize
+    }
+
+    fn read(&mut self, offset: usize) -> u32 {
+        match offset {
+            GRSTCTL => self.regs[offset / 4] | AHBIDL,
+            GINTSTS => self.gintsts(),
+            GRXSTSR => self.rx_status.front().map_or(0, |(status, _)| *status),
+            GRXSTSP => match self.rx_status.pop_front() {
+                Some((status, data)) => {
+                    self.rx_data = data.into();
+                    status
+                }
+                None => 0,
+            },
+            DAINT => self.daint(),
+            FIFO.. => self.rx_data.pop_front().unwrap_or(0),
+            EP_IN..=0xaff if offset % 0x20 == EPINT => self.diepint((offset - EP_IN) / 0x20),
+            EP_IN..=0xaff if offset % 0x20 == DTXFSTS => {
+                let ep = (offset - EP_IN) / 0x20;
+                let used = self.tx_fifos[ep].len();
+                self.tx_fifo_size(ep).saturating_sub(used) as u32
+            }
+            _ => self.regs[offset / 4],
+        }
+    }
+
+    fn write(&mut self, offset: usize, value: u32) {
+        match offset {
+            GRSTCTL => {
+                if value & CSRST != 0 {
+                    self.regs[GINTSTS / 4] = 0;
+                    self.rx_status.clear();
+                    self.rx_data.clear();
+                    self.tx_fifos.iter_mut().for_each(VecDeque::clear);
+                }
+                if value & RXFFLSH != 0 {
+                    self.rx_status.clear();
+                    self.rx_data.clear();
+                }
+                if value & TXFFLSH != 0 {
+                    match ((value >> 6) & 0x1f) as usize {
+                        0x10 => self.tx_fifos.iter_mut().for_each(VecDeque::clear),
+                        ep => self.tx_fifos[ep].clear(),
+                    }
+                }
+                self.regs[offset / 4] = value & !(CSRST | RXFFLSH | TXFFLSH);
+            }
+            GINTSTS | GOTGINT => self.regs[offset / 4] &= !value,
+            GRXSTSR | GRXSTSP | DSTS | DAINT => {}
+            FIFO.. => {
+                let ep = (offset - FIFO) / 0x1000;
+                self.tx_fifos[ep].push_back(value);
+            }
+            EP_IN..=0xcff if offset % 0x20 == EPINT => self.regs[offset / 4] &= !value,
+            EP_IN..=0xcff if offset % 0x20 == EPCTL => self.write_epctl(offset, value),
+            _ => self.regs[offset / 4] = value,
+        }
+    }
+
+    /// Writes DIEPCTL/DOEPCTL, handling the bits that are only set by software.
+    fn write_epctl(&mut self, offset: usize, value: u32) {
+        let old = self.regs[offset / 4];
+        let mut new =
+            (value & !(EPDIS | SODDFRM | SD0PID | SNAK | CNAK | NAKSTS)) | (old & (EPENA | NAKSTS));
+        if value & SNAK != 0 {
+            new |= NAKSTS;
+        }
+        if value & CNAK != 0 {
+            new &= !NAKSTS;
+        }
+        if value & EPDIS != 0 && old & EPENA != 0 {
+            new &= !EPENA;
+            self.regs[(offset + EPINT) / 4] |= EPDISD;
+        }
+        self.regs[offset / 4] = new;
+    }
+}
@@ -0,0 +1,292 @@
+//! Scripted host for end-to-end tests against the simulated core.
+//!
+//! A [`VirtualHost`] performs transactions and control transfers on the [`Simulator`], polling
+//! the device in between. Sequences of them can be written down as a script of [`Step`]s, and
+//! [`VirtualHost::enumerate`] runs the enumeration sequence of [`Enumerator`] on it.
+
+use super::{Handshake, Simulator, DSTS, ENUMSPD, ENUMSPD_HS};
+use crate::enumeration::{EnumeratedDevice, EnumerationError, EnumerationHost, Enumerator};
+use crate::host::{DeviceSpeed, HostError, Pipe, PipeConfig};
+use embedded_hal::blocking::delay::DelayMs;
+
+pub use crate::enumeration::setup_packet;
+
+/// Number of NAK responses after which a transaction is abandoned.
+const NAK_RETRY_LIMIT: usize = 16;
+
+/// Number of times the device is polled after each bus event or transaction.
+const POLLS_PER_TRANSACTION: usize = 3;
+
+/// One step of a host script.
+#[derive(Copy, Clone, Debug)]
+pub enum Step<'a> {
+    /// Resets the bus.
+    Reset,
+    /// Suspends the bus.
+    Suspend,
+    /// Resumes the bus.
+    Resume,
+    /// Sends a SETUP packet to an endpoint.
+    Setup(u8, [u8; 8]),
+    /// Sends an OUT packet to an endpoint.
+    Out(u8, &'a [u8]),
+    /// Requests an IN packet from an endpoint, the packet is collected.
+    In(u8),
+    /// Requests an IN packet from an endpoint and compares it.
+    ExpectIn(u8, &'a [u8]),
+    /// Requests an IN packet from an endpoint, which has to answer with STALL.
+    ExpectStall(u8),
+    /// Runs a control read on endpoint 0, the data stage is collected.
+    ControlIn([u8; 8]),
+    /// Runs a control read on endpoint 0 and compares the data stage.
+    ExpectControlIn([u8; 8], &'a [u8]),
+    /// Runs a control write on endpoint 0 with the given (possibly empty) data stage.
+    ControlOut([u8; 8], &'a [u8]),
+}
+
+/// Errors reported by [`VirtualHost::run`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ScriptError {
+    /// A transaction of the step failed.
+    Host {
+        /// Index of the step in the script
+        step: usize,
+        error: HostError,
+    },
+    /// The device sent different data than expected, or data instead of a STALL.
+    Mismatch {
+        /// Index of the step in the script
+        step: usize,
+        received: Vec<u8>,
+    },
+}
+
+/// The simulated bus has no timing, delays return immediately.
+struct NoDelay;
+
+impl DelayMs<u32> for NoDelay {
+    fn delay_ms(&mut self, _ms: u32) {}
+}
+
+/// Host side of the simulated bus driving a device through transactions.
+///
+/// `poll` is called after each bus event and transaction and whenever the device answers with
+/// NAK. It has to poll the `UsbDevice` and its classes.
+pub struct VirtualHost<'a, P> {
+    sim: &'a Simulator,
+    poll: P,
+    max_packet_size_0: usize,
+}
+
+impl<'a, P: FnMut()> VirtualHost<'a, P> {
+    pub fn new(sim: &'a Simulator, poll: P) -> Self {
+        VirtualHost {
+            sim,
+            poll,
+            max_packet_size_0: 8,
+        }
+    }
+
+    /// Sets the maximum packet size of endpoint 0, used to detect the end of a data stage.
+    ///
+    /// [`enumerate`](Self::enumerate) sets it from the device descriptor.
+    pub fn set_max_packet_size_0(&mut self, max_packet_size: usize) {
+        self.max_packet_size_0 = max_packet_size;
+    }
+
+    fn poll(&mut self) {
+        for _ in 0..POLLS_PER_TRANSACTION {
+            (self.poll)();
+        }
+    }
+
+    /// Retries a transaction while the device answers with NAK.
+    fn transaction<T>(
+        &mut self,
+        mut f: impl FnMut(&Simulator) -> Result<T, Handshake>,
+    ) -> Result<T, HostError> {
+        for _ in 0..NAK_RETRY_LIMIT {
+            match f(self.sim) {
+                Ok(result) => {
+                    self.poll();
+                    return Ok(result);
+                }
+                Err(Handshake::Stall) => return Err(HostError::Stall),
+                Err(Handshake::Nak) => self.poll(),
+            }
+        }
+        Err(HostError::Nak)
+    }
+
+    /// Resets the bus.
+    pub fn reset(&mut self) {
+        self.sim.bus_reset();
+        self.poll();
+    }
+
+    /// Suspends the bus.
+    pub fn suspend(&mut self) {
+        self.sim.suspend();
+        self.poll();
+    }
+
+    /// Resumes the bus.
+    pub fn resume(&mut self) {
+        self.sim.resume();
+        self.poll();
+    }
+
+    /// Sends a SETUP packet.
+    pub fn setup(&mut self, ep: u8, packet: [u8; 8]) {
+        self.sim.send_setup(ep, packet);
+        self.poll();
+    }
+
+    /// Sends an OUT packet.
+    pub fn out_packet(&mut self, ep: u8, data: &[u8]) -> Result<(), HostError> {
+        self.transaction(|sim| sim.send_out(ep, data))
+    }
+
+    /// Requests an IN packet.
+    pub fn in_packet(&mut self, ep: u8) -> Result<Vec<u8>, HostError> {
+        self.transaction(|sim| sim.receive_in(ep))
+    }
+
+    /// Runs a control read on endpoint 0 and returns the data stage.
+    pub fn control_in(&mut self, setup: [u8; 8]) -> Result<Vec<u8>, HostError> {
+        let length = u16::from_le_bytes([setup[6], setup[7]]) as usize;
+
+        self.setup(0, setup);
+
+        let mut data = Vec::new();
+        while data.len() < length {
+            let packet = self.in_packet(0)?;
+            let short = packet.len() < self.max_packet_size_0;
+            data.extend(packet);
+            if short {
+                break;
+            }
+        }
+        if data.len() > length {
+            return Err(HostError::Babble);
+        }
+
+        // Status stage
+        self.out_packet(0, &[])?;
+        Ok(data)
+    }
+
+    /// Runs a control write on endpoint 0.
+    pub fn control_out(&mut self, setup: [u8; 8], data: &[u8]) -> Result<(), HostError> {
+        self.setup(0, setup);
+
+        for packet in data.chunks(self.max_packet_size_0) {
+            self.out_packet(0, packet)?;
+        }
+
+        // Status stage
+        match self.in_packet(0)? {
+            packet if packet.is_empty() => Ok(()),
+            _ => Err(HostError::Babble),
+        }
+    }
+
+    /// Runs a script, returning the data collected by the `In` and `ControlIn` steps.
+    pub fn run(&mut self, script: &[Step<'_>]) -> Result<Vec<Vec<u8>>, ScriptError> {
+        let mut collected = Vec::new();
+
+        for (step, action) in script.iter().enumerate() {
+            let err = |error| ScriptError::Host { step, error };
+            let expect = |received: Vec<u8>, expected: &[u8]| {
+                if received == expected {
+                    Ok(())
+                } else {
+                    Err(ScriptError::Mismatch { step, received })
+                }
+            };
+
+            match *action {
+                Step::Reset => self.reset(),
+                Step::Suspend => self.suspend(),
+                Step::Resume => self.resume(),
+                Step::Setup(ep, packet) => self.setup(ep, packet),
+                Step::Out(ep, data) => self.out_packet(ep, data).map_err(err)?,
+                Step::In(ep) => collected.push(self.in_packet(ep).map_err(err)?),
+                Step::ExpectIn(ep, expected) => expect(self.in_packet(ep).map_err(err)?, expected)?,
+                Step::ExpectStall(ep) => match self.in_packet(ep) {
+                    Err(HostError::Stall) => {}
+                    Err(error) => return Err(err(error)),
+                    Ok(received) => return Err(ScriptError::Mismatch { step, received }),
+                },
+                Step::ControlIn(setup) => collected.push(self.control_in(setup).map_err(err)?),
+                Step::ExpectControlIn(setup, expected) => {
+                    expect(self.control_in(setup).map_err(err)?, expected)?
+                }
+                Step::ControlOut(setup, data) => self.control_out(setup, data).map_err(err)?,
+            }
+        }
+
+        Ok(collected)
+    }
+
+    /// Enumerates the device with `enumerator`, which assigns its address, and selects its
+    /// first configuration.
+    pub fn enumerate(
+        &mut self,
+        enumerator: &mut Enumerator,
+    ) -> Result<EnumeratedDevice, EnumerationError> {
+        let mut buf = vec![0; u16::MAX as usize];
+        enumerator.enumerate_on(self, &mut NoDelay, &mut buf)
+    }
+}
+
+/// Host side of [`Enumerator`] with the single control pipe of the virtual host, which ignores
+/// the device address.
+impl<P: FnMut()> EnumerationHost for VirtualHost<'_, P> {
+    fn reset_port(&mut self, _delay: &mut impl DelayMs<u32>) -> Result<DeviceSpeed, HostError> {
+        self.reset();
+        match self.sim.register(DSTS) & ENUMSPD {
+            ENUMSPD_HS => Ok(DeviceSpeed::High),
+            _ => Ok(DeviceSpeed::Full),
+        }
+    }
+
+    fn alloc_pipe(&mut self, config: PipeConfig) -> Result<Pipe, HostError> {
+        self.max_packet_size_0 = config.max_packet_size as usize;
+        Ok(Pipe(0))
+    }
+
+    fn free_pipe(&mut self, _pipe: Pipe) {}
+
+    fn reconfigure_pipe(
+        &mut self,
+        _pipe: Pipe,
+        _device_address: u8,
+        max_packet_size: u16,
+    ) -> Result<(), HostError> {
+        self.max_packet_size_0 = max_packet_size as usize;
+        Ok(())
+    }
+
+    fn control_in(
+        &mut self,
+        _pipe: Pipe,
+        setup: &[u8; 8],
+        buf: &mut [u8],
+    ) -> Result<usize, HostError> {
+        let data = VirtualHost::control_in(self, *setup)?;
+        let buf = buf.get_mut(..data.len()).ok_or(HostError::Babble)?;
+        buf.copy_from_slice(&data);
+        Ok(data.len())
+    }
+
+    fn control_out(
+        &mut self,
+        _pipe: Pipe,
+        setup: &[u8; 8],
+        data: &[u8],
+    ) -> Result<usize, HostError> {
+        VirtualHost::control_out(self, *setup, data)?;
+        Ok(data.len())
+    }
+}
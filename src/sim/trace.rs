@@ -0,0 +1,415 @@
+//! Recording of the register accesses made by the driver.
+//!
+//! [`Simulator::start_trace`](super::Simulator::start_trace) records every read and write that
+//! goes through the RAL register types, accesses of the [`Simulator`](super::Simulator) itself
+//! aren't recorded. A [`Trace`] prints one access per line with the register name and the
+//! fields that are set, so that the initialization sequence of a core can be compared against a
+//! checked-in trace.
+
+use core::fmt;
+
+/// Direction of a register access.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/// A single register access.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Access {
+    pub kind: AccessKind,
+    /// Offset of the register from the start of the core
+    pub offset: usize,
+    /// Value read from or written to the register
+    pub value: u32,
+}
+
+/// Register accesses recorded by a [`Simulator`](super::Simulator).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Trace {
+    core_id: u32,
+    accesses: Vec<Access>,
+}
+
+impl Trace {
+    pub(crate) fn new(core_id: u32) -> Self {
+        Trace {
+            core_id,
+            accesses: Vec::new(),
+        }
+    }
+
+    pub(crate) fn push(&mut self, access: Access) {
+        self.accesses.push(access);
+    }
+
+    /// Returns the recorded accesses in order.
+    pub fn accesses(&self) -> &[Access] {
+        &self.accesses
+    }
+}
+
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for access in &self.accesses {
+            let kind = match access.kind {
+                AccessKind::Read => 'R',
+                AccessKind::Write => 'W',
+            };
+            write!(
+                f,
+                "{} {:<12} {:08x}",
+                kind,
+                register_name(access.offset),
+                access.value
+            )?;
+            for &(name, lsb, width) in fields(self.core_id, access.offset) {
+                let value = (access.value >> lsb) & (u32::MAX >> (32 - width));
+                match value {
+                    0 => {}
+                    1 if width == 1 => write!(f, " {}", name)?,
+                    _ => write!(f, " {}={:#x}", name, value)?,
+                }
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the name of the register at `offset`.
+pub fn register_name(offset: usize) -> String {
+    let name = match offset {
+        0x000 => "GOTGCTL",
+        0x004 => "GOTGINT",
+        0x008 => "GAHBCFG",
+        0x00c => "GUSBCFG",
+        0x010 => "GRSTCTL",
+        0x014 => "GINTSTS",
+        0x018 => "GINTMSK",
+        0x01c => "GRXSTSR",
+        0x020 => "GRXSTSP",
+        0x024 => "GRXFSIZ",
+        0x028 => "DIEPTXF0",
+        0x02c => "HNPTXSTS",
+        0x038 => "GCCFG",
+        0x03c => "CID",
+        0x054 => "GLPMCFG",
+        0x100 => "HPTXFSIZ",
+        0x104..=0x13c => return format!("DIEPTXF{}", (offset - 0x100) / 4),
+        0x400 => "HCFG",
+        0x404 => "HFIR",
+        0x408 => "HFNUM",
+        0x410 => "HPTXSTS",
+        0x414 => "HAINT",
+        0x418 => "HAINTMSK",
+        0x440 => "HPRT",
+        0x500..=0x6ff => {
+            let name = match offset % 0x20 {
+                0x00 => "HCCHAR",
+                0x04 => "HCSPLT",
+                0x08 => "HCINT",
+                0x0c => "HCINTMSK",
+                0x10 => "HCTSIZ",
+                0x14 => "HCDMA",
+                _ => return format!("{:#05x}", offset),
+            };
+            return format!("{}{}", name, (offset - 0x500) / 0x20);
+        }
+        0x800 => "DCFG",
+        0x804 => "DCTL",
+        0x808 => "DSTS",
+        0x810 => "DIEPMSK",
+        0x814 => "DOEPMSK",
+        0x818 => "DAINT",
+        0x81c => "DAINTMSK",
+        0x828 => "DVBUSDIS",
+        0x82c => "DVBUSPULSE",
+        0x830 => "DTHRCTL",
+        0x834 => "DIEPEMPMSK",
+        0x838 => "DEACHINT",
+        0x83c => "DEACHINTMSK",
+        0x900..=0xcff => {
+            let (dir, base) = if offset < 0xb00 {
+                ("DIEP", 0x900)
+            } else {
+                ("DOEP", 0xb00)
+            };
+            let name = match offset % 0x20 {
+                0x00 => "CTL",
+                0x08 => "INT",
+                0x10 => "TSIZ",
+                0x14 => "DMA",
+                0x18 if dir == "DIEP" => return format!("DTXFSTS{}", (offset - base) / 0x20),
+                _ => return format!("{:#05x}", offset),
+            };
+            return format!("{}{}{}", dir, name, (offset - base) / 0x20);
+        }
+        0xe00 => "PCGCCTL",
+        0x1000.. => return format!("FIFO{}", (offset - 0x1000) / 0x1000),
+        _ => return format!("{:#05x}", offset),
+    };
+    name.into()
+}
+
+/// A register field: name, position of the least significant bit and width.
+type Field = (&'static str, u32, u32);
+
+const GOTGCTL: &[Field] = &[
+    ("SRQSCS", 0, 1),
+    ("SRQ", 1, 1),
+    ("VBVALOEN", 2, 1),
+    ("VBVALOVAL", 3, 1),
+    ("AVALOEN", 4, 1),
+    ("AVALOVAL", 5, 1),
+    ("BVALOEN", 6, 1),
+    ("BVALOVAL", 7, 1),
+    ("HNGSCS", 8, 1),
+    ("HNPRQ", 9, 1),
+    ("HSHNPEN", 10, 1),
+    ("DHNPEN", 11, 1),
+    ("CIDSTS", 16, 1),
+    ("DBCT", 17, 1),
+    ("ASVLD", 18, 1),
+    ("BSVLD", 19, 1),
+];
+
+const GAHBCFG: &[Field] = &[
+    ("GINT", 0, 1),
+    ("HBSTLEN", 1, 4),
+    ("DMAEN", 5, 1),
+    ("TXFELVL", 7, 1),
+    ("PTXFELVL", 8, 1),
+];
+
+const GUSBCFG: &[Field] = &[
+    ("TOCAL", 0, 3),
+    ("ULPISEL", 4, 1),
+    ("PHYSEL", 6, 1),
+    ("SRPCAP", 8, 1),
+    ("HNPCAP", 9, 1),
+    ("TRDT", 10, 4),
+    ("PHYLPCS", 15, 1),
+    ("ULPIFSLS", 17, 1),
+    ("ULPIAR", 18, 1),
+    ("ULPICSM", 19, 1),
+    ("ULPIEVBUSD", 20, 1),
+    ("ULPIEVBUSI", 21, 1),
+    ("TSDPS", 22, 1),
+    ("PCCI", 23, 1),
+    ("PTCI", 24, 1),
+    ("ULPIIPD", 25, 1),
+    ("FHMOD", 29, 1),
+    ("FDMOD", 30, 1),
+    ("CTXPKT", 31, 1),
+];
+
+const GRSTCTL: &[Field] = &[
+    ("CSRST", 0, 1),
+    ("HSRST", 1, 1),
+    ("FCRST", 2, 1),
+    ("RXFFLSH", 4, 1),
+    ("TXFFLSH", 5, 1),
+    ("TXFNUM", 6, 5),
+    ("DMAREQ", 30, 1),
+    ("AHBIDL", 31, 1),
+];
+
+const GINTSTS: &[Field] = &[
+    ("CMOD", 0, 1),
+    ("MMIS", 1, 1),
+    ("OTGINT", 2, 1),
+    ("SOF", 3, 1),
+    ("RXFLVL", 4, 1),
+    ("NPTXFE", 5, 1),
+    ("GINAKEFF", 6, 1),
+    ("GONAKEFF", 7, 1),
+    ("ESUSP", 10, 1),
+    ("USBSUSP", 11, 1),
+    ("USBRST", 12, 1),
+    ("ENUMDNE", 13, 1),
+    ("ISOODRP", 14, 1),
+    ("EOPF", 15, 1),
+    ("IEPINT", 18, 1),
+    ("OEPINT", 19, 1),
+    ("IISOIXFR", 20, 1),
+    ("IPXFR", 21, 1),
+    ("DATAFSUSP", 22, 1),
+    ("RSTDET", 23, 1),
+    ("HPRTINT", 24, 1),
+    ("HCINT", 25, 1),
+    ("PTXFE", 26, 1),
+    ("LPMINT", 27, 1),
+    ("CIDSCHG", 28, 1),
+    ("DISCINT", 29, 1),
+    ("SRQINT", 30, 1),
+    ("WKUPINT", 31, 1),
+];
+
+const GRXSTSR: &[Field] = &[
+    ("EPNUM", 0, 4),
+    ("BCNT", 4, 11),
+    ("DPID", 15, 2),
+    ("PKTSTS", 17, 4),
+    ("FRMNUM", 21, 4),
+];
+
+const GRXFSIZ: &[Field] = &[("RXFD", 0, 16)];
+
+const TXFSIZ: &[Field] = &[("FSA", 0, 16), ("FD", 16, 16)];
+
+/// GCCFG of the F429-like cores
+const GCCFG_V1: &[Field] = &[
+    ("PWRDWN", 16, 1),
+    ("VBUSASEN", 18, 1),
+    ("VBUSBSEN", 19, 1),
+    ("SOFOUTEN", 20, 1),
+    ("NOVBUSSENS", 21, 1),
+];
+
+/// GCCFG of the F446-like cores with battery charging detection
+const GCCFG_V2: &[Field] = &[
+    ("DCDET", 0, 1),
+    ("PDET", 1, 1),
+    ("SDET", 2, 1),
+    ("PS2DET", 3, 1),
+    ("PWRDWN", 16, 1),
+    ("BCDEN", 17, 1),
+    ("DCDEN", 18, 1),
+    ("PDEN", 19, 1),
+    ("SDEN", 20, 1),
+    ("VBDEN", 21, 1),
+    ("PHYHSEN", 23, 1),
+];
+
+const DCFG: &[Field] = &[
+    ("DSPD", 0, 2),
+    ("NZLSOHSK", 2, 1),
+    ("DAD", 4, 7),
+    ("PFIVL", 11, 2),
+    ("XCVRDLY", 14, 1),
+    ("PERSCHIVL", 24, 2),
+];
+
+const DCTL: &[Field] = &[
+    ("RWUSIG", 0, 1),
+    ("SDIS", 1, 1),
+    ("GINSTS", 2, 1),
+    ("GONSTS", 3, 1),
+    ("TCTL", 4, 3),
+    ("SGINAK", 7, 1),
+    ("CGINAK", 8, 1),
+    ("SGONAK", 9, 1),
+    ("CGONAK", 10, 1),
+    ("POPRGDNE", 11, 1),
+];
+
+const DSTS: &[Field] = &[
+    ("SUSPSTS", 0, 1),
+    ("ENUMSPD", 1, 2),
+    ("EERR", 3, 1),
+    ("FNSOF", 8, 14),
+];
+
+const DIEPMSK: &[Field] = &[
+    ("XFRCM", 0, 1),
+    ("EPDM", 1, 1),
+    ("TOM", 3, 1),
+    ("ITTXFEMSK", 4, 1),
+    ("INEPNMM", 5, 1),
+    ("INEPNEM", 6, 1),
+    ("TXFURM", 8, 1),
+    ("BIM", 9, 1),
+];
+
+const DOEPMSK: &[Field] = &[
+    ("XFRCM", 0, 1),
+    ("EPDM", 1, 1),
+    ("STUPM", 3, 1),
+    ("OTEPDM", 4, 1),
+    ("B2BSTUP", 6, 1),
+    ("OPEM", 8, 1),
+    ("BOIM", 9, 1),
+];
+
+const DAINT: &[Field] = &[("IEPINT", 0, 16), ("OEPINT", 16, 16)];
+
+const DEPCTL: &[Field] = &[
+    ("MPSIZ", 0, 11),
+    ("USBAEP", 15, 1),
+    ("EONUM_DPID", 16, 1),
+    ("NAKSTS", 17, 1),
+    ("EPTYP", 18, 2),
+    ("STALL", 21, 1),
+    ("TXFNUM", 22, 4),
+    ("CNAK", 26, 1),
+    ("SNAK", 27, 1),
+    ("SD0PID", 28, 1),
+    ("SODDFRM", 29, 1),
+    ("EPDIS", 30, 1),
+    ("EPENA", 31, 1),
+];
+
+const DIEPINT: &[Field] = &[
+    ("XFRC", 0, 1),
+    ("EPDISD", 1, 1),
+    ("TOC", 3, 1),
+    ("ITTXFE", 4, 1),
+    ("INEPNE", 6, 1),
+    ("TXFE", 7, 1),
+];
+
+const DOEPINT: &[Field] = &[
+    ("XFRC", 0, 1),
+    ("EPDISD", 1, 1),
+    ("STUP", 3, 1),
+    ("OTEPDIS", 4, 1),
+    ("STSPHSRX", 5, 1),
+    ("B2BSTUP", 6, 1),
+];
+
+const DEPTSIZ: &[Field] = &[("XFRSIZ", 0, 19), ("PKTCNT", 19, 10), ("MCNT", 29, 2)];
+
+const DTXFSTS: &[Field] = &[("INEPTFSAV", 0, 16)];
+
+const PCGCCTL: &[Field] = &[
+    ("STPPCLK", 0, 1),
+    ("GATEHCLK", 1, 1),
+    ("PHYSUSP", 4, 1),
+    ("ENL1GTG", 5, 1),
+    ("PHYSLEEP", 6, 1),
+    ("SUSP", 7, 1),
+];
+
+/// Returns the fields decoded for the register at `offset` of a core with the given CID.
+fn fields(core_id: u32, offset: usize) -> &'static [Field] {
+    match offset {
+        0x000 => GOTGCTL,
+        0x008 => GAHBCFG,
+        0x00c => GUSBCFG,
+        0x010 => GRSTCTL,
+        0x014 | 0x018 => GINTSTS,
+        0x01c | 0x020 => GRXSTSR,
+        0x024 => GRXFSIZ,
+        0x028 | 0x100..=0x13c => TXFSIZ,
+        0x038 if core_id == 0x0000_1100 || core_id == 0x0000_1200 => GCCFG_V1,
+        0x038 => GCCFG_V2,
+        0x800 => DCFG,
+        0x804 => DCTL,
+        0x808 => DSTS,
+        0x810 => DIEPMSK,
+        0x814 => DOEPMSK,
+        0x818 | 0x81c => DAINT,
+        0x900..=0xcff => match offset % 0x20 {
+            0x00 => DEPCTL,
+            0x08 if offset < 0xb00 => DIEPINT,
+            0x08 => DOEPINT,
+            0x10 => DEPTSIZ,
+            0x18 if offset < 0xb00 => DTXFSTS,
+            _ => &[],
+        },
+        0xe00 => PCGCCTL,
+        _ => &[],
+    }
+}
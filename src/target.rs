@@ -2,12 +2,14 @@
 
 use vcell::VolatileCell;
 
+use crate::otg::OtgCapabilities;
 use crate::ral::register::RWRegister;
 use crate::ral::{
-    endpoint0_out, endpoint_in, endpoint_out, otg_device, otg_global, otg_global_dieptxfx,
-    otg_pwrclk,
+    endpoint0_out, endpoint_in, endpoint_out, host_channel, otg_device, otg_global,
+    otg_global_dieptxfx, otg_host, otg_pwrclk,
 };
-use crate::UsbPeripheral;
+use crate::ral::{modify_reg, read_reg};
+use crate::{PhyType, UsbPeripheral};
 
 pub fn fifo_write(usb: UsbRegisters, channel: impl Into<usize>, mut buf: &[u8]) {
     let fifo = usb.fifo(channel.into());
@@ -25,6 +27,21 @@ pub fn fifo_write(usb: UsbRegisters, channel: impl Into<usize>, mut buf: &[u8])
     }
 }
 
+pub fn fifo_read(usb: UsbRegisters, mut buf: &mut [u8]) {
+    let fifo = usb.fifo(0);
+
+    while buf.len() >= 4 {
+        let word = fifo.read();
+        buf[..4].copy_from_slice(&word.to_ne_bytes());
+        buf = &mut buf[4..];
+    }
+    if !buf.is_empty() {
+        let word = fifo.read();
+        let len = buf.len();
+        buf.copy_from_slice(&word.to_ne_bytes()[..len]);
+    }
+}
+
 pub fn fifo_read_into(usb: UsbRegisters, buf: &[VolatileCell<u32>]) {
     let fifo = usb.fifo(0);
 
@@ -34,6 +51,115 @@ pub fn fifo_read_into(usb: UsbRegisters, buf: &[VolatileCell<u32>]) {
     }
 }
 
+/// Mode the core is forced into during initialization
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum CoreMode {
+    Device,
+    Host,
+}
+
+/// Selects the PHY and performs the core soft-reset sequence shared by device and host modes
+pub fn init_core<USB: UsbPeripheral>(
+    peripheral: &USB,
+    regs: UsbRegisters,
+    mode: CoreMode,
+    otg: OtgCapabilities,
+    timeout_calibration: u8,
+) {
+    // Wait for AHB ready
+    while read_reg!(otg_global, regs.global(), GRSTCTL, AHBIDL) == 0 {}
+
+    // Configure OTG as device or host. HNP needs the core to switch roles on its own. Without
+    // it the mode is forced, `otg::DualRole` rewrites it on every switch.
+    let (fdmod, fhmod) = match mode {
+        _ if otg.hnp => (0, 0),
+        CoreMode::Device => (1, 0),
+        CoreMode::Host => (0, 1),
+    };
+    modify_reg!(otg_global, regs.global(), GUSBCFG,
+        SRPCAP: otg.srp as u32,
+        HNPCAP: otg.hnp as u32,
+        FHMOD: fhmod,
+        FDMOD: fdmod
+    );
+    if USB::HIGH_SPEED {
+        modify_reg!(otg_global, regs.global(), GUSBCFG, TOCAL: timeout_calibration as u32);
+    }
+
+    // Configure USB PHY
+    #[cfg(feature = "hs")]
+    match peripheral.phy_type() {
+        PhyType::InternalFullSpeed => {
+            // Select FS Embedded PHY
+            modify_reg!(otg_global, regs.global(), GUSBCFG, PHYSEL: 1);
+        }
+        PhyType::InternalHighSpeed => {
+            // Turn off PHY
+            modify_reg!(otg_global, regs.global(), GCCFG, PWRDWN: 0);
+
+            // Init The UTMI Interface
+            modify_reg!(otg_global, regs.global(), GUSBCFG,
+                TSDPS: 0,
+                ULPIFSLS: 0,
+                PHYSEL: 0 // ULPI or UTMI
+            );
+
+            // Select VBUS source
+            modify_reg!(otg_global, regs.global(), GUSBCFG,
+                ULPIEVBUSD: 0,
+                ULPIEVBUSI: 0
+            );
+
+            // Select UTMI Interace
+            //modify_reg!(otg_global, regs.global(), GUSBCFG, ULPISEL: 0);
+            modify_reg!(otg_global, regs.global(), GUSBCFG, |r| r & !(1 << 4));
+
+            // This is a secret bit from ST that is not mentioned anywhere except
+            // the driver code shipped with STM32CubeIDE.
+            //modify_reg!(otg_global, regs.global(), GCCFG, PHYHSEN: 1);
+            modify_reg!(otg_global, regs.global(), GCCFG, |r| r | (1 << 23));
+
+            peripheral.setup_internal_hs_phy();
+        }
+        PhyType::ExternalHighSpeed => {
+            // Turn off embedded PHY
+            modify_reg!(otg_global, regs.global(), GCCFG, PWRDWN: 0);
+
+            // Init The ULPI Interface
+            modify_reg!(otg_global, regs.global(), GUSBCFG,
+                TSDPS: 0,
+                ULPIFSLS: 0,
+                PHYSEL: 0 // ULPI or UTMI
+            );
+
+            // Select VBUS source
+            modify_reg!(otg_global, regs.global(), GUSBCFG,
+                ULPIEVBUSD: 0,
+                ULPIEVBUSI: 0
+            );
+        }
+    }
+
+    // Proposed fix for core reset hang on stm32h7 / cortex-m7
+    // https://github.com/stm32-rs/stm32h7xx-hal/issues/503
+    #[cfg(feature = "cortex-m")]
+    {
+        cortex_m::asm::isb();
+        cortex_m::asm::dsb();
+        cortex_m::asm::dmb();
+    }
+
+    // Perform core soft-reset
+    while read_reg!(otg_global, regs.global(), GRSTCTL, AHBIDL) == 0 {}
+    modify_reg!(otg_global, regs.global(), GRSTCTL, CSRST: 1);
+    while read_reg!(otg_global, regs.global(), GRSTCTL, CSRST) == 1 {}
+
+    if peripheral.phy_type() == PhyType::InternalFullSpeed {
+        // Activate the USB Transceiver
+        modify_reg!(otg_global, regs.global(), GCCFG, PWRDWN: 1);
+    }
+}
+
 /// Wrapper around device-specific peripheral that provides unified register interface
 #[derive(Copy, Clone)]
 pub struct UsbRegisters(usize);
@@ -49,6 +175,11 @@ impl UsbRegisters {
         unsafe { &*(self.0 as *const _) }
     }
 
+    #[inline(always)]
+    pub fn host(&self) -> &'static otg_host::RegisterBlock {
+        unsafe { &*((self.0 + 0x400) as *const _) }
+    }
+
     #[inline(always)]
     pub fn device(&self) -> &'static otg_device::RegisterBlock {
         unsafe { &*((self.0 + 0x800) as *const _) }
@@ -89,4 +220,10 @@ impl UsbRegisters {
         let address = self.0 + 0xb00 + 0x20 * index;
         unsafe { &*(address as *const _) }
     }
+
+    #[inline(always)]
+    pub fn host_channel(&self, index: usize) -> &'static host_channel::RegisterBlock {
+        let address = self.0 + 0x500 + 0x20 * index;
+        unsafe { &*(address as *const _) }
+    }
 }
@@ -15,6 +15,23 @@ pub struct EndpointDescriptor {
     pub interval: u8,
 }
 
+impl EndpointDescriptor {
+    /// Size of a single packet, without the additional transactions bits of wMaxPacketSize.
+    pub fn packet_size(&self) -> u16 {
+        self.max_packet_size & 0x7ff
+    }
+
+    /// Number of transactions per microframe, more than one for high-bandwidth endpoints.
+    pub fn transactions(&self) -> u16 {
+        ((self.max_packet_size >> 11) & 0b11) + 1
+    }
+
+    /// Largest amount of data transferred in a single (micro)frame.
+    pub fn max_payload_size(&self) -> usize {
+        self.packet_size() as usize * self.transactions() as usize
+    }
+}
+
 /// Configuration for an endpoint allocation.
 pub struct EndpointConfig {
     /// The transfer type of the endpoint to be allocated.
@@ -0,0 +1,32 @@
+//! Fixtures shared by the tests running on the simulated core.
+
+// Not every test uses every fixture
+#![allow(dead_code)]
+
+use synopsys_usb_otg::sim::SimPeripheral;
+use synopsys_usb_otg::UsbBus;
+use usb_device::bus::UsbBusAllocator;
+use usb_device::device::{StringDescriptors, UsbDevice, UsbDeviceBuilder, UsbVidPid};
+
+pub type Bus = UsbBus<SimPeripheral>;
+
+/// Returns the allocator of a simulated bus with the default settings.
+pub fn allocator() -> UsbBusAllocator<Bus> {
+    let ep_memory = Box::leak(Box::new([0u32; 1024]));
+    UsbBus::new(SimPeripheral, ep_memory)
+}
+
+/// Returns a device builder with the test VID/PID and the given product string.
+pub fn device_builder<'a, B: usb_device::bus::UsbBus>(
+    alloc: &'a UsbBusAllocator<B>,
+    product: &'a str,
+) -> UsbDeviceBuilder<'a, B> {
+    UsbDeviceBuilder::new(alloc, UsbVidPid(0x16c0, 0x27dd))
+        .strings(&[StringDescriptors::default().product(product)])
+        .unwrap()
+}
+
+/// Returns a device without any class and an 8 byte control endpoint.
+pub fn device(alloc: &UsbBusAllocator<Bus>) -> UsbDevice<'_, Bus> {
+    device_builder(alloc, "Simulated device").build()
+}
@@ -0,0 +1,215 @@
+mod common;
+
+use common::{allocator, device, Bus};
+use synopsys_usb_otg::bus::{FrameInterval, LpmConfig};
+use synopsys_usb_otg::sim::{Handshake, SimPeripheral, Simulator};
+use synopsys_usb_otg::{UsbBus, UsbBusConfig};
+use usb_device::device::{UsbDevice, UsbDeviceState};
+use usb_device::endpoint::{EndpointAddress, EndpointType, Out};
+use usb_device::UsbError;
+
+const GET_DEVICE_DESCRIPTOR: [u8; 8] = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 18, 0x00];
+
+fn poll(dev: &mut UsbDevice<'_, Bus>) {
+    for _ in 0..3 {
+        dev.poll(&mut []);
+    }
+}
+
+/// Runs a control read and its status stage, returning the data stage.
+fn control_in(sim: &Simulator, dev: &mut UsbDevice<'_, Bus>, setup: [u8; 8]) -> Vec<u8> {
+    let length = u16::from_le_bytes([setup[6], setup[7]]) as usize;
+
+    sim.send_setup(0, setup);
+    poll(dev);
+
+    let mut data = Vec::new();
+    loop {
+        let packet = sim.receive_in(0).expect("no data stage");
+        poll(dev);
+        let short = packet.len() < 8;
+        data.extend(packet);
+        if short || data.len() >= length {
+            break;
+        }
+    }
+
+    sim.send_out(0, &[]).expect("status stage refused");
+    poll(dev);
+    data
+}
+
+/// Runs a control request without data stage.
+fn control_out(sim: &Simulator, dev: &mut UsbDevice<'_, Bus>, setup: [u8; 8]) {
+    sim.send_setup(0, setup);
+    poll(dev);
+
+    assert_eq!(sim.receive_in(0), Ok(Vec::new()));
+    poll(dev);
+}
+
+#[test]
+fn enable_connects_pull_up() {
+    let sim = Simulator::new();
+    let alloc = allocator();
+    assert!(!sim.is_connected());
+
+    let mut dev = device(&alloc);
+    poll(&mut dev);
+    assert!(sim.is_connected());
+    assert_eq!(dev.state(), UsbDeviceState::Default);
+}
+
+#[test]
+fn reset_raises_interrupt() {
+    let sim = Simulator::new();
+    let alloc = allocator();
+    let mut dev = device(&alloc);
+    assert!(!sim.interrupt_pending());
+
+    sim.bus_reset();
+    assert!(sim.interrupt_pending());
+
+    poll(&mut dev);
+    assert!(!sim.interrupt_pending());
+}
+
+#[test]
+fn get_device_descriptor() {
+    let sim = Simulator::new();
+    let alloc = allocator();
+    let mut dev = device(&alloc);
+
+    sim.bus_reset();
+    poll(&mut dev);
+
+    let descriptor = control_in(&sim, &mut dev, GET_DEVICE_DESCRIPTOR);
+    assert_eq!(descriptor.len(), 18);
+    assert_eq!(&descriptor[..2], &[18, 0x01]);
+    assert_eq!(&descriptor[8..12], &[0xc0, 0x16, 0xdd, 0x27]);
+}
+
+#[test]
+fn get_device_descriptor_f446_like() {
+    let sim = Simulator::with_core_id(0x0000_2000);
+    let alloc = allocator();
+    let mut dev = device(&alloc);
+
+    sim.bus_reset();
+    poll(&mut dev);
+
+    let descriptor = control_in(&sim, &mut dev, GET_DEVICE_DESCRIPTOR);
+    assert_eq!(&descriptor[..2], &[18, 0x01]);
+}
+
+#[test]
+fn set_address() {
+    let sim = Simulator::new();
+    let alloc = allocator();
+    let mut dev = device(&alloc);
+
+    sim.bus_reset();
+    poll(&mut dev);
+
+    control_out(
+        &sim,
+        &mut dev,
+        [0x00, 0x05, 0x2a, 0x00, 0x00, 0x00, 0x00, 0x00],
+    );
+    assert_eq!(sim.device_address(), 0x2a);
+    assert_eq!(dev.state(), UsbDeviceState::Addressed);
+}
+
+#[test]
+fn unknown_descriptor_stalls() {
+    let sim = Simulator::new();
+    let alloc = allocator();
+    let mut dev = device(&alloc);
+
+    sim.bus_reset();
+    poll(&mut dev);
+
+    sim.send_setup(0, [0x80, 0x06, 0x00, 0x42, 0x00, 0x00, 0x40, 0x00]);
+    poll(&mut dev);
+    assert_eq!(sim.receive_in(0), Err(Handshake::Stall));
+
+    // The next SETUP packet clears the halt
+    let descriptor = control_in(&sim, &mut dev, GET_DEVICE_DESCRIPTOR);
+    assert_eq!(descriptor.len(), 18);
+}
+
+#[test]
+fn suspend_and_resume() {
+    let sim = Simulator::new();
+    let alloc = allocator();
+    let mut dev = device(&alloc);
+
+    sim.bus_reset();
+    poll(&mut dev);
+
+    sim.suspend();
+    poll(&mut dev);
+    assert_eq!(dev.state(), UsbDeviceState::Suspend);
+
+    sim.resume();
+    poll(&mut dev);
+    assert_eq!(dev.state(), UsbDeviceState::Default);
+}
+
+#[test]
+fn unsupported_ep0_size_is_rejected() {
+    let _sim = Simulator::new();
+    let alloc = allocator();
+
+    let ep0 = EndpointAddress::from(0);
+    let result = alloc.alloc::<Out>(Some(ep0), EndpointType::Control, 12, 0);
+    assert!(matches!(result, Err(UsbError::Unsupported)));
+    assert!(alloc
+        .alloc::<Out>(Some(ep0), EndpointType::Control, 16, 0)
+        .is_ok());
+}
+
+#[test]
+fn config_sets_frame_interval_and_rx_fifo_slack() {
+    let sim = Simulator::new();
+    let config = UsbBusConfig::new()
+        .frame_interval(FrameInterval::Percent90)
+        .rx_fifo_slack(40);
+    let ep_memory = Box::leak(Box::new([0u32; 1024]));
+    let alloc = UsbBus::with_config(SimPeripheral, ep_memory, config);
+    let mut dev = device(&alloc);
+
+    sim.bus_reset();
+    poll(&mut dev);
+
+    // PFIVL
+    assert_eq!((sim.register(0x800) >> 11) & 0b11, 0b10);
+    // GRXFSIZ, the slack and the 8 byte EP0 OUT buffer
+    assert_eq!(sim.register(0x024), 40 + 2);
+}
+
+const LPM: LpmConfig = LpmConfig {
+    besl_threshold: 0,
+    deep_sleep: false,
+};
+
+#[test]
+fn lpm_is_unsupported_on_f429_like_cores() {
+    let _sim = Simulator::with_core_id(0x0000_1200);
+    let alloc = allocator();
+    let dev = device(&alloc);
+
+    assert_eq!(dev.bus().set_lpm(Some(LPM)), Err(UsbError::Unsupported));
+    assert_eq!(dev.bus().set_lpm(None), Ok(()));
+}
+
+#[test]
+fn lpm_is_enabled_on_f446_like_cores() {
+    let sim = Simulator::with_core_id(0x0000_2000);
+    let alloc = allocator();
+    let dev = device(&alloc);
+
+    assert_eq!(dev.bus().set_lpm(Some(LPM)), Ok(()));
+    // GLPMCFG.LPMEN
+    assert_eq!(sim.register(0x54) & 1, 1);
+}
@@ -0,0 +1,168 @@
+//! Compares the register accesses of the initialization sequence against the traces in
+//! `tests/golden`.
+//!
+//! Run with `UPDATE_GOLDEN=1` to rewrite the traces after an intended change, and review the
+//! difference.
+
+mod common;
+
+use common::device_builder;
+use std::fs;
+use std::path::PathBuf;
+use synopsys_usb_otg::sim::{SimHsPeripheral, SimPeripheral, Simulator};
+use synopsys_usb_otg::{PhyType, UsbBus, UsbBusConfig, UsbPeripheral};
+
+/// Core IDs with dedicated handling in the driver
+const CORE_IDS: &[u32] = &[
+    0x0000_1100,
+    0x0000_1200,
+    0x0000_2000,
+    0x0000_2100,
+    0x0000_2300,
+    0x0000_3000,
+    0x0000_3100,
+];
+
+/// PHY types configured by the driver, only the `hs` feature supports the high-speed PHYs
+#[cfg(not(feature = "hs"))]
+const PHY_TYPES: &[(PhyType, &str)] = &[(PhyType::InternalFullSpeed, "fs-phy")];
+#[cfg(feature = "hs")]
+const PHY_TYPES: &[(PhyType, &str)] = &[
+    (PhyType::InternalFullSpeed, "fs-phy"),
+    (PhyType::InternalHighSpeed, "utmi"),
+    (PhyType::ExternalHighSpeed, "ulpi"),
+];
+
+/// Records the trace of a full-speed core with the internal PHY, a high-speed core otherwise.
+fn record(core_id: u32, phy_type: PhyType) -> String {
+    let config = UsbBusConfig::new();
+    match phy_type {
+        PhyType::InternalFullSpeed => record_with(SimPeripheral, core_id, phy_type, config),
+        _ => record_with(SimHsPeripheral, core_id, phy_type, config),
+    }
+}
+
+fn record_with<P: UsbPeripheral>(
+    peripheral: P,
+    core_id: u32,
+    phy_type: PhyType,
+    config: UsbBusConfig,
+) -> String {
+    let sim = Simulator::with_core_id(core_id);
+    sim.set_phy_type(phy_type);
+
+    let ep_memory = Box::leak(Box::new([0u32; 1024]));
+    let base = ep_memory.as_ptr() as u32;
+    let alloc = UsbBus::with_config(peripheral, ep_memory, config);
+
+    sim.start_trace();
+    let mut dev = device_builder(&alloc, "Traced device").build();
+    let mut trace = format!("# enable\n{}", sim.take_trace());
+
+    sim.bus_reset();
+    sim.start_trace();
+    dev.poll(&mut []);
+    trace += &format!("# reset\n{}", sim.take_trace());
+    relative_dma_addresses(&trace, base)
+}
+
+/// Replaces the DMA addresses, which point into the leaked endpoint memory, with their offset
+/// from `base` to keep the traces deterministic.
+fn relative_dma_addresses(trace: &str, base: u32) -> String {
+    trace
+        .lines()
+        .map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [access, name, value] if name.contains("DMA") && !name.ends_with("CFG") => {
+                    let address = u32::from_str_radix(value, 16).unwrap();
+                    format!(
+                        "{} {:<12} +{:07x}\n",
+                        access,
+                        name,
+                        address.wrapping_sub(base)
+                    )
+                }
+                _ => format!("{}\n", line),
+            }
+        })
+        .collect()
+}
+
+fn golden_path(core_id: u32, phy: &str) -> PathBuf {
+    let mode = if cfg!(feature = "hs") { "hs" } else { "fs" };
+    let name = format!("{}-cid{:04x}-{}.trace", mode, core_id, phy);
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("golden")
+        .join(name)
+}
+
+/// Compares `trace` against the golden trace at `path`, or rewrites it with `UPDATE_GOLDEN`.
+fn matches_golden(path: &PathBuf, trace: &str) -> bool {
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::write(path, trace).unwrap();
+        return true;
+    }
+    let golden = fs::read_to_string(path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+    if golden != trace {
+        eprintln!("--- {}\n+++ recorded\n{}", path.display(), trace);
+        return false;
+    }
+    true
+}
+
+#[test]
+#[cfg_attr(
+    feature = "xcvrdly",
+    ignore = "the traces are recorded without xcvrdly"
+)]
+fn init_sequences_match_golden_traces() {
+    let mut mismatches = Vec::new();
+
+    for &core_id in CORE_IDS {
+        for &(phy_type, phy) in PHY_TYPES {
+            let trace = record(core_id, phy_type);
+            let path = golden_path(core_id, phy);
+            if !matches_golden(&path, &trace) {
+                mismatches.push(path);
+            }
+        }
+    }
+
+    assert!(
+        mismatches.is_empty(),
+        "register traces differ, rerun with UPDATE_GOLDEN=1 if intended: {:?}",
+        mismatches
+    );
+}
+
+#[test]
+fn trace_decodes_fields() {
+    let trace = record(0x0000_2000, PhyType::InternalFullSpeed);
+
+    assert!(trace.contains("R CID          00002000\n"));
+    assert!(trace.contains("W GRSTCTL      80000001 CSRST AHBIDL\n"));
+    assert!(trace.contains("W GOTGCTL      000000c0 BVALOEN BVALOVAL\n"));
+    assert!(trace.contains("W DCFG         00000003 DSPD=0x3\n"));
+}
+
+/// Covers the DMA setup and the transceiver delay of an H7-like core with a ULPI PHY.
+#[test]
+#[cfg(feature = "hs")]
+fn dma_init_sequence_matches_golden_trace() {
+    let config = UsbBusConfig::new().dma(true).transceiver_delay(true);
+    let trace = record_with(
+        SimHsPeripheral,
+        0x0000_2300,
+        PhyType::ExternalHighSpeed,
+        config,
+    );
+
+    let path = golden_path(0x0000_2300, "ulpi-dma");
+    assert!(
+        matches_golden(&path, &trace),
+        "register trace differs, rerun with UPDATE_GOLDEN=1 if intended: {:?}",
+        path
+    );
+}
@@ -0,0 +1,285 @@
+mod common;
+
+use common::{allocator, device_builder, Bus};
+use synopsys_usb_otg::enumeration::{
+    ConfigurationInfo, EnumerationError, Enumerator, MAX_ENDPOINTS, MAX_INTERFACES,
+};
+use synopsys_usb_otg::host::HostError;
+use synopsys_usb_otg::sim::host::{setup_packet, ScriptError, Step, VirtualHost};
+use synopsys_usb_otg::sim::Simulator;
+use usb_device::bus::{InterfaceNumber, UsbBusAllocator};
+use usb_device::class::{ControlIn, ControlOut, UsbClass};
+use usb_device::control::{Recipient, RequestType};
+use usb_device::descriptor::DescriptorWriter;
+use usb_device::device::{UsbDevice, UsbDeviceState};
+use usb_device::endpoint::{EndpointAddress, EndpointIn, EndpointOut, EndpointType};
+
+const SET_LINE_CODING: u8 = 0x20;
+const GET_LINE_CODING: u8 = 0x21;
+const SET_CONTROL_LINE_STATE: u8 = 0x22;
+
+/// Minimal CDC ACM function echoing the data it receives.
+struct EchoSerial<'a> {
+    comm_if: InterfaceNumber,
+    comm_ep: EndpointIn<'a, Bus>,
+    data_if: InterfaceNumber,
+    read_ep: EndpointOut<'a, Bus>,
+    write_ep: EndpointIn<'a, Bus>,
+    line_coding: [u8; 7],
+}
+
+impl<'a> EchoSerial<'a> {
+    fn new(alloc: &'a UsbBusAllocator<Bus>) -> Self {
+        EchoSerial {
+            comm_if: alloc.interface(),
+            comm_ep: alloc.interrupt(8, 255),
+            data_if: alloc.interface(),
+            read_ep: alloc.bulk(64),
+            write_ep: alloc.bulk(64),
+            line_coding: [0x80, 0x25, 0x00, 0x00, 0x00, 0x00, 0x08],
+        }
+    }
+
+    fn is_comm_request(&self, request_type: RequestType, recipient: Recipient, index: u16) -> bool {
+        request_type == RequestType::Class
+            && recipient == Recipient::Interface
+            && index == u8::from(self.comm_if) as u16
+    }
+}
+
+impl UsbClass<Bus> for EchoSerial<'_> {
+    fn get_configuration_descriptors(
+        &self,
+        writer: &mut DescriptorWriter,
+    ) -> usb_device::Result<()> {
+        writer.iad(self.comm_if, 2, 0x02, 0x02, 0x00, None)?;
+        writer.interface(self.comm_if, 0x02, 0x02, 0x00)?;
+        writer.write(0x24, &[0x00, 0x10, 0x01])?;
+        writer.write(0x24, &[0x01, 0x00, self.data_if.into()])?;
+        writer.write(0x24, &[0x02, 0x00])?;
+        writer.write(0x24, &[0x06, self.comm_if.into(), self.data_if.into()])?;
+        writer.endpoint(&self.comm_ep)?;
+        writer.interface(self.data_if, 0x0a, 0x00, 0x00)?;
+        writer.endpoint(&self.write_ep)?;
+        writer.endpoint(&self.read_ep)?;
+        Ok(())
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<Bus>) {
+        let req = *xfer.request();
+        if self.is_comm_request(req.request_type, req.recipient, req.index)
+            && req.request == GET_LINE_CODING
+        {
+            xfer.accept_with(&self.line_coding).ok();
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<Bus>) {
+        let req = *xfer.request();
+        if !self.is_comm_request(req.request_type, req.recipient, req.index) {
+            return;
+        }
+        match req.request {
+            SET_LINE_CODING if xfer.data().len() == 7 => {
+                self.line_coding.copy_from_slice(xfer.data());
+                xfer.accept().ok();
+            }
+            SET_CONTROL_LINE_STATE => {
+                xfer.accept().ok();
+            }
+            _ => {
+                xfer.reject().ok();
+            }
+        }
+    }
+
+    fn endpoint_out(&mut self, addr: EndpointAddress) {
+        if addr != self.read_ep.address() {
+            return;
+        }
+        let mut buf = [0; 64];
+        if let Ok(count) = self.read_ep.read(&mut buf) {
+            self.write_ep.write(&buf[..count]).ok();
+        }
+    }
+}
+
+fn device(alloc: &UsbBusAllocator<Bus>) -> UsbDevice<'_, Bus> {
+    device_builder(alloc, "Echo serial")
+        .composite_with_iads()
+        .max_packet_size_0(64)
+        .unwrap()
+        .build()
+}
+
+fn enumerates_cdc(core_id: u32) {
+    let sim = Simulator::with_core_id(core_id);
+    let alloc = allocator();
+    let mut serial = EchoSerial::new(&alloc);
+    let mut dev = device(&alloc);
+
+    let enumerated = {
+        let mut host = VirtualHost::new(&sim, || {
+            dev.poll(&mut [&mut serial]);
+        });
+        host.enumerate(&mut Enumerator::new()).unwrap()
+    };
+
+    assert_eq!(enumerated.address, 1);
+    assert_eq!(sim.device_address(), 1);
+    assert_eq!(dev.state(), UsbDeviceState::Configured);
+
+    assert_eq!(enumerated.device.vendor_id, 0x16c0);
+    assert_eq!(enumerated.device.product_id, 0x27dd);
+    assert_eq!(enumerated.device.max_packet_size_0, 64);
+
+    let interfaces = enumerated.configuration.interfaces();
+    assert_eq!(interfaces.len(), 2);
+    assert_eq!((interfaces[0].class, interfaces[0].sub_class), (0x02, 0x02));
+    assert_eq!(interfaces[1].class, 0x0a);
+
+    let comm: Vec<_> = interfaces[0].endpoints().collect();
+    assert_eq!(comm.len(), 1);
+    assert_eq!(comm[0].ep_type, EndpointType::Interrupt);
+
+    let data: Vec<_> = interfaces[1].endpoints().collect();
+    assert_eq!(data.len(), 2);
+    assert!(data.iter().all(|ep| ep.ep_type == EndpointType::Bulk));
+    assert!(data.iter().all(|ep| ep.max_packet_size == 64));
+}
+
+#[test]
+fn enumerate_cdc_f429_like() {
+    enumerates_cdc(0x0000_1200);
+}
+
+#[test]
+fn enumerate_cdc_f446_like() {
+    enumerates_cdc(0x0000_2000);
+}
+
+#[test]
+fn scripted_class_requests_and_echo() {
+    let sim = Simulator::new();
+    let alloc = allocator();
+    let mut serial = EchoSerial::new(&alloc);
+    let mut dev = device(&alloc);
+    let mut host = VirtualHost::new(&sim, || {
+        dev.poll(&mut [&mut serial]);
+    });
+
+    let enumerated = host.enumerate(&mut Enumerator::new()).unwrap();
+    let data_if = &enumerated.configuration.interfaces()[1];
+    let endpoint = |is_in: bool| {
+        let ep = data_if.endpoints().find(|ep| ep.address.is_in() == is_in);
+        ep.unwrap().address.index() as u8
+    };
+    let (ep_in, ep_out) = (endpoint(true), endpoint(false));
+
+    let line_coding = [0x00, 0xc2, 0x01, 0x00, 0x00, 0x00, 0x08];
+    let collected = host
+        .run(&[
+            Step::ControlOut(setup_packet(0x21, SET_LINE_CODING, 0, 0, 7), &line_coding),
+            Step::ExpectControlIn(setup_packet(0xa1, GET_LINE_CODING, 0, 0, 7), &line_coding),
+            Step::ControlOut(setup_packet(0x21, SET_CONTROL_LINE_STATE, 0b11, 0, 0), &[]),
+            Step::Out(ep_out, b"hello"),
+            Step::ExpectIn(ep_in, b"hello"),
+            Step::Out(ep_out, &[0x55; 64]),
+            Step::In(ep_in),
+        ])
+        .unwrap();
+    assert_eq!(collected, vec![vec![0x55; 64]]);
+}
+
+#[test]
+fn script_reports_failing_step() {
+    let sim = Simulator::new();
+    let alloc = allocator();
+    let mut serial = EchoSerial::new(&alloc);
+    let mut dev = device(&alloc);
+    let mut host = VirtualHost::new(&sim, || {
+        dev.poll(&mut [&mut serial]);
+    });
+    host.enumerate(&mut Enumerator::new()).unwrap();
+
+    // Unsupported class request, the device stalls the data stage
+    let result = host.run(&[
+        Step::ControlIn(setup_packet(0x80, 0x06, 0x0300, 0x0409, 255)),
+        Step::ControlIn(setup_packet(0xa1, 0x7f, 0, 0, 8)),
+    ]);
+    assert_eq!(
+        result,
+        Err(ScriptError::Host {
+            step: 1,
+            error: HostError::Stall
+        })
+    );
+
+    // Nothing has been written to the bulk IN endpoint
+    let result = host.run(&[Step::ExpectIn(2, b"")]);
+    assert_eq!(
+        result,
+        Err(ScriptError::Host {
+            step: 0,
+            error: HostError::Nak
+        })
+    );
+}
+
+#[test]
+fn enumeration_fails_without_connect() {
+    let sim = Simulator::new();
+    let alloc = allocator();
+    let _serial = EchoSerial::new(&alloc);
+
+    // The device isn't polled, so nothing answers
+    let mut host = VirtualHost::new(&sim, || {});
+    assert!(matches!(
+        host.enumerate(&mut Enumerator::new()),
+        Err(EnumerationError::Host(_, HostError::Nak))
+    ));
+}
+
+#[test]
+fn suspend_resume_script() {
+    let sim = Simulator::new();
+    let alloc = allocator();
+    let mut serial = EchoSerial::new(&alloc);
+    let mut dev = device(&alloc);
+    {
+        let mut host = VirtualHost::new(&sim, || {
+            dev.poll(&mut [&mut serial]);
+        });
+        host.enumerate(&mut Enumerator::new()).unwrap();
+        host.run(&[Step::Suspend]).unwrap();
+    }
+    assert_eq!(dev.state(), UsbDeviceState::Suspend);
+
+    {
+        let mut host = VirtualHost::new(&sim, || {
+            dev.poll(&mut [&mut serial]);
+        });
+        host.run(&[Step::Resume]).unwrap();
+    }
+    assert_eq!(dev.state(), UsbDeviceState::Configured);
+}
+
+#[test]
+fn configuration_parse_skips_extra_entries() {
+    let mut data = vec![9, 0x02, 0, 0, 9, 1, 0, 0x80, 50];
+    for number in 0..MAX_INTERFACES as u8 + 1 {
+        data.extend_from_slice(&[9, 0x04, number, 0, 5, 0xff, 0, 0, 0]);
+        for ep in 1..=5 {
+            data.extend_from_slice(&[7, 0x05, 0x80 | ep, 0x02, 64, 0, 0]);
+        }
+    }
+    let total_length = (data.len() as u16).to_le_bytes();
+    data[2..4].copy_from_slice(&total_length);
+
+    let config = ConfigurationInfo::parse(&data).unwrap();
+    assert!(config.is_truncated());
+    assert_eq!(config.interfaces().len(), MAX_INTERFACES);
+    let last = config.interfaces().last().unwrap();
+    assert_eq!(last.number, MAX_INTERFACES as u8 - 1);
+    assert_eq!(last.endpoints().count(), MAX_ENDPOINTS);
+}